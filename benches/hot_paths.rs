@@ -0,0 +1,73 @@
+//! Benchmarks for the paths which mutators hit most often: safepoint polls
+//! which don't collect, allocation, and the safepoint table lookup done for
+//! each frame when scanning a stack.
+//!
+//! Run with `cargo bench --features bench`.
+
+use std::sync::Once;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gcrt::{Gc, GcStr};
+
+/// The number of frames looked up per iteration of the table benchmark.
+const LOOKUPS: usize = 1024;
+
+static INIT: Once = Once::new();
+
+fn init() {
+    INIT.call_once(gcrt::init);
+}
+
+fn poll(c: &mut Criterion) {
+    init();
+    c.bench_function("safepoint_poll (no collection)", |b| {
+        b.iter(gcrt::safepoint_poll)
+    });
+}
+
+fn alloc(c: &mut Criterion) {
+    init();
+    let mut group = c.benchmark_group("alloc");
+    macro_rules! sized {
+        ($($n:literal),*) => {$(
+            group.throughput(Throughput::Bytes($n));
+            group.bench_function(BenchmarkId::new("Gc::new", $n), |b| {
+                b.iter(|| Gc::new(black_box([0u8; $n])))
+            });
+        )*};
+    }
+    // Nothing keeps the objects alive, so this includes the cost of the
+    // collections needed to make room for them, as a mutator would see.
+    sized!(16, 64, 256, 1024, 4096);
+    for &len in &[16, 1024, 65536] {
+        let s = "x".repeat(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("GcStr::new", len), &s, |b, s| {
+            b.iter(|| GcStr::new(s))
+        });
+    }
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    init();
+    let safepoints = gcrt::bench::safepoints();
+    let mut group = c.benchmark_group("safepoint table");
+    group.throughput(Throughput::Elements(LOOKUPS as u64));
+    // Stack scanning looks up every frame, most of which aren't at a safepoint
+    // in code which mixes GC-using and native frames, so measure both.
+    if !safepoints.is_empty() {
+        let hits: Vec<u64> = safepoints.iter().cycle().take(LOOKUPS).cloned().collect();
+        group.bench_function("hit", |b| {
+            b.iter(|| gcrt::bench::count_safepoints(black_box(&hits)))
+        });
+    }
+    let misses: Vec<u64> = (0..LOOKUPS as u64).map(|i| 0x1000 + i * 8).collect();
+    group.bench_function("miss", |b| {
+        b.iter(|| gcrt::bench::count_safepoints(black_box(&misses)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, poll, alloc, lookup);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+//! Runs arbitrary sequences of allocations, releases and collections, checking
+//! after each step that no two live objects overlap and that every live object
+//! still holds what was written to it.
+//!
+//! Each input is read as a series of three-byte operations. The first byte
+//! picks the operation and the other two are its argument:
+//!
+//!   0: allocate a string of `arg % 8192` bytes, each set to the low byte of
+//!      `arg`, and keep it alive
+//!   1: release the kept object at index `arg`, if there is one
+//!   2: collect
+//!
+//! The collector is disabled except during the explicit collections, so the
+//! only roots are the ones registered with `Gc::into_raw`.
+
+#![no_main]
+
+use std::{collections::BTreeMap, sync::Once};
+
+use gcrt::{Gc, GcStr};
+use libfuzzer_sys::fuzz_target;
+
+static INIT: Once = Once::new();
+
+fuzz_target!(|data: &[u8]| {
+    INIT.call_once(|| {
+        gcrt::init();
+        gcrt::disable();
+    });
+
+    let mut kept: Vec<(*const GcStr, usize, u8)> = Vec::new();
+    for op in data.chunks_exact(3) {
+        let arg = u16::from_le_bytes([op[1], op[2]]) as usize;
+        match op[0] % 3 {
+            0 => {
+                let len = arg % 8192;
+                let byte = (arg as u8) % 0x80;
+                let s = GcStr::new(&String::from_utf8(vec![byte; len]).unwrap());
+                kept.push((Gc::into_raw(Gc::new(s)), len, byte));
+            }
+            1 if arg < kept.len() => {
+                let (obj, ..) = kept.swap_remove(arg);
+                unsafe { Gc::from_raw(obj) };
+            }
+            2 => {
+                gcrt::enable();
+                gcrt::force_collect();
+                gcrt::disable();
+            }
+            _ => ()
+        }
+        check(&kept);
+    }
+
+    for (obj, ..) in kept {
+        unsafe { Gc::from_raw(obj) };
+    }
+});
+
+fn check(kept: &[(*const GcStr, usize, u8)]) {
+    // The start and end of each object's body, keyed by its start.
+    let mut objects = BTreeMap::new();
+    for &(obj, len, byte) in kept {
+        let s = unsafe { &*obj };
+        assert_eq!(s.len(), len);
+        assert!(s.bytes().all(|b| b == byte), "string was overwritten");
+        let start = s.as_ptr() as usize - std::mem::size_of::<usize>();
+        for &(start, end) in &[
+            (obj as usize, obj as usize + std::mem::size_of::<GcStr>()),
+            (start, s.as_ptr() as usize + len)
+        ] {
+            assert!(gcrt::is_gc_ptr(start as *const u8));
+            assert!(
+                objects.insert(start, end).is_none(),
+                "objects share an address"
+            );
+        }
+    }
+    let mut prev_end = 0;
+    for (&start, &end) in &objects {
+        assert!(start >= prev_end, "objects overlap at {:#x}", start);
+        prev_end = end;
+    }
+}
@@ -0,0 +1,68 @@
+//! Feeds arbitrary bytes to the safepoint table builder as the contents of an
+//! ELF file's `.llvm_stackmaps` section.
+
+#![no_main]
+
+use std::{env, fs, path::PathBuf, process};
+
+use libfuzzer_sys::fuzz_target;
+
+const SHSTRTAB: &[u8] = b"\0.llvm_stackmaps\0.shstrtab\0";
+
+fuzz_target!(|data: &[u8]| {
+    let path = scratch_path();
+    fs::write(&path, elf_with_stackmaps(data)).unwrap();
+    gcrt::fuzzing::safepoint_table(&path);
+});
+
+fn scratch_path() -> PathBuf {
+    env::temp_dir().join(format!("rgcrt-fuzz-stackmaps-{}", process::id()))
+}
+
+/// Builds a relocatable x86_64 ELF file with two sections: `.llvm_stackmaps`,
+/// holding `stackmaps`, and the section name table.
+fn elf_with_stackmaps(stackmaps: &[u8]) -> Vec<u8> {
+    let stackmaps_off = 64;
+    let shstrtab_off = stackmaps_off + stackmaps.len();
+    let shoff = (shstrtab_off + SHSTRTAB.len() + 7) & !7;
+
+    let mut elf = Vec::with_capacity(shoff + 3 * 64);
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    elf.extend_from_slice(&1u16.to_le_bytes()); // e_type: ET_REL
+    elf.extend_from_slice(&62u16.to_le_bytes()); // e_machine: EM_X86_64
+    elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&(shoff as u64).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    elf.extend_from_slice(&0u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&0u16.to_le_bytes()); // e_phnum
+    elf.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+    elf.extend_from_slice(&3u16.to_le_bytes()); // e_shnum
+    elf.extend_from_slice(&2u16.to_le_bytes()); // e_shstrndx
+
+    elf.extend_from_slice(stackmaps);
+    elf.extend_from_slice(SHSTRTAB);
+    elf.resize(shoff, 0);
+
+    section_header(&mut elf, 0, 0, 0, 0, 0);
+    // SHT_PROGBITS, SHF_ALLOC
+    section_header(&mut elf, 1, 1, 2, stackmaps_off, stackmaps.len());
+    // SHT_STRTAB
+    section_header(&mut elf, 17, 3, 0, shstrtab_off, SHSTRTAB.len());
+    elf
+}
+
+fn section_header(elf: &mut Vec<u8>, name: u32, ty: u32, flags: u64, off: usize, size: usize) {
+    elf.extend_from_slice(&name.to_le_bytes());
+    elf.extend_from_slice(&ty.to_le_bytes());
+    elf.extend_from_slice(&flags.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes()); // sh_addr
+    elf.extend_from_slice(&(off as u64).to_le_bytes());
+    elf.extend_from_slice(&(size as u64).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes()); // sh_link
+    elf.extend_from_slice(&0u32.to_le_bytes()); // sh_info
+    elf.extend_from_slice(&8u64.to_le_bytes()); // sh_addralign
+    elf.extend_from_slice(&0u64.to_le_bytes()); // sh_entsize
+}
@@ -0,0 +1,148 @@
+//! `#[derive(Scan)]` for the gcrt runtime.
+//!
+//! The generated `scan()` visits every field of a struct, or every field of the
+//! active variant of an enum, in declaration order. Fields which can never hold
+//! a GC pointer must still implement `Scan` (the runtime provides empty impls
+//! for primitives), so forgetting to account for a field is a compile error
+//! rather than a silent collector bug.
+//!
+//! `#[derive(NoTrace)]` is for leaf types which can never hold a GC pointer. It
+//! requires every field to be `NoTrace`, so the unsafe marker impl it emits is
+//! checked by the compiler, and generates a `Scan` impl which tells the
+//! collector not to bother tracing the type at all.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam, Index, Type};
+
+#[proc_macro_derive(Scan)]
+pub fn derive_scan(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    // Every type parameter must itself be scannable for the derived impl to
+    // be sound.
+    for param in input.generics.params.iter_mut() {
+        if let GenericParam::Type(ref mut ty) = *param {
+            ty.bounds.push(parse_quote!(::gcrt::Scan));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let body = match input.data {
+        Data::Struct(ref data) => scan_struct(&data.fields),
+        Data::Enum(ref data) => {
+            let arms = data.variants.iter().map(|v| {
+                let variant = &v.ident;
+                let (pat, calls) = bind_fields(&v.fields);
+                quote!(#name::#variant #pat => { #calls })
+            });
+            quote! {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "`Scan` cannot be derived for unions")
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::gcrt::Scan for #name #ty_generics #where_clause {
+            fn scan(&self, tracer: &mut ::gcrt::Tracer) {
+                #body
+            }
+        }
+    };
+    expanded.into()
+}
+
+#[proc_macro_derive(NoTrace)]
+pub fn derive_no_trace(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    for param in input.generics.params.iter_mut() {
+        if let GenericParam::Type(ref mut ty) = *param {
+            ty.bounds.push(parse_quote!(::gcrt::NoTrace));
+        }
+    }
+
+    let field_tys: Vec<&Type> = match input.data {
+        Data::Struct(ref data) => data.fields.iter().map(|f| &f.ty).collect(),
+        Data::Enum(ref data) => data
+            .variants
+            .iter()
+            .flat_map(|v| v.fields.iter().map(|f| &f.ty))
+            .collect(),
+        Data::Union(ref data) => data.fields.named.iter().map(|f| &f.ty).collect()
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut where_clause = where_clause.cloned().unwrap_or_else(|| parse_quote!(where));
+    for ty in field_tys {
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::gcrt::NoTrace));
+    }
+
+    let expanded = quote! {
+        unsafe impl #impl_generics ::gcrt::NoTrace for #name #ty_generics #where_clause {}
+
+        impl #impl_generics ::gcrt::Scan for #name #ty_generics #where_clause {
+            #[inline]
+            fn scan(&self, _: &mut ::gcrt::Tracer) {}
+
+            #[inline]
+            fn needs_trace() -> bool {
+                false
+            }
+        }
+    };
+    expanded.into()
+}
+
+fn scan_struct(fields: &Fields) -> TokenStream2 {
+    let calls = fields.iter().enumerate().map(|(i, f)| match f.ident {
+        Some(ref ident) => quote!(::gcrt::Scan::scan(&self.#ident, tracer);),
+        None => {
+            let idx = Index::from(i);
+            quote!(::gcrt::Scan::scan(&self.#idx, tracer);)
+        }
+    });
+    quote!(#(#calls)*)
+}
+
+/// Returns a pattern binding each field of an enum variant by reference, along
+/// with the calls which scan those bindings.
+fn bind_fields(fields: &Fields) -> (TokenStream2, TokenStream2) {
+    match *fields {
+        Fields::Named(ref named) => {
+            let idents: Vec<_> = named
+                .named
+                .iter()
+                .map(|f| f.ident.as_ref().unwrap())
+                .collect();
+            (
+                quote!({ #(ref #idents),* }),
+                quote!(#(::gcrt::Scan::scan(#idents, tracer);)*)
+            )
+        }
+        Fields::Unnamed(ref unnamed) => {
+            let idents: Vec<_> = (0..unnamed.unnamed.len())
+                .map(|i| format_ident!("__f{}", i))
+                .collect();
+            (
+                quote!(( #(ref #idents),* )),
+                quote!(#(::gcrt::Scan::scan(#idents, tracer);)*)
+            )
+        }
+        Fields::Unit => (quote!(), quote!())
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * The C ABI of the rgcrt garbage collector runtime. Build the runtime as a
+ * shared library with:
+ *
+ *     cargo rustc --release --features capi --crate-type cdylib
+ *
+ * Code which holds GC pointers must be compiled with LLVM statepoints, and
+ * call rgcrt_safepoint_poll() (or test the word returned by rgcrt_poll_flag())
+ * at the poll sites LLVM inserts: the collector finds the pointers on each
+ * thread's stack from the stackmaps in the executable.
+ */
+
+#ifndef RGCRT_H
+#define RGCRT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Incremented whenever one of the functions below changes incompatibly.
+ * Compare it against rgcrt_abi_version() to check that the library loaded at
+ * run time matches this header.
+ */
+#define RGCRT_ABI_VERSION 1
+
+uint32_t rgcrt_abi_version(void);
+
+/* Initialises the runtime and registers the calling thread. Call it once,
+ * before anything else. */
+void rgcrt_init(void);
+
+/* Every other thread which holds GC pointers must register itself before it
+ * does so, and unregister before it exits. */
+void rgcrt_register_thread(void);
+void rgcrt_unregister_thread(void);
+
+/* Brackets code which may block for a long time and doesn't touch the GC
+ * heap, so that collections needn't wait for the thread. */
+void rgcrt_enter_native(void);
+void rgcrt_exit_native(void);
+
+/* Stops the thread for a collection, if one is pending. */
+void rgcrt_safepoint_poll(void);
+
+/* The address of a word which is non-zero whenever rgcrt_safepoint_poll() has
+ * work to do, so that poll sites can test it inline. It changes when the
+ * thread registers or unregisters. */
+const size_t *rgcrt_poll_flag(void);
+
+/* Allocates a zeroed, 16-byte aligned object of at least `size` bytes, or
+ * returns NULL if the heap is exhausted. The object is scanned conservatively:
+ * any aligned word in it holding the address of a GC object keeps that object
+ * alive. */
+void *rgcrt_alloc(size_t size);
+
+/* As rgcrt_alloc(), but the object is never scanned, so must not hold GC
+ * pointers. */
+void *rgcrt_alloc_atomic(size_t size);
+
+/* Keeps `obj` alive while it is only referenced from somewhere the collector
+ * can't see, such as a global variable or a malloc()ed structure. Each call to
+ * rgcrt_add_root() must be matched by one to rgcrt_remove_root(). */
+void rgcrt_add_root(const void *obj);
+void rgcrt_remove_root(const void *obj);
+
+/* Call after storing a GC pointer into `obj`. */
+void rgcrt_write_barrier(const void *obj);
+
+/* Collects now, unless the collector is disabled. */
+void rgcrt_collect(void);
+
+/* Disabling the collector stops collections from happening until it is
+ * enabled again; calls nest. */
+void rgcrt_disable(void);
+void rgcrt_enable(void);
+
+/* Returns true if `addr` is the start of a live GC object. */
+bool rgcrt_is_gc_ptr(const void *addr);
+
+/* dlopen() and dlclose(), which also add the library's safepoints to the table
+ * and remove them once it is unloaded. Libraries compiled with statepoints
+ * must be loaded through these, or reported with rgcrt_library_loaded(). */
+void *rgcrt_dlopen(const char *filename, int flags);
+int rgcrt_dlclose(void *handle);
+
+/* Adds the safepoints of a library the caller opened with dlopen(), and returns
+ * how many there were, or -1 if its stackmaps couldn't be read. Call
+ * rgcrt_forget_unloaded_libraries() after closing it with dlclose(). */
+long rgcrt_library_loaded(void *handle);
+void rgcrt_forget_unloaded_libraries(void);
+
+/*
+ * The embedding API, for hosts written in C or C++. A host's stack isn't
+ * described by stackmaps, so the collector can't see GC pointers in its local
+ * variables: keep them in handles instead, and read the object's address back
+ * from the handle after anything which might collect.
+ */
+
+typedef struct rgcrt_tracer rgcrt_tracer;
+typedef struct rgcrt_type rgcrt_type;
+
+/* Called during marking to report, with rgcrt_trace(), each GC pointer held by
+ * `obj`. It must not allocate or otherwise call into the runtime. */
+typedef void (*rgcrt_trace_fn)(const void *obj, rgcrt_tracer *tracer);
+
+/* Registers a type of object. `trace` may be NULL for objects which never hold
+ * GC pointers. Types are never freed. */
+const rgcrt_type *rgcrt_register_type(const char *name, rgcrt_trace_fn trace);
+
+/* Allocates a zeroed object of type `type`, or returns NULL if the heap is
+ * exhausted. */
+void *rgcrt_alloc_typed(const rgcrt_type *type, size_t size);
+
+void rgcrt_trace(rgcrt_tracer *tracer, const void *obj);
+
+/* Local handles belong to the calling thread and are released together when
+ * the scope they were created in is closed. Scopes nest, and must be closed in
+ * the reverse order to that in which they were opened. */
+size_t rgcrt_open_scope(void);
+void rgcrt_close_scope(size_t scope);
+void **rgcrt_handle(void *obj);
+
+/* A root keeps whatever object it holds alive until it is freed. Roots may be
+ * shared between threads; store a different object (or NULL) to change what it
+ * holds. */
+void **rgcrt_root_new(void *obj);
+void rgcrt_root_free(void **root);
+
+/* Collection control, beyond rgcrt_collect(), rgcrt_disable() and
+ * rgcrt_enable(). */
+uint64_t rgcrt_collection_count(void);
+size_t rgcrt_heap_size(void);
+/* Allocations which would grow the heap past `bytes` fail. */
+void rgcrt_set_max_heap_size(size_t bytes);
+/* Requests a collection at the next safepoint poll once `bytes` have been
+ * allocated since the last one. */
+void rgcrt_set_gc_trigger_threshold(size_t bytes);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RGCRT_H */
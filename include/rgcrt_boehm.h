@@ -0,0 +1,47 @@
+/*
+ * The subset of the Boehm-Demers-Weiser collector's API provided by rgcrt's
+ * `boehm` feature, for moving Boehm-based runtimes onto rgcrt. Include this
+ * instead of <gc.h>.
+ *
+ * Objects are scanned conservatively, but C stacks are not: a GC pointer held
+ * only on the stack of code which wasn't compiled with LLVM statepoints must
+ * be registered with rgcrt_add_root() across any allocation.
+ */
+
+#ifndef RGCRT_BOEHM_H
+#define RGCRT_BOEHM_H
+
+#include <stddef.h>
+
+#include "rgcrt.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+void GC_init(void);
+void *GC_malloc(size_t size);
+void *GC_malloc_atomic(size_t size);
+/* Uncollectable objects stay alive, and keep what they point to alive, until
+ * they are passed to GC_free(). */
+void *GC_malloc_uncollectable(size_t size);
+void *GC_realloc(void *obj, size_t size);
+/* Only frees uncollectable objects; others are left to the collector. */
+void GC_free(void *obj);
+void GC_gcollect(void);
+void GC_disable(void);
+void GC_enable(void);
+size_t GC_get_heap_size(void);
+
+#define GC_INIT() GC_init()
+#define GC_MALLOC(n) GC_malloc(n)
+#define GC_MALLOC_ATOMIC(n) GC_malloc_atomic(n)
+#define GC_MALLOC_UNCOLLECTABLE(n) GC_malloc_uncollectable(n)
+#define GC_REALLOC(p, n) GC_realloc((p), (n))
+#define GC_FREE(p) GC_free(p)
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RGCRT_BOEHM_H */
@@ -0,0 +1,64 @@
+//! An exponentially weighted estimate of the rate at which the mutator
+//! allocates.
+//!
+//! Each collection contributes a sample: the bytes allocated since the end of
+//! the previous collection, divided by the time the mutator has run since then.
+//! Samples are weighted by that time, with older ones decaying by half every
+//! `HALF_LIFE`, so that a burst of collections a few milliseconds apart doesn't
+//! swamp the estimate any more than a single long interval would.
+
+use std::time::{Duration, Instant};
+
+const HALF_LIFE: Duration = Duration::from_secs(1);
+
+pub(crate) struct AllocRate {
+    /// The estimate in bytes per second, as of `since`.
+    rate: f64,
+    /// `false` until the first sample, which is taken as the estimate outright.
+    primed: bool,
+    /// When the current interval started.
+    since: Instant
+}
+
+impl AllocRate {
+    pub(crate) fn new() -> Self {
+        AllocRate {
+            rate: 0.0,
+            primed: false,
+            since: Instant::now()
+        }
+    }
+
+    /// Returns the estimate as of the last sample.
+    pub(crate) fn sampled(&self) -> f64 {
+        self.rate
+    }
+
+    /// Returns the estimate, taking into account `bytes` allocated in the
+    /// interval which is still in progress.
+    pub(crate) fn estimate(&self, bytes: usize, now: Instant) -> f64 {
+        let secs = now.saturating_duration_since(self.since).as_secs_f64();
+        if secs <= 0.0 {
+            return self.rate;
+        }
+        let sample = bytes as f64 / secs;
+        if !self.primed {
+            return sample;
+        }
+        let weight = 1.0 - 0.5f64.powf(secs / HALF_LIFE.as_secs_f64());
+        self.rate + weight * (sample - self.rate)
+    }
+
+    /// Ends the current interval, in which `bytes` were allocated.
+    pub(crate) fn sample(&mut self, bytes: usize, now: Instant) {
+        self.rate = self.estimate(bytes, now);
+        self.primed = true;
+        self.since = now;
+    }
+
+    /// Starts a new interval at `now`, without taking a sample. Time spent in
+    /// a collection is skipped this way, since the mutator can't allocate.
+    pub(crate) fn restart(&mut self, now: Instant) {
+        self.since = now;
+    }
+}
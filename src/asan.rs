@@ -0,0 +1,48 @@
+//! Manual AddressSanitizer poisoning of the GC heap, enabled by the `asan`
+//! feature. The feature only links when the program is built with
+//! `-Zsanitizer=address`; without it, every function here does nothing.
+//!
+//! ASan knows nothing of the collector's allocator: to it, a heap chunk is one
+//! large `alloc_zeroed` block which is never freed. So the runtime tells it
+//! which parts of a chunk the mutator may touch. A new chunk starts out
+//! poisoned. Formatting a block header unpoisons the header, and formatting
+//! an object unpoisons its body; the sweep poisons the body of every object it
+//! frees. Out-of-bounds accesses past the end of an object, and any access to
+//! an object after it has been collected, then land in poisoned memory.
+//!
+//! Headers are never poisoned once written, so the collector's heap walks
+//! never trip the sanitizer.
+
+#[cfg(feature = "asan")]
+mod imp {
+    use libc::c_void;
+
+    extern "C" {
+        fn __asan_poison_memory_region(addr: *const c_void, size: usize);
+        fn __asan_unpoison_memory_region(addr: *const c_void, size: usize);
+    }
+
+    #[inline]
+    pub(crate) fn poison(addr: usize, size: usize) {
+        unsafe { __asan_poison_memory_region(addr as *const c_void, size) };
+    }
+
+    #[inline]
+    pub(crate) fn unpoison(addr: usize, size: usize) {
+        unsafe { __asan_unpoison_memory_region(addr as *const c_void, size) };
+    }
+}
+
+#[cfg(not(feature = "asan"))]
+mod imp {
+    #[inline(always)]
+    pub(crate) fn poison(_addr: usize, _size: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn unpoison(_addr: usize, _size: usize) {}
+}
+
+/// Forbids the mutator from accessing `[addr, addr + size)`.
+pub(crate) use self::imp::poison;
+/// Allows the mutator to access `[addr, addr + size)` again.
+pub(crate) use self::imp::unpoison;
@@ -0,0 +1,118 @@
+//! A subset of the Boehm-Demers-Weiser collector's C API, so that a runtime
+//! built on Boehm can be moved onto this collector one piece at a time.
+//!
+//! Objects are scanned conservatively, as Boehm's are, but stacks are not:
+//! only stack slots described by the stackmaps of statepoint-compiled code
+//! are roots. A GC pointer which C code keeps on its stack across an
+//! allocation must be registered with `rgcrt_add_root` (or the collector
+//! disabled) for that long. Don't link this alongside libgc: the symbols
+//! clash.
+
+#![allow(non_snake_case)]
+
+use std::{os::raw::c_void, ptr};
+
+use crate::{
+    capi::{alloc_cleared, trace_words, ATOMIC_INFO, WORDS_INFO},
+    collector::{Header, TypeInfo, HDR},
+    COLLECTOR
+};
+
+/// The type of objects from `GC_malloc_uncollectable`, which are roots until
+/// they are passed to `GC_free`.
+static UNCOLLECTABLE_INFO: TypeInfo = TypeInfo {
+    name: |_| "GC_malloc_uncollectable",
+    trace: Some(trace_words),
+    conservative: true
+};
+
+unsafe fn header<'a>(obj: *const c_void) -> &'a Header {
+    &*((obj as usize - HDR) as *const Header)
+}
+
+#[no_mangle]
+pub extern "C" fn GC_init() {
+    // Boehm allows `GC_INIT()` to be called more than once.
+    let _ = crate::try_init();
+}
+
+#[no_mangle]
+pub extern "C" fn GC_malloc(size: usize) -> *mut c_void {
+    alloc_cleared(size, &WORDS_INFO)
+}
+
+#[no_mangle]
+pub extern "C" fn GC_malloc_atomic(size: usize) -> *mut c_void {
+    alloc_cleared(size, &ATOMIC_INFO)
+}
+
+#[no_mangle]
+pub extern "C" fn GC_malloc_uncollectable(size: usize) -> *mut c_void {
+    let obj = alloc_cleared(size, &UNCOLLECTABLE_INFO);
+    if !obj.is_null() {
+        COLLECTOR.with(|c| c.add_raw_root(obj as *mut u8));
+    }
+    obj
+}
+
+/// Allocates an object of `size` bytes of the same kind as `obj`, and copies
+/// as much of `obj` into it as fits.
+///
+/// # Safety
+///
+/// `obj` must be null or an object from one of the `GC_malloc` functions.
+#[no_mangle]
+pub unsafe extern "C" fn GC_realloc(obj: *mut c_void, size: usize) -> *mut c_void {
+    if obj.is_null() {
+        return GC_malloc(size);
+    }
+    let old_size = header(obj).size() - HDR;
+    let ty = header(obj).ty().unwrap();
+    // The caller's pointer to `obj` may not be visible to the collector.
+    COLLECTOR.with(|c| c.add_raw_root(obj as *mut u8));
+    let new = alloc_cleared(size, ty);
+    COLLECTOR.with(|c| c.remove_raw_root(obj as *mut u8));
+    if new.is_null() {
+        return new;
+    }
+    ptr::copy_nonoverlapping(obj as *const u8, new as *mut u8, old_size.min(size));
+    if ptr::eq(ty, &UNCOLLECTABLE_INFO) {
+        COLLECTOR.with(|c| {
+            c.add_raw_root(new as *mut u8);
+            c.remove_raw_root(obj as *mut u8);
+        });
+    }
+    new
+}
+
+/// Frees an uncollectable object. Other objects are left for the collector.
+///
+/// # Safety
+///
+/// `obj` must be null or an object from one of the `GC_malloc` functions.
+#[no_mangle]
+pub unsafe extern "C" fn GC_free(obj: *mut c_void) {
+    if !obj.is_null() && ptr::eq(header(obj).ty().unwrap(), &UNCOLLECTABLE_INFO) {
+        COLLECTOR.with(|c| c.remove_raw_root(obj as *mut u8));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn GC_gcollect() {
+    crate::force_collect();
+}
+
+#[no_mangle]
+pub extern "C" fn GC_disable() {
+    crate::disable();
+}
+
+#[no_mangle]
+pub extern "C" fn GC_enable() {
+    crate::enable();
+}
+
+#[no_mangle]
+pub extern "C" fn GC_get_heap_size() -> usize {
+    COLLECTOR.with(|c| c.heap_size())
+}
@@ -0,0 +1,193 @@
+//! The C ABI, for language frontends which target LLVM statepoints but aren't
+//! written in Rust. The runtime is built as a shared library with:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type cdylib
+//! ```
+//!
+//! `include/rgcrt.h` declares everything exported here. `RGCRT_ABI_VERSION` is
+//! incremented whenever an existing symbol changes incompatibly; adding symbols
+//! doesn't change it.
+//!
+//! Objects from `rgcrt_alloc` are scanned conservatively: every aligned word in
+//! them which holds the address of a GC object keeps that object alive. Objects
+//! from `rgcrt_alloc_atomic` are never scanned, so must not hold GC pointers.
+//! Both are zeroed. A pointer to a GC object kept anywhere other than a stack
+//! slot described by the stackmaps, or another GC object, must be registered
+//! with `rgcrt_add_root` until it is dropped.
+
+use std::{
+    ffi::CStr,
+    mem,
+    os::raw::{c_char, c_int, c_long, c_void},
+    ptr
+};
+
+use crate::{
+    collector::{AllocPolicy, Header, TypeInfo, HDR},
+    Tracer, COLLECTOR
+};
+
+pub const RGCRT_ABI_VERSION: u32 = 1;
+
+pub(crate) static WORDS_INFO: TypeInfo = TypeInfo {
+    name: |_| "rgcrt_alloc",
+    trace: Some(trace_words),
+    conservative: true
+};
+
+pub(crate) static ATOMIC_INFO: TypeInfo = TypeInfo {
+    name: |_| "rgcrt_alloc_atomic",
+    trace: None,
+    conservative: false
+};
+
+/// Reports every word of the object at `obj` to `tracer`. Marking ignores the
+/// ones which aren't GC pointers.
+pub(crate) unsafe fn trace_words(obj: *const u8, tracer: &mut Tracer) {
+    let hdr = &*((obj as usize - HDR) as *const Header);
+    let words = (hdr.size() - HDR) / mem::size_of::<usize>();
+    for i in 0..words {
+        tracer.trace(*(obj as *const *const u8).add(i));
+    }
+}
+
+/// Allocates a zeroed object with room for `size` bytes, of type `ty`, or
+/// returns null if the heap is exhausted.
+pub(crate) fn alloc_cleared(size: usize, ty: &'static TypeInfo) -> *mut c_void {
+    match COLLECTOR.alloc_block(size, ty, AllocPolicy::Collect) {
+        Ok(obj) => {
+            // Zero the whole block, not just `size` bytes: the conservative
+            // scan reads all of it.
+            unsafe {
+                let hdr = &*((obj as usize - HDR) as *const Header);
+                ptr::write_bytes(obj, 0, hdr.size() - HDR);
+            }
+            obj as *mut c_void
+        }
+        Err(e) => {
+            error!("{}", e);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_abi_version() -> u32 {
+    RGCRT_ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_init() {
+    crate::init();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_register_thread() {
+    crate::register_thread();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_unregister_thread() {
+    crate::unregister_thread();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_enter_native() {
+    crate::enter_native();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_exit_native() {
+    crate::exit_native();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_safepoint_poll() {
+    crate::safepoint_poll();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_poll_flag() -> *const usize {
+    crate::gcrt_poll_flag()
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_alloc(size: usize) -> *mut c_void {
+    alloc_cleared(size, &WORDS_INFO)
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_alloc_atomic(size: usize) -> *mut c_void {
+    alloc_cleared(size, &ATOMIC_INFO)
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_add_root(obj: *const c_void) {
+    COLLECTOR.with(|c| c.add_raw_root(obj as *mut u8));
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_remove_root(obj: *const c_void) {
+    COLLECTOR.with(|c| c.remove_raw_root(obj as *mut u8));
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_write_barrier(obj: *const c_void) {
+    crate::write_barrier(obj);
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_collect() {
+    crate::force_collect();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_disable() {
+    crate::disable();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_enable() {
+    crate::enable();
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_is_gc_ptr(addr: *const c_void) -> bool {
+    crate::is_gc_ptr(addr)
+}
+
+/// # Safety
+///
+/// As for `dlopen`, and `filename` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_dlopen(filename: *const c_char, flags: c_int) -> *mut c_void {
+    crate::dlopen(CStr::from_ptr(filename), flags)
+}
+
+/// # Safety
+///
+/// As for `dlclose`.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_dlclose(handle: *mut c_void) -> c_int {
+    crate::dlclose(handle)
+}
+
+/// # Safety
+///
+/// `handle` must have been returned by `dlopen`, and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_library_loaded(handle: *mut c_void) -> c_long {
+    match crate::library_loaded(handle) {
+        Ok(n) => n as c_long,
+        Err(e) => {
+            error!("{}", e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_forget_unloaded_libraries() {
+    crate::forget_unloaded_libraries();
+}
@@ -0,0 +1,178 @@
+//! Export of GC activity as Chrome trace events, which can be loaded into
+//! Perfetto or `chrome://tracing`.
+//!
+//! Recording is enabled by setting `RGCRT_TRACE_EVENTS` to the path of the
+//! JSON file to write when the process exits. Every collection is recorded as
+//! a span on the collecting thread, with its phases nested inside, and as a
+//! sample of the allocation rate since the previous collection.
+//!
+//! Timestamps are taken from `CLOCK_MONOTONIC`, in microseconds, so that the
+//! events line up with traces recorded by other tools using the same clock.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+/// When the most recent collection finished, in microseconds.
+static LAST_GC_END: AtomicU64 = AtomicU64::new(0);
+
+/// The path given in `RGCRT_TRACE_EVENTS`, written at exit.
+static EXIT_PATH: Mutex<Option<String>> = Mutex::new(None);
+
+enum Event {
+    /// A span of time on one thread.
+    Complete {
+        name: &'static str,
+        tid: u64,
+        ts: u64,
+        dur: u64
+    },
+    /// A sample of one or more values, drawn as a graph.
+    Counter {
+        name: &'static str,
+        ts: u64,
+        values: Vec<(&'static str, u64)>
+    }
+}
+
+fn events() -> MutexGuard<'static, Vec<Event>> {
+    EVENTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn start() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The current time in microseconds, or 0 if recording is off.
+#[inline]
+pub(crate) fn now() -> u64 {
+    if !enabled() {
+        return 0;
+    }
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+/// Records a span called `name` on the calling thread, from `start` (as
+/// returned by `now()`) until now. Returns the end of the span.
+pub(crate) fn span(name: &'static str, start: u64) -> u64 {
+    if !enabled() {
+        return 0;
+    }
+    let end = now();
+    let tid = unsafe { libc::gettid() } as u64;
+    events().push(Event::Complete {
+        name,
+        tid,
+        ts: start,
+        dur: end.saturating_sub(start)
+    });
+    end
+}
+
+/// Records the start of a collection, after `allocated` bytes had been
+/// allocated since the last one.
+pub(crate) fn collection_start(ts: u64, allocated: usize) {
+    if !enabled() {
+        return;
+    }
+    let last = LAST_GC_END.load(Ordering::Relaxed);
+    let mut values = vec![("allocated since last GC", allocated as u64)];
+    if last != 0 && ts > last {
+        // Bytes per microsecond is the same as megabytes per second.
+        values.push(("MB/s", allocated as u64 / (ts - last)));
+    }
+    events().push(Event::Counter {
+        name: "allocation",
+        ts,
+        values
+    });
+}
+
+/// Records the end of the collection which started at `start`.
+pub(crate) fn collection_end(start: u64) {
+    if !enabled() {
+        return;
+    }
+    LAST_GC_END.store(span("collection", start), Ordering::Relaxed);
+}
+
+/// Writes the events recorded so far to `w` as a JSON array of trace events.
+pub(crate) fn write_to<W: Write>(w: &mut W) -> io::Result<()> {
+    let pid = unsafe { libc::getpid() };
+    let events = events();
+    writeln!(w, "[")?;
+    for (i, e) in events.iter().enumerate() {
+        let sep = if i + 1 == events.len() { "" } else { "," };
+        match e {
+            Event::Complete { name, tid, ts, dur } => writeln!(
+                w,
+                r#"{{"name":"{}","cat":"gc","ph":"X","pid":{},"tid":{},"ts":{},"dur":{}}}{}"#,
+                name, pid, tid, ts, dur, sep
+            )?,
+            Event::Counter { name, ts, values } => {
+                let args: Vec<String> = values
+                    .iter()
+                    .map(|(k, v)| format!(r#""{}":{}"#, k, v))
+                    .collect();
+                writeln!(
+                    w,
+                    r#"{{"name":"{}","cat":"gc","ph":"C","pid":{},"ts":{},"args":{{{}}}}}{}"#,
+                    name,
+                    pid,
+                    ts,
+                    args.join(","),
+                    sep
+                )?
+            }
+        }
+    }
+    writeln!(w, "]")
+}
+
+pub(crate) fn save<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_to(&mut w)?;
+    w.flush()
+}
+
+/// Starts recording if `RGCRT_TRACE_EVENTS` is set, and arranges for the
+/// events to be written to the file it names when the process exits.
+pub(crate) fn install_from_env() {
+    let path = match env::var("RGCRT_TRACE_EVENTS") {
+        Ok(p) if !p.is_empty() => p,
+        _ => return
+    };
+    *EXIT_PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path);
+    start();
+    unsafe { libc::atexit(exit_save) };
+}
+
+extern "C" fn exit_save() {
+    let path = EXIT_PATH.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(path) = path {
+        if let Err(e) = save(&path) {
+            eprintln!("rgcrt: can't write trace events to {}: {}", path, e);
+        }
+    }
+}
@@ -1,77 +1,2676 @@
 use std::{
-    alloc::{alloc, Layout},
-    cell::{Cell, UnsafeCell},
-    collections::HashMap,
-    path::Path
+    alloc::{alloc_zeroed, Layout},
+    any,
+    cell::{Cell, RefCell, UnsafeCell},
+    collections::{HashMap, VecDeque},
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::Range,
+    path::Path,
+    process,
+    ptr::{self, NonNull},
+    slice,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Mutex, MutexGuard, TryLockError
+    },
+    thread,
+    time::{Duration, Instant}
 };
 
+#[cfg(feature = "capi")]
+use crate::embed;
+#[cfg(feature = "verify")]
+use crate::verify;
 use crate::{
-    safepoints::{ReturnAddress, SafepointRoots},
-    Scan, GcErr
+    alloc_rate::AllocRate,
+    asan, chrome_trace,
+    crash::{self, Phase},
+    deque::{Deque, Steal, Terminator},
+    dump::{DumpObject, HeapDump},
+    ephemeron,
+    frames::{self, Frames},
+    info::{GcInfo, GcKind, GcReason, TypeCensus},
+    intern, leaks,
+    log::{self, Level},
+    pauses::PauseHistogram,
+    profile, replay,
+    retention::{RetainedObject, RetainingPath, Root},
+    safepoints::{gen_library_safepoint_table, gen_safepoint_table, ReturnAddress, SafepointRoots},
+    stress,
+    threads::{self, StoppedThread},
+    tlab, unrooted, valgrind, workers, GcErr, Scan, Tracer
 };
 
-/// The size of the heap in bytes
+/// The size of a heap chunk in bytes. Objects larger than this get a chunk of
+/// their own.
 const HSIZE: usize = 1024;
 
-/// The byte alignment of the heap
-const HALIGN: usize = 4;
+/// The default number of bytes which can be allocated between collections
+/// before the next safepoint poll is armed to collect.
+const DEFAULT_TRIGGER: usize = 8 * HSIZE;
+
+/// The default fraction of the heap limit which survivors must occupy for a
+/// collection to count as under memory pressure.
+const DEFAULT_SOFT_OCCUPANCY: f64 = 0.9;
+
+/// The number of consecutive collections under memory pressure after which
+/// soft references are cleared.
+const SOFT_PRESSURE_COLLECTIONS: usize = 2;
+
+/// The minimum number of chunks each sweeper thread is given. Heaps with fewer
+/// than twice this many chunks are swept on the collecting thread.
+const SWEEP_CHUNKS_PER_WORKER: usize = 8;
+
+/// The minimum number of roots each marking worker is given. Collections with
+/// fewer than twice this many roots are marked on the collecting thread.
+const MARK_ROOTS_PER_WORKER: usize = 256;
+
+/// The byte alignment of the heap. Every object header, and therefore every
+/// object, starts on a boundary of this size.
+pub(crate) const HALIGN: usize = 16;
+
+/// The size of an object header. Objects are laid out immediately after their
+/// header.
+pub(crate) const HDR: usize = mem::size_of::<Header>();
+
+/// The size of the thread-local allocation buffers handed to each thread.
+const TLAB_SIZE: usize = HSIZE / 4;
+
+/// Blocks larger than this are always allocated directly from the shared heap,
+/// so that a TLAB isn't wasted on a single object.
+const TLAB_MAX_BLOCK: usize = TLAB_SIZE / 2;
+
+/// The smallest free block worth splitting off from the remainder of an
+/// allocation.
+const MIN_BLOCK: usize = HDR + HALIGN;
+
+/// Every block in the GC heap -- allocated or free -- starts with a `Header`.
+/// Walking a chunk from its start, following `size`, visits every block.
+#[repr(C, align(16))]
+pub(crate) struct Header {
+    /// The size of the block in bytes, including this header.
+    size: usize,
+    /// Describes the object's type. `None` for free blocks.
+    ty: Option<&'static TypeInfo>,
+    /// Set during marking when the object is found to be reachable.
+    marked: AtomicBool,
+    /// `false` for free blocks.
+    live: Cell<bool>,
+    /// Set once the object's destructor has run. The object is kept for as
+    /// long as it is reachable, but is no longer traced, since what it held
+    /// has been dropped.
+    finalised: Cell<bool>
+}
+
+/// Describes the type of a GC object. Every object of a given type points to
+/// the same `TypeInfo`.
+pub(crate) struct TypeInfo {
+    /// Returns the name of the type, for diagnostics. It is passed the
+    /// `TypeInfo` itself so that types described at run time can find theirs.
+    pub(crate) name: fn(&TypeInfo) -> &'static str,
+    /// Reports the GC pointers held by an object of the type. `None` for types
+    /// which can't contain GC pointers.
+    pub(crate) trace: Option<unsafe fn(*const u8, &mut Tracer)>,
+    /// Set if `trace` reports words which may not be pointers at all, as for
+    /// objects allocated through the C API. Marking ignores those anyway, but
+    /// the verifier must not treat them as dangling.
+    #[cfg_attr(not(feature = "verify"), allow(dead_code))]
+    pub(crate) conservative: bool
+}
+
+impl TypeInfo {
+    #[inline]
+    pub(crate) fn name(&self) -> &'static str {
+        (self.name)(self)
+    }
+}
+
+/// The `TypeInfo`s for objects of type `T`. These are constants so that a
+/// reference to one is promoted to a `'static`.
+pub(crate) struct TypeInfoOf<T>(PhantomData<T>);
+
+impl<T: Scan> TypeInfoOf<T> {
+    const TRACED: TypeInfo = TypeInfo {
+        name: |_| any::type_name::<T>(),
+        trace: Some(trace_obj::<T>),
+        conservative: false
+    };
+    const UNTRACED: TypeInfo = TypeInfo {
+        name: |_| any::type_name::<T>(),
+        trace: None,
+        conservative: false
+    };
+
+    pub(crate) fn get() -> &'static TypeInfo {
+        if T::needs_trace() {
+            &Self::TRACED
+        } else {
+            &Self::UNTRACED
+        }
+    }
+}
+
+/// What an allocation may do when the heap has no free block large enough.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AllocPolicy {
+    /// Collect, then grow the heap, and only then fail.
+    Collect,
+    /// Fail immediately with `GcErr::HeapFull`.
+    NoCollect
+}
+
+impl Header {
+    /// The size of the block in bytes, including the header.
+    #[cfg(any(feature = "verify", feature = "capi"))]
+    #[inline]
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub(crate) fn is_marked(&self) -> bool {
+        self.marked.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn ty(&self) -> Option<&'static TypeInfo> {
+        self.ty
+    }
+
+    #[inline]
+    pub(crate) fn is_finalised(&self) -> bool {
+        self.finalised.get()
+    }
+
+    /// The function which reports the object's GC pointers, or `None` if it
+    /// has none to report.
+    #[inline]
+    pub(crate) fn trace_fn(&self) -> Option<unsafe fn(*const u8, &mut Tracer)> {
+        if self.finalised.get() {
+            return None;
+        }
+        self.ty.and_then(|t| t.trace)
+    }
+
+    /// Formats `block` as a live object of `size` bytes (including the header).
+    #[inline]
+    pub(crate) unsafe fn init(block: *mut Header, size: usize, ty: &'static TypeInfo) {
+        asan::unpoison(block.addr(), size);
+        valgrind::make_undefined(block.addr(), HDR);
+        valgrind::alloc(block.addr() + HDR, size - HDR);
+        ptr::write(
+            block,
+            Header {
+                size,
+                ty: Some(ty),
+                marked: AtomicBool::new(false),
+                live: Cell::new(true),
+                finalised: Cell::new(false)
+            }
+        );
+    }
+
+    /// Formats `block` as a free block of `size` bytes.
+    #[inline]
+    pub(crate) unsafe fn init_free(block: *mut Header, size: usize) {
+        asan::unpoison(block.addr(), HDR);
+        valgrind::make_undefined(block.addr(), HDR);
+        ptr::write(
+            block,
+            Header {
+                size,
+                ty: None,
+                marked: AtomicBool::new(false),
+                live: Cell::new(false),
+                finalised: Cell::new(false)
+            }
+        );
+    }
+}
+
+#[cfg(feature = "verify")]
+impl Header {
+    #[inline]
+    pub(crate) fn is_live(&self) -> bool {
+        self.live.get()
+    }
+}
+
+/// A contiguous region of memory obtained from the system allocator. The
+/// region starts with the chunk's object-start bitmap (see `start_bit()`),
+/// which is followed by the blocks.
+struct Chunk {
+    /// The start of the region, which every pointer into it is derived from.
+    base: NonNull<u8>,
+    /// The address of the first block.
+    start: usize,
+    end: usize,
+    /// The bump pointer: memory in `[top, end)` has never been allocated, and
+    /// is zeroed. The newest chunk is bumped without the collector lock.
+    top: AtomicUsize
+}
+
+impl Chunk {
+    #[inline]
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    #[inline]
+    fn top(&self) -> usize {
+        self.top.load(Ordering::Acquire)
+    }
+
+    /// Claims `size` bytes from the end of the allocated part of the chunk,
+    /// or returns `None` if the chunk doesn't have room.
+    fn bump(&self, size: usize) -> Option<*mut Header> {
+        let mut top = self.top.load(Ordering::Relaxed);
+        loop {
+            let new = match top.checked_add(size) {
+                Some(new) if new <= self.end => new,
+                _ => return None
+            };
+            match self
+                .top
+                .compare_exchange_weak(top, new, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(self.base.as_ptr().with_addr(top).cast()),
+                Err(t) => top = t
+            }
+        }
+    }
+}
+
+/// The newest chunk, from which registered threads refill their TLABs with a
+/// CAS on the chunk's `top` rather than by taking the collector lock. Chunks
+/// are boxed and never freed, so the pointer stays valid.
+static BUMP_CHUNK: AtomicPtr<Chunk> = AtomicPtr::new(ptr::null_mut());
+
+/// Bytes handed out from `BUMP_CHUNK` without the lock since the last
+/// collection.
+static UNLOCKED_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Once `UNLOCKED_ALLOCATED` would exceed this, TLABs are refilled under the
+/// lock again, so that the collection trigger is checked. Recomputed by
+/// `check_trigger`.
+static UNLOCKED_BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct SweepStats {
+    bytes_reclaimed: usize,
+    survivors: usize,
+    survivor_bytes: usize,
+    /// If a census was requested, the number of surviving objects and bytes,
+    /// keyed by the address of their `TypeInfo`.
+    census: Option<HashMap<usize, (usize, usize)>>
+}
+
+impl SweepStats {
+    fn add(&mut self, s: SweepStats) {
+        self.bytes_reclaimed += s.bytes_reclaimed;
+        self.survivors += s.survivors;
+        self.survivor_bytes += s.survivor_bytes;
+        if let Some(chunk_census) = s.census {
+            let census = self.census.get_or_insert_with(HashMap::new);
+            for (ty, (objects, bytes)) in chunk_census {
+                let entry = census.entry(ty).or_default();
+                entry.0 += objects;
+                entry.1 += bytes;
+            }
+        }
+    }
+}
+
+/// The buffers a collection uses while the world is stopped. A thread stopped
+/// by a signal may hold the global allocator's lock, so nothing is allocated
+/// until the world resumes: the buffers are kept from one collection to the
+/// next, and grown by `Collector::prepare_pause` before the world is stopped.
+/// A census, a heap snapshot and `why_alive` do allocate, so they stop the
+/// world with `threads::stop_at_safepoints` instead.
+struct Scratch {
+    stopped: Vec<StoppedThread>,
+    safepoints: Vec<usize>,
+    tracer: Tracer,
+    ranges: Vec<(usize, usize)>,
+    /// The threads which can mark or sweep, as found by `prepare_pause`, which
+    /// is the only place it is safe to ask.
+    parallelism: usize,
+    /// The capacity of the mark stack for the next collection, and whether
+    /// the last one overflowed it.
+    mark_stack: usize,
+    overflowed: bool,
+    /// A work-stealing deque and a tracer for each marking worker.
+    deques: Vec<Deque>,
+    tracers: Vec<Mutex<Tracer>>,
+    /// The free blocks found, and the statistics gathered, by each sweeper.
+    sweepers: usize,
+    swept: Vec<Mutex<(Vec<usize>, SweepStats)>>,
+    /// The number of roots the last collection found.
+    roots: usize
+}
+
+impl Scratch {
+    fn new() -> Self {
+        Scratch {
+            stopped: Vec::new(),
+            safepoints: Vec::new(),
+            tracer: Tracer::bounded(),
+            ranges: Vec::new(),
+            parallelism: 1,
+            mark_stack: MIN_MARK_STACK,
+            overflowed: false,
+            deques: Vec::new(),
+            tracers: Vec::new(),
+            sweepers: 1,
+            swept: Vec::new(),
+            roots: 0
+        }
+    }
+}
+
+/// The initial capacity of the mark stack: the collecting thread's worklist,
+/// and each marking worker's deque and worklist. It is doubled before the
+/// collection after one which overflowed it.
+#[cfg(not(test))]
+const MIN_MARK_STACK: usize = 4096;
+/// Small enough that the tests overflow it.
+#[cfg(test)]
+const MIN_MARK_STACK: usize = 64;
+
+/// The allocated part of each chunk while the world is stopped, in which
+/// `mark_overflowed` looks for objects. Empty at other times.
+static MARK_RANGES: AtomicPtr<(usize, usize)> = AtomicPtr::new(ptr::null_mut());
+static MARK_RANGES_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The lowest address of an object which `mark_overflowed` has marked but
+/// which hasn't been traced, or `usize::MAX` if there is none.
+static OVERFLOW_FROM: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn set_mark_ranges(ranges: &[(usize, usize)]) {
+    MARK_RANGES.store(ranges.as_ptr() as *mut _, Ordering::Release);
+    MARK_RANGES_LEN.store(ranges.len(), Ordering::Release);
+}
+
+/// Deals with a pointer which was found while the mark stack was full, by
+/// marking the object it refers to without tracing it. Once the mark stack has
+/// been drained, the heap is rescanned from the lowest such object, tracing
+/// every marked object, so marking completes however deep or wide the object
+/// graph is.
+pub(crate) fn mark_overflowed(obj: usize) {
+    let ranges = unsafe {
+        slice::from_raw_parts(
+            MARK_RANGES.load(Ordering::Acquire),
+            MARK_RANGES_LEN.load(Ordering::Acquire)
+        )
+    };
+    debug_assert!(
+        !ranges.is_empty(),
+        "the mark stack overflowed outside a collection"
+    );
+    if let Some(hdr) = unsafe { find_header_in(ranges, obj) } {
+        // An object with nothing to trace is done with once it is marked.
+        if !unsafe { (*hdr).marked.swap(true, Ordering::AcqRel) }
+            && unsafe { (*hdr).trace_fn() }.is_some()
+        {
+            OVERFLOW_FROM.fetch_min(obj, Ordering::AcqRel);
+        }
+    }
+}
+
+/// The most free blocks a sweep can find in `[start, end)`: free blocks are
+/// coalesced, so each is followed by a live block, and no block is smaller
+/// than a header.
+fn max_free_blocks(start: usize, end: usize) -> usize {
+    (end - start) / (2 * HDR) + 1
+}
+
+/// Empties `v`, and ensures that it can then hold `n` elements without
+/// reallocating.
+fn make_room<T>(v: &mut Vec<T>, n: usize) {
+    v.clear();
+    v.reserve(n);
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Turns the address of something in the heap back into a pointer. The heap's
+/// addresses are passed around as integers -- in free lists, mark stacks and
+/// tracers -- so every chunk's provenance is exposed when it is allocated.
+#[inline]
+pub(crate) fn heap_ptr<T>(addr: usize) -> *mut T {
+    ptr::with_exposed_provenance_mut(addr)
+}
+
+/// The size of the object-start bitmap which precedes a chunk of `size` bytes.
+fn bitmap_size(size: usize) -> usize {
+    round_up((size / HALIGN).div_ceil(64) * 8, HALIGN)
+}
+
+/// Returns the word of the object-start bitmap of the chunk starting at
+/// `chunk` which holds the bit for the block at `block`, and that bit. Each
+/// chunk has a bit for every `HALIGN` bytes, set where a live object's header
+/// starts. The words are laid out backwards from the chunk's start, so that the
+/// bitmap can be found from the start alone.
+///
+/// # Safety
+///
+/// `chunk` must be the start of a heap chunk which contains `block`.
+#[inline]
+unsafe fn start_bit(chunk: usize, block: usize) -> (&'static AtomicU64, u64) {
+    let i = (block - chunk) / HALIGN;
+    let word = &*heap_ptr::<AtomicU64>(chunk - 8 * (i / 64 + 1));
+    (word, 1 << (i % 64))
+}
+
+/// Records that `block`, in the chunk starting at `chunk`, has been formatted
+/// as a live object.
+///
+/// # Safety
+///
+/// As for `start_bit()`.
+#[inline]
+pub(crate) unsafe fn set_object_start(chunk: usize, block: usize) {
+    let (word, bit) = start_bit(chunk, block);
+    word.fetch_or(bit, Ordering::Release);
+}
+
+/// Returns the header of the live object starting at `addr`, if it lies within
+/// one of the chunks `[start, top)` in `ranges`.
+///
+/// # Safety
+///
+/// Every range must be the allocated part of a heap chunk, and the ranges must
+/// be sorted by address.
+pub(crate) unsafe fn find_header_in(ranges: &[(usize, usize)], addr: usize) -> Option<*mut Header> {
+    let i = ranges.partition_point(|&(start, _)| start < addr);
+    let (start, top) = *ranges.get(i.checked_sub(1)?)?;
+    header_at(start, top, addr)
+}
+
+/// Returns the header of the live object starting at `addr`, if there is one
+/// in the allocated part `[start, top)` of a heap chunk.
+///
+/// # Safety
+///
+/// `[start, top)` must be the allocated part of a heap chunk.
+#[inline]
+unsafe fn header_at(start: usize, top: usize, addr: usize) -> Option<*mut Header> {
+    if !addr.is_multiple_of(HALIGN) || addr < start + HDR || addr >= top {
+        return None;
+    }
+    let (word, bit) = start_bit(start, addr - HDR);
+    if word.load(Ordering::Acquire) & bit == 0 {
+        return None;
+    }
+    Some(heap_ptr(addr - HDR))
+}
+
+/// Marks the object at `obj`, if it is a live object which hasn't been marked
+/// yet, and reports its children to `tracer`. Safe to call concurrently from
+/// several marking workers: each object is traced exactly once.
+unsafe fn mark_obj(ranges: &[(usize, usize)], obj: *mut u8, tracer: &mut Tracer) {
+    let hdr = match find_header_in(ranges, obj.addr()) {
+        Some(hdr) => hdr,
+        None => return
+    };
+    if (*hdr).marked.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Some(trace) = (*hdr).trace_fn() {
+        trace(obj, tracer);
+    }
+}
+
+fn mark_worker(
+    id: usize,
+    roots: &[usize],
+    deques: &[Deque],
+    term: &Terminator,
+    ranges: &[(usize, usize)],
+    tracer: &mut Tracer
+) {
+    let own = &deques[id];
+    let push = |obj| {
+        if !own.push(obj) {
+            mark_overflowed(obj);
+        }
+    };
+    for &r in roots {
+        push(r);
+    }
+    loop {
+        while let Some(obj) = own.pop() {
+            unsafe { mark_obj(ranges, heap_ptr(obj), tracer) };
+            while let Some(child) = tracer.pop() {
+                push(child.addr());
+            }
+        }
+        match steal_any(id, deques) {
+            Some(obj) => push(obj),
+            None => {
+                if term.offer_termination(deques) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Tries to steal from every deque other than `id`'s, returning `None` only if
+/// they were all seen to be empty.
+fn steal_any(id: usize, deques: &[Deque]) -> Option<usize> {
+    loop {
+        let mut retry = false;
+        for (i, d) in deques.iter().enumerate() {
+            if i == id {
+                continue;
+            }
+            match d.steal() {
+                Steal::Success(obj) => return Some(obj),
+                Steal::Retry => retry = true,
+                Steal::Empty => ()
+            }
+        }
+        if !retry {
+            return None;
+        }
+    }
+}
+
+/// Gives the calling thread a fresh TLAB by bumping `BUMP_CHUNK`, without
+/// taking the collector lock. Returns `false` if the lock must be taken instead.
+fn refill_tlab_unlocked() -> bool {
+    // The thread must not be suspended between claiming the block and
+    // formatting it, or the heap would be unwalkable during the collection.
+    threads::no_suspend(|| {
+        let mut used = UNLOCKED_ALLOCATED.load(Ordering::Relaxed);
+        loop {
+            if used + TLAB_SIZE > UNLOCKED_BUDGET.load(Ordering::Relaxed) {
+                return false;
+            }
+            match UNLOCKED_ALLOCATED.compare_exchange_weak(
+                used,
+                used + TLAB_SIZE,
+                Ordering::Relaxed,
+                Ordering::Relaxed
+            ) {
+                Ok(_) => break,
+                Err(u) => used = u
+            }
+        }
+        let chunk = match unsafe { BUMP_CHUNK.load(Ordering::Acquire).as_ref() } {
+            Some(chunk) => chunk,
+            None => return false
+        };
+        let block = match chunk.bump(TLAB_SIZE) {
+            Some(block) => block,
+            None => {
+                UNLOCKED_ALLOCATED.fetch_sub(TLAB_SIZE, Ordering::Relaxed);
+                return false;
+            }
+        };
+        unsafe {
+            Header::init_free(block, TLAB_SIZE);
+            tlab::refill(block, block.addr() + TLAB_SIZE, chunk.start());
+        }
+        true
+    })
+}
+
+/// Sweeps the blocks in `[start, top)`, adding the addresses of the
+/// (coalesced) free blocks found to `free`. If `census` is set, survivors are
+/// also tallied by type.
+///
+/// # Safety
+///
+/// `[start, top)` must be the allocated part of a heap chunk, and the world
+/// must be stopped.
+unsafe fn sweep_chunk(start: usize, top: usize, census: bool, free: &mut Vec<usize>) -> SweepStats {
+    let mut stats = SweepStats::default();
+    if census {
+        stats.census = Some(HashMap::new());
+    }
+    let mut cur = start;
+    let mut run: Option<*mut Header> = None;
+    while cur < top {
+        let hdr = heap_ptr::<Header>(cur);
+        let size = (*hdr).size;
+        let was_live = (*hdr).live.get();
+        let live = was_live && (*hdr).marked.load(Ordering::Relaxed);
+        if live {
+            stats.survivors += 1;
+            stats.survivor_bytes += size;
+            if let (Some(census), Some(ty)) = (stats.census.as_mut(), (*hdr).ty) {
+                let entry = census.entry(ty as *const TypeInfo as usize).or_default();
+                entry.0 += 1;
+                entry.1 += size;
+            }
+        } else if was_live {
+            let (word, bit) = start_bit(start, cur);
+            word.fetch_and(!bit, Ordering::Relaxed);
+            stats.bytes_reclaimed += size;
+            asan::poison(cur + HDR, size - HDR);
+            valgrind::free(cur + HDR);
+        }
+        (*hdr).marked.store(false, Ordering::Relaxed);
+        (*hdr).live.set(live);
+        if live {
+            if let Some(r) = run.take() {
+                #[cfg(feature = "verify")]
+                verify::poison(r);
+                free.push(r.addr());
+            }
+        } else {
+            match run {
+                Some(r) => (*r).size += size,
+                None => run = Some(hdr)
+            }
+        }
+        cur += size;
+    }
+    if let Some(r) = run {
+        #[cfg(feature = "verify")]
+        verify::poison(r);
+        free.push(r.addr());
+    }
+    stats
+}
+
+unsafe fn trace_obj<T: Scan>(obj: *const u8, tracer: &mut Tracer) {
+    (*(obj as *const T)).scan(tracer);
+}
+
+#[inline]
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+/// The largest object which can be allocated: with its header and padding, a
+/// bigger one couldn't be described by a `Layout`.
+const MAX_OBJECT: usize = isize::MAX as usize - HDR - HALIGN;
+
+/// The size of the heap block (including the header) needed for an object of
+/// `size` bytes, or `None` if no heap could hold one.
+#[inline]
+fn block_size(size: usize) -> Option<usize> {
+    if size > MAX_OBJECT {
+        return None;
+    }
+    Some(HDR + round_up(size, HALIGN))
+}
+
+/// The objects allocated with a type which needs dropping, the function which
+/// drops each, and the id of the thread which allocated it. Threads push to it
+/// with suspension deferred, so a stopped thread never holds the lock.
+static DROPS: Mutex<Vec<(usize, DropFn, usize)>> = Mutex::new(Vec::new());
+
+/// Unreachable objects from `DROPS` whose destructors are waiting to run on the
+/// threads which allocated them, since the value being dropped may not be
+/// `Send`. They are roots until their destructors have run. Locked, like
+/// `DROPS`, only with suspension deferred.
+static PENDING: Mutex<Vec<(usize, DropFn, usize)>> = Mutex::new(Vec::new());
+
+type DropFn = unsafe fn(*mut u8);
+
+fn drops() -> MutexGuard<'static, Vec<(usize, DropFn, usize)>> {
+    lock(&DROPS)
+}
+
+fn pending() -> MutexGuard<'static, Vec<(usize, DropFn, usize)>> {
+    lock(&PENDING)
+}
+
+unsafe fn drop_obj<T>(obj: *mut u8) {
+    ptr::drop_in_place(obj as *mut T);
+}
+
+thread_local!(static IN_COLLECTOR: Cell<bool> = const { Cell::new(false) });
+
+// Set on the collecting thread, and on the marking workers, while the heap is
+// being traced or swept. `Scan` impls run then, and must neither allocate nor
+// start a collection: either would bump a chunk or a TLAB which the collector
+// is part way through walking. This is per thread rather than a field of
+// `Collector` so that the lock-free allocation path can check it too.
+thread_local!(static COLLECTING: Cell<bool> = const { Cell::new(false) });
+
+/// Marks the calling thread as collecting until dropped.
+struct Collecting;
+
+impl Collecting {
+    fn enter(what: &str) -> Self {
+        forbid_reentry(what);
+        COLLECTING.with(|c| c.set(true));
+        Collecting
+    }
+}
+
+impl Drop for Collecting {
+    fn drop(&mut self) {
+        COLLECTING.with(|c| c.set(false));
+    }
+}
+
+// Set on the collecting thread while it runs destructors, which happens once
+// the world has resumed and the heap has been swept. Destructors may allocate,
+// but a collection started from one would free objects whose destructors are
+// still queued, so it is put off until the next safepoint poll instead.
+thread_local!(static FINALISING: Cell<bool> = const { Cell::new(false) });
+
+/// Lifts `Collecting` on the calling thread while destructors run, until
+/// dropped.
+struct Finalising;
+
+impl Finalising {
+    fn enter() -> Self {
+        COLLECTING.with(|c| c.set(false));
+        FINALISING.with(|f| f.set(true));
+        Finalising
+    }
+}
+
+impl Drop for Finalising {
+    fn drop(&mut self) {
+        FINALISING.with(|f| f.set(false));
+        COLLECTING.with(|c| c.set(true));
+    }
+}
+
+fn is_marked(obj: usize) -> bool {
+    unsafe { (*heap_ptr::<Header>(obj - HDR)).is_marked() }
+}
+
+fn is_finalised(obj: usize) -> bool {
+    unsafe { (*heap_ptr::<Header>(obj - HDR)).is_finalised() }
+}
+
+/// Moves the elements of `v` for which `f` is true to its front, returning how
+/// many there are.
+fn partition<T>(v: &mut [T], f: impl Fn(&T) -> bool) -> usize {
+    let mut n = 0;
+    for i in 0..v.len() {
+        if f(&v[i]) {
+            v.swap(n, i);
+            n += 1;
+        }
+    }
+    n
+}
+
+/// Runs the destructors of the objects in `dead`, a range of `DROPS` found by
+/// `find_dead_drops`, in order, so that an object's destructor runs before
+/// those of anything it refers to. This happens after the world has resumed,
+/// since destructors are free to use the global allocator, and the GC heap.
+/// The objects are reclaimed by the next collection which finds them
+/// unreachable: a destructor can resurrect its object, which is then kept, but
+/// never dropped again.
+///
+/// Only the objects which the calling thread allocated are dropped here. The
+/// rest are queued in `PENDING` for the threads which allocated them.
+fn run_destructors(dead: Range<usize>) {
+    let me = threads::current_id();
+    let (mine, others): (Vec<_>, Vec<_>) =
+        threads::no_suspend(|| drops().drain(dead).partition(|d| me != 0 && d.2 == me));
+    debug!(
+        "running {} destructors, and queuing {} for other threads",
+        mine.len(),
+        others.len()
+    );
+    if !others.is_empty() {
+        let mut owners = others.iter().map(|d| d.2).collect::<Vec<_>>();
+        owners.sort_unstable();
+        owners.dedup();
+        threads::no_suspend(|| pending().extend(others));
+        for owner in owners {
+            if !threads::request_finalise(owner) {
+                threads::no_suspend(|| forget_pending(owner));
+            }
+        }
+    }
+    let _finalising = Finalising::enter();
+    for (obj, drop, _) in mine {
+        unsafe { finalise(obj, drop) };
+    }
+    run_pending_destructors();
+}
+
+/// Drops the object `obj` with `drop`.
+unsafe fn finalise(obj: usize, drop: DropFn) {
+    // Set first, so that a destructor which stores its object somewhere
+    // reachable doesn't have it traced.
+    (*heap_ptr::<Header>(obj - HDR)).finalised.set(true);
+    drop(heap_ptr(obj));
+}
+
+/// Runs the destructors in `PENDING` which are waiting for the calling thread.
+/// Called from its safepoint polls, after it collects, and when it
+/// unregisters. Each object stays a root until its destructor has returned.
+pub(crate) fn run_pending_destructors() {
+    let me = threads::current_id();
+    let mine = threads::no_suspend(|| {
+        pending()
+            .iter()
+            .filter(|&&(obj, _, owner)| owner == me && !is_finalised(obj))
+            .map(|&(obj, drop, _)| (obj, drop))
+            .collect::<Vec<_>>()
+    });
+    if mine.is_empty() {
+        return;
+    }
+    debug!("running {} destructors queued by other threads", mine.len());
+    for &(obj, drop) in &mine {
+        unsafe { finalise(obj, drop) };
+    }
+    let mut done = mine.into_iter().map(|(obj, _)| obj).collect::<Vec<_>>();
+    done.sort_unstable();
+    threads::no_suspend(|| pending().retain(|p| done.binary_search(&p.0).is_err()));
+}
+
+/// Gives up on the destructors in `PENDING` which are waiting for the thread
+/// `owner`, which has exited: they can't be run on any other. The objects are
+/// reclaimed without being dropped, as if by `mem::forget`.
+pub(crate) fn forget_pending(owner: usize) {
+    pending().retain(|&(obj, _, o)| {
+        if o == owner {
+            unsafe { (*heap_ptr::<Header>(obj - HDR)).finalised.set(true) };
+        }
+        o != owner
+    });
+}
+
+/// Aborts the process if a panic unwinds past it. Collections are entered from
+/// frames which can't be unwound (safepoint polls in code which only has
+/// stackmaps, and C callers), and with the world stopped, so a panic inside one
+/// must not propagate.
+pub(crate) struct AbortOnPanic(pub(crate) &'static str);
+
+impl Drop for AbortOnPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            log::write(
+                Level::Error,
+                format_args!("a panic unwound out of {}; aborting", self.0)
+            );
+            process::abort();
+        }
+    }
+}
+
+/// Aborts if the calling thread is in the middle of a collection, reporting
+/// `what` it tried to do. Unwinding from here would leave the world stopped.
+#[inline]
+pub(crate) fn forbid_reentry(what: &str) {
+    if COLLECTING.with(|c| c.get()) {
+        reentered(what);
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn reentered(what: &str) -> ! {
+    log::write(
+        Level::Error,
+        format_args!(
+            "{} while the heap was being collected (e.g. from a Scan impl)",
+            what
+        )
+    );
+    process::abort();
+}
+
+/// The process-wide collector. Every thread shares one heap, and access to it is
+/// serialised by a lock. The lock is reentrant on the thread which holds it, so
+/// runtime code called from inside the collector may use the public API.
+pub(crate) struct GlobalCollector {
+    lock: Mutex<()>,
+    inner: UnsafeCell<Option<Collector>>,
+    // How far `init()` has got. Read without the lock by the allocation fast
+    // path.
+    state: AtomicU8
+}
+
+const UNINITIALISED: u8 = 0;
+const INITIALISING: u8 = 1;
+const INITIALISED: u8 = 2;
+
+unsafe impl Sync for GlobalCollector {}
+
+/// Resets `IN_COLLECTOR` when the lock is released, even on unwind.
+struct Reentry;
+
+impl Drop for Reentry {
+    fn drop(&mut self) {
+        IN_COLLECTOR.with(|c| c.set(false));
+    }
+}
+
+impl GlobalCollector {
+    pub(crate) const fn new() -> Self {
+        GlobalCollector {
+            lock: Mutex::new(()),
+            inner: UnsafeCell::new(None),
+            state: AtomicU8::new(UNINITIALISED)
+        }
+    }
+
+    /// Claims initialisation for the calling thread. Fails if `init()` has
+    /// already been called, so that a second call can't replace the heap.
+    pub(crate) fn begin_init(&self) -> Result<(), GcErr> {
+        self.state
+            .compare_exchange(
+                UNINITIALISED,
+                INITIALISING,
+                Ordering::AcqRel,
+                Ordering::Acquire
+            )
+            .map(|_| ())
+            .map_err(|_| GcErr::AlreadyInitialised)
+    }
+
+    /// Called once the heap and safepoint table have been created.
+    pub(crate) fn finish_init(&self) {
+        self.state.store(INITIALISED, Ordering::Release);
+    }
+
+    pub(crate) fn is_initialised(&self) -> bool {
+        self.state.load(Ordering::Acquire) == INITIALISED
+    }
+
+    pub(crate) fn with<R, F: FnOnce(&Collector) -> R>(&self, f: F) -> R {
+        if IN_COLLECTOR.with(|c| c.get()) {
+            return f(self.get());
+        }
+        // A thread waiting for the lock may be waiting on a collection, so it
+        // must not hold that collection up.
+        let _guard = match self.lock.try_lock() {
+            Ok(g) => g,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                threads::blocking(|| self.lock.lock().unwrap_or_else(|e| e.into_inner()))
+            }
+        };
+        IN_COLLECTOR.with(|c| c.set(true));
+        let _reentry = Reentry;
+        f(self.get())
+    }
+
+    /// Moves `object` into the GC heap.
+    pub(crate) fn alloc_obj<T: Scan>(
+        &self,
+        object: T,
+        policy: AllocPolicy
+    ) -> Result<*mut T, GcErr> {
+        assert!(
+            mem::align_of::<T>() <= HALIGN,
+            "GC objects must be at most 16-byte aligned"
+        );
+        let ty = TypeInfoOf::<T>::get();
+        let obj = self.alloc_block(mem::size_of::<T>(), ty, policy)? as *mut T;
+        unsafe { ptr::write(obj, object) };
+        if mem::needs_drop::<T>() {
+            let owner = threads::current_id();
+            threads::no_suspend(|| drops().push((obj.addr(), drop_obj::<T>, owner)));
+        }
+        Ok(obj)
+    }
+
+    /// Allocates an uninitialised object of `size` bytes, aligned to `HALIGN`.
+    /// This is the allocation path for dynamically sized objects: `ty.trace` is
+    /// called with the object's address during marking, so it must only read
+    /// parts of the object which the caller has initialised before the next
+    /// safepoint.
+    ///
+    /// Small objects allocated by registered threads are bump-allocated from the
+    /// thread's TLAB without taking the collector lock. An exhausted TLAB is
+    /// normally refilled from the newest chunk without the lock too; the lock is
+    /// only taken when that chunk is full, when the collection trigger may have
+    /// been reached, or for large objects.
+    pub(crate) fn alloc_block(
+        &self,
+        size: usize,
+        ty: &'static TypeInfo,
+        policy: AllocPolicy
+    ) -> Result<*mut u8, GcErr> {
+        forbid_reentry("GC allocation");
+        if !self.is_initialised() {
+            return Err(GcErr::NotInitialised);
+        }
+        let bsize = block_size(size).ok_or(GcErr::TooLarge(size))?;
+        profile::record(bsize);
+        replay::count_alloc();
+        stress::on_alloc();
+        let obj = self.place_block(bsize, ty, policy)?;
+        leaks::record_alloc(obj);
+        Ok(obj)
+    }
+
+    #[inline]
+    fn place_block(
+        &self,
+        bsize: usize,
+        ty: &'static TypeInfo,
+        policy: AllocPolicy
+    ) -> Result<*mut u8, GcErr> {
+        if bsize <= TLAB_MAX_BLOCK && threads::is_registered() {
+            if let Some(obj) = tlab::alloc(bsize, ty) {
+                return Ok(obj);
+            }
+            // A failed refill only means there is no room for a whole TLAB,
+            // and there may still be for the object.
+            if refill_tlab_unlocked() || self.with(|c| c.refill_tlab(policy)).is_ok() {
+                if let Some(obj) = tlab::alloc(bsize, ty) {
+                    return Ok(obj);
+                }
+            }
+        }
+        self.with(|c| c.alloc_block(bsize, ty, policy))
+    }
+
+    /// Writes the heap's layout and allocation counters for the crash handler,
+    /// marking the chunk which contains `fault`, if any. This doesn't take the
+    /// lock, so it may see the heap mid-update.
+    pub(crate) fn write_crash_state(&self, w: &mut dyn fmt::Write, fault: usize) -> fmt::Result {
+        let c = match unsafe { &*self.inner.get() } {
+            Some(c) => c,
+            None => return writeln!(w, "rgcrt: the heap has not been created")
+        };
+        writeln!(
+            w,
+            "rgcrt: {} collections, {} bytes allocated since the last",
+            c.collections.get(),
+            c.allocated_since_gc()
+        )?;
+        let chunks = match unsafe { c.chunks.try_borrow_unguarded() } {
+            Ok(chunks) => chunks,
+            Err(_) => return writeln!(w, "rgcrt: the chunk list is being updated")
+        };
+        let bump = BUMP_CHUNK.load(Ordering::Relaxed) as *const Chunk;
+        for chunk in chunks.iter() {
+            write!(
+                w,
+                "rgcrt: chunk {:#x}-{:#x}, top {:#x}",
+                chunk.start(),
+                chunk.end,
+                chunk.top()
+            )?;
+            if ptr::eq(&**chunk, bump) {
+                w.write_str(" (bump chunk)")?;
+            }
+            if fault >= chunk.start() && fault < chunk.end {
+                w.write_str(" <- faulting address")?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Must only be called with the lock held.
+    fn get(&self) -> &Collector {
+        unsafe {
+            let inner = &mut *self.inner.get();
+            inner.get_or_insert_with(Collector::new)
+        }
+    }
+}
 
 pub(crate) struct Collector {
-    hptr: Cell<*mut usize>,
-    hstart: Cell<usize>,
-    hend: Cell<usize>,
+    // Boxed so that a chunk stays put when the list is sorted or grows:
+    // `BUMP_CHUNK` points to one without the lock.
+    #[allow(clippy::vec_box)]
+    chunks: RefCell<Vec<Box<Chunk>>>,
+
+    // The size of `chunks` in bytes.
+    heap_size: Cell<usize>,
+
+    // Blocks freed by the last sweep, available for reuse.
+    free: RefCell<Vec<usize>>,
 
     collect_next: Cell<bool>,
 
-    roots: UnsafeCell<Option<HashMap<ReturnAddress, SafepointRoots>>>
+    // Bytes handed out by `alloc_obj` (including headers) since the last
+    // collection.
+    allocated_since_gc: Cell<usize>,
+
+    // The number of collections performed so far.
+    collections: Cell<u64>,
+
+    // A report on the most recent collection.
+    last_info: Cell<Option<GcInfo>>,
+
+    // The pause time of every collection.
+    pauses: RefCell<PauseHistogram>,
+
+    // The mutator's recent allocation rate.
+    alloc_rate: RefCell<AllocRate>,
+
+    // Memory held outside the GC heap by GC objects, as reported by the
+    // embedder: the amount currently live, and the amount reported since the
+    // last collection.
+    external_bytes: Cell<usize>,
+    external_since_gc: Cell<usize>,
+
+    // Once `allocated_since_gc` plus `external_since_gc` exceeds this,
+    // `collect_next` is set.
+    trigger_threshold: Cell<usize>,
+
+    // The most the heap's chunks may add up to, in bytes.
+    max_heap: Cell<usize>,
+
+    // The fraction of `max_heap` which, if survivors occupy more of it, counts
+    // as memory pressure.
+    soft_occupancy: Cell<f64>,
+
+    // The number of consecutive collections which ended under memory
+    // pressure. Soft references are cleared once this reaches
+    // `SOFT_PRESSURE_COLLECTIONS`.
+    soft_pressure: Cell<usize>,
+
+    // Set to clear soft references at the next collection regardless.
+    clear_soft: Cell<bool>,
+
+    // The nesting depth of `disable()` calls. Collections are suppressed while
+    // this is non-zero.
+    disabled: Cell<usize>,
+
+    roots: UnsafeCell<Option<HashMap<ReturnAddress, SafepointRoots>>>,
+
+    scratch: RefCell<Scratch>,
+
+    // The safepoints which each shared library loaded after `init()` added to
+    // `roots`, keyed by the address the library was loaded at.
+    libraries: RefCell<HashMap<usize, Vec<ReturnAddress>>>,
+
+    // Objects which have escaped to places the collector can't scan (via
+    // `Gc::into_raw`), mapped to the number of outstanding escapes.
+    raw_roots: RefCell<HashMap<usize, usize>>
 }
 
 impl Collector {
     pub(crate) fn new() -> Self {
         Collector {
-            hptr: Cell::new(0 as *mut usize),
-            hstart: Cell::new(0),
-            hend: Cell::new(0),
+            chunks: RefCell::new(Vec::new()),
+            heap_size: Cell::new(0),
+            free: RefCell::new(Vec::new()),
 
             collect_next: Cell::new(false),
-            roots: UnsafeCell::new(None)
+            allocated_since_gc: Cell::new(0),
+            collections: Cell::new(0),
+            last_info: Cell::new(None),
+            pauses: RefCell::new(PauseHistogram::new()),
+            alloc_rate: RefCell::new(AllocRate::new()),
+            external_bytes: Cell::new(0),
+            external_since_gc: Cell::new(0),
+            trigger_threshold: Cell::new(DEFAULT_TRIGGER),
+            max_heap: Cell::new(usize::MAX),
+            soft_occupancy: Cell::new(DEFAULT_SOFT_OCCUPANCY),
+            soft_pressure: Cell::new(0),
+            clear_soft: Cell::new(false),
+            disabled: Cell::new(0),
+            roots: UnsafeCell::new(None),
+            scratch: RefCell::new(Scratch::new()),
+            libraries: RefCell::new(HashMap::new()),
+            raw_roots: RefCell::new(HashMap::new())
         }
     }
 
     #[inline]
     pub fn collect_next(&self) {
         self.collect_next.set(true);
+        threads::request_collect();
     }
 
     #[inline]
     pub fn should_collect(&self) -> bool {
-        self.collect_next.get()
+        (self.collect_next.get() || stress::collect_now() || unrooted::pending())
+            && self.disabled.get() == 0
+    }
+
+    #[inline]
+    pub fn allocated_since_gc(&self) -> usize {
+        self.allocated_since_gc.get() + UNLOCKED_ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn collections(&self) -> u64 {
+        self.collections.get()
+    }
+
+    pub fn last_info(&self) -> Option<GcInfo> {
+        self.last_info.get()
+    }
+
+    pub fn pauses(&self) -> PauseHistogram {
+        self.pauses.borrow().clone()
+    }
+
+    /// Returns the estimated allocation rate in bytes per second.
+    pub fn alloc_rate(&self) -> f64 {
+        self.alloc_rate
+            .borrow()
+            .estimate(self.allocated_since_gc(), Instant::now())
+    }
+
+    pub fn trigger_threshold(&self) -> usize {
+        self.trigger_threshold.get()
+    }
+
+    pub fn set_trigger_threshold(&self, bytes: usize) {
+        self.trigger_threshold.set(bytes);
+        self.check_trigger();
+    }
+
+    /// Arms `collect_next` if the allocation volume since the last collection
+    /// has passed the trigger threshold, and recomputes how much may be
+    /// allocated without the lock before it needs checking again.
+    #[inline]
+    fn check_trigger(&self) {
+        let locked = self
+            .allocated_since_gc
+            .get()
+            .saturating_add(self.external_since_gc.get());
+        let threshold = self.trigger_threshold.get();
+        let trigger = if replay::replaying() {
+            replay::trigger_due()
+        } else {
+            locked.saturating_add(UNLOCKED_ALLOCATED.load(Ordering::Relaxed)) > threshold
+        };
+        if trigger {
+            if !self.collect_next.get() {
+                replay::triggered();
+            }
+            self.collect_next();
+        }
+        UNLOCKED_BUDGET.store(threshold.saturating_sub(locked), Ordering::Relaxed);
+    }
+
+    pub fn external_bytes(&self) -> usize {
+        self.external_bytes.get()
+    }
+
+    pub fn report_external_alloc(&self, bytes: usize) {
+        self.external_bytes
+            .set(self.external_bytes.get().saturating_add(bytes));
+        self.external_since_gc
+            .set(self.external_since_gc.get().saturating_add(bytes));
+        self.check_trigger();
+    }
+
+    pub fn report_external_free(&self, bytes: usize) {
+        self.external_bytes
+            .set(self.external_bytes.get().saturating_sub(bytes));
+    }
+
+    pub fn disable(&self) {
+        self.disabled.set(self.disabled.get() + 1);
+    }
+
+    pub fn enable(&self) {
+        let depth = self.disabled.get();
+        assert!(
+            depth > 0,
+            "gcrt::enable() called without a matching disable()"
+        );
+        self.disabled.set(depth - 1);
+    }
+
+    #[inline]
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.get() > 0
+    }
+
+    pub(crate) fn add_raw_root(&self, ptr: *mut u8) {
+        *self.raw_roots.borrow_mut().entry(ptr as usize).or_insert(0) += 1;
+    }
+
+    pub(crate) fn remove_raw_root(&self, ptr: *mut u8) {
+        let mut raw_roots = self.raw_roots.borrow_mut();
+        let count = raw_roots
+            .get_mut(&(ptr as usize))
+            .expect("Gc::from_raw called on a pointer not from Gc::into_raw");
+        *count -= 1;
+        if *count == 0 {
+            raw_roots.remove(&(ptr as usize));
+        }
     }
 
     pub fn mk_heap(&self) {
-        let layout = Layout::from_size_align(HSIZE, HALIGN).unwrap();
-        let ptr = unsafe { alloc(layout) as *mut usize };
+        if self.mk_chunk(HSIZE).is_err() {
+            panic!("Can't allocate memory.");
+        }
+    }
+
+    pub fn max_heap_size(&self) -> usize {
+        self.max_heap.get()
+    }
+
+    pub fn set_max_heap_size(&self, bytes: usize) {
+        self.max_heap.set(bytes);
+    }
+
+    pub fn set_soft_occupancy(&self, fraction: f64) {
+        self.soft_occupancy.set(fraction);
+    }
+
+    /// Adds a new chunk of at least `size` bytes to the heap.
+    fn mk_chunk(&self, size: usize) -> Result<(), GcErr> {
+        let size = round_up(size.max(HSIZE), HALIGN);
+        let max = self.max_heap.get();
+        if self.heap_size().saturating_add(size) > max {
+            error!(
+                "can't grow the heap by {} bytes without exceeding its {} byte limit",
+                size, max
+            );
+            return Err(GcErr::OOM(format!(
+                "Growing the heap by {} bytes would exceed its {} byte limit",
+                size, max
+            )));
+        }
+        let bitmap = bitmap_size(size);
+        let ptr = match size
+            .checked_add(bitmap)
+            .and_then(|n| Layout::from_size_align(n, HALIGN).ok())
+        {
+            Some(layout) => unsafe { alloc_zeroed(layout) },
+            None => ptr::null_mut()
+        };
 
         if ptr.is_null() {
-            panic!("Can't allocate memory.");
+            error!("can't allocate a {} byte heap chunk", size);
+            return Err(GcErr::OOM(format!("Can't allocate a {} byte chunk", size)));
         }
 
-        self.hptr.set(ptr);
-        self.hstart.set(ptr as usize);
-        self.hend.set(ptr as usize + HSIZE);
+        // See `heap_ptr()`.
+        let start = ptr.expose_provenance() + bitmap;
+        asan::poison(start, size);
+        valgrind::create_pool();
+        replay::heap_grown(size);
+        let chunk = Box::new(Chunk {
+            base: unsafe { NonNull::new_unchecked(ptr) },
+            start,
+            end: start + size,
+            top: AtomicUsize::new(start)
+        });
+        BUMP_CHUNK.store(&*chunk as *const Chunk as *mut Chunk, Ordering::Release);
+        // Chunks are kept in address order, so that they can be searched.
+        let mut chunks = self.chunks.borrow_mut();
+        let i = chunks.partition_point(|c| c.start() < start);
+        chunks.insert(i, chunk);
+        let heap_size = self.heap_size.get() + size;
+        self.heap_size.set(heap_size);
+        info!(
+            "heap grown by {} bytes to {} bytes in {} chunks",
+            size,
+            heap_size,
+            chunks.len()
+        );
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            grown_by = size,
+            heap_size,
+            chunks = chunks.len(),
+            "gcrt heap grown"
+        );
+        Ok(())
     }
 
     pub fn mk_root_table<P: AsRef<Path>>(&self, path: P) {
-        unimplemented!()
+        if cfg!(feature = "simulation") {
+            info!("simulation: no safepoints loaded, roots are raw roots only");
+            return;
+        }
+        let table = gen_safepoint_table(&path);
+        info!(
+            "loaded {} safepoints from {}",
+            table.len(),
+            path.as_ref().display()
+        );
+        unsafe { *self.roots.get() = Some(table) };
+    }
+
+    /// Merges the safepoints of the shared library at `path`, loaded at
+    /// `base`, into the safepoint table, and returns how many there were. Does
+    /// nothing if the library's safepoints are already in the table.
+    pub(crate) fn add_library_safepoints(&self, path: &Path, base: usize) -> Result<usize, String> {
+        if cfg!(feature = "simulation") || self.libraries.borrow().contains_key(&base) {
+            return Ok(0);
+        }
+        let table = gen_library_safepoint_table(path, base as u64)?;
+        info!(
+            "loaded {} safepoints from {} at {:#x}",
+            table.len(),
+            path.display(),
+            base
+        );
+        let n = table.len();
+        self.libraries
+            .borrow_mut()
+            .insert(base, table.keys().cloned().collect());
+        unsafe { &mut *self.roots.get() }
+            .get_or_insert_with(HashMap::new)
+            .extend(table);
+        Ok(n)
+    }
+
+    /// Removes the safepoints of each shared library for whose load address
+    /// `loaded` returns false.
+    pub(crate) fn retain_libraries<F: FnMut(usize) -> bool>(&self, mut loaded: F) {
+        let roots = unsafe { &mut *self.roots.get() };
+        self.libraries.borrow_mut().retain(|&base, addrs| {
+            if loaded(base) {
+                return true;
+            }
+            info!(
+                "removed {} safepoints of the library unloaded from {:#x}",
+                addrs.len(),
+                base
+            );
+            if let Some(roots) = roots.as_mut() {
+                for addr in addrs.iter() {
+                    roots.remove(addr);
+                }
+            }
+            false
+        });
+    }
+
+    /// Returns the return addresses of every safepoint in the table.
+    #[cfg(feature = "bench")]
+    pub(crate) fn safepoint_addresses(&self) -> Vec<u64> {
+        match unsafe { &*self.roots.get() } {
+            Some(roots) => roots.keys().map(|r| r.0).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Looks each of `addrs` up in the safepoint table, as stack scanning does
+    /// for each frame, and returns how many were found.
+    #[cfg(feature = "bench")]
+    pub(crate) fn count_safepoints(&self, addrs: &[u64]) -> usize {
+        let roots = match unsafe { &*self.roots.get() } {
+            Some(roots) => roots,
+            None => return 0
+        };
+        addrs
+            .iter()
+            .filter(|&&a| roots.contains_key(&ReturnAddress(a)))
+            .count()
+    }
+
+    /// Returns the size of the heap's chunks in bytes.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size.get()
+    }
+
+    /// Returns `true` if `addr` points to the start of a live object in the GC
+    /// heap.
+    pub(crate) fn is_gc_ptr(&self, addr: usize) -> bool {
+        self.find_header(addr).is_some()
+    }
+
+    /// Returns the `[start, top)` range of the allocated part of every chunk.
+    fn chunk_ranges(&self) -> Vec<(usize, usize)> {
+        self.chunks
+            .borrow()
+            .iter()
+            .map(|c| (c.start(), c.top()))
+            .collect()
+    }
+
+    /// Returns the type name and block size of the live object at `addr`, if
+    /// there is one.
+    pub(crate) fn object_info(&self, addr: usize) -> Option<(&'static str, usize)> {
+        let hdr = self.find_header(addr)?;
+        unsafe { Some(((*hdr).ty?.name(), (*hdr).size)) }
+    }
+
+    /// Returns the header of the live object starting at `addr`, if any.
+    fn find_header(&self, addr: usize) -> Option<*mut Header> {
+        let chunks = self.chunks.borrow();
+        let i = chunks.partition_point(|c| c.start() < addr);
+        let c = chunks.get(i.checked_sub(1)?)?;
+        unsafe { header_at(c.start(), c.top(), addr) }
+    }
+
+    /// Returns the start of the chunk containing the block at `block`.
+    fn chunk_start(&self, block: usize) -> usize {
+        let chunks = self.chunks.borrow();
+        let i = chunks.partition_point(|c| c.start() <= block);
+        chunks[i - 1].start()
     }
 
     // Perform the actual garbage collection. We use the name `reclaim` to
     // disambiguate from Rust's notion of `collect` on iterators.
-    pub(crate) fn reclaim(&self) {
-        unimplemented!()
+    pub(crate) fn reclaim(&self, reason: GcReason) {
+        self.reclaim_with(reason, false);
+    }
+
+    /// Performs a collection and tallies the survivors by type.
+    pub(crate) fn census(&self) -> Vec<TypeCensus> {
+        let mut by_name: HashMap<&'static str, TypeCensus> = HashMap::new();
+        for (ty, (objects, bytes)) in self.reclaim_with(GcReason::Forced, true) {
+            let name = unsafe { (*(ty as *const TypeInfo)).name() };
+            let entry = by_name.entry(name).or_insert(TypeCensus {
+                type_name: name,
+                objects: 0,
+                bytes: 0
+            });
+            entry.objects += objects;
+            entry.bytes += bytes;
+        }
+        let mut census: Vec<TypeCensus> = by_name.into_values().collect();
+        census.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(b.type_name)));
+        census
+    }
+
+    /// Counts the collection which has just swept as under memory pressure if
+    /// its survivors occupy more than the soft occupancy of the heap limit.
+    /// There is no pressure without a limit.
+    fn note_pressure(&self, survivor_bytes: usize, cleared_soft: bool) {
+        let max = self.max_heap.get();
+        let pressed =
+            max != usize::MAX && survivor_bytes as f64 > max as f64 * self.soft_occupancy.get();
+        if pressed && !cleared_soft {
+            self.soft_pressure.set(self.soft_pressure.get() + 1);
+        } else {
+            self.soft_pressure.set(0);
+        }
+    }
+
+    /// Performs a collection, returning the census of the survivors if
+    /// `census` is set (and an empty map otherwise).
+    fn reclaim_with(&self, reason: GcReason, census: bool) -> HashMap<usize, (usize, usize)> {
+        if FINALISING.with(|f| f.get()) {
+            debug!("collection ({:?}) started by a destructor deferred", reason);
+            self.collect_next.set(true);
+            threads::request_collect();
+            return HashMap::new();
+        }
+        let _abort = AbortOnPanic("a collection");
+        let _collecting = Collecting::enter("a collection was started");
+        let start = Instant::now();
+        self.alloc_rate
+            .borrow_mut()
+            .sample(self.allocated_since_gc(), start);
+        let trace_start = chrome_trace::now();
+        chrome_trace::collection_start(trace_start, self.allocated_since_gc());
+        // Nothing may be reported to a `tracing` subscriber while the world is
+        // stopped, as a stopped thread may hold a lock which the subscriber
+        // needs. The span is entered beforehand and the results are reported
+        // afterwards.
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "gcrt collection",
+            collection = self.collections.get() + 1,
+            reason = ?reason
+        )
+        .entered();
+        debug!(
+            "collection {} started ({:?}) after {} bytes of allocation",
+            self.collections.get() + 1,
+            reason,
+            self.allocated_since_gc()
+        );
+        replay::collection_started(self.collections.get() + 1, reason);
+        self.collect_next.set(false);
+        let clear_soft =
+            self.clear_soft.replace(false) || self.soft_pressure.get() >= SOFT_PRESSURE_COLLECTIONS;
+        if clear_soft {
+            debug!("clearing soft references");
+        }
+        if !stress::polls_always_check() {
+            threads::clear_collect();
+        }
+        let mut scratch = self.scratch.replace(Scratch::new());
+        self.prepare_pause(&mut scratch);
+        crash::set_phase(Phase::StoppingWorld);
+        if census {
+            threads::stop_at_safepoints(&mut scratch.stopped);
+        } else {
+            threads::stop_the_world(&mut scratch.stopped);
+        }
+        let t = chrome_trace::span("stop the world", trace_start);
+        crash::set_phase(Phase::Marking);
+        // Every thread's TLAB is abandoned: its unused tail is already
+        // formatted as a free block, which the sweep will reclaim.
+        tlab::invalidate_all();
+        self.fill_ranges(&mut scratch.ranges);
+        set_mark_ranges(&scratch.ranges);
+        self.scan_roots(&mut scratch);
+        scratch.roots = scratch.tracer.len();
+        let t = chrome_trace::span("scan roots", t);
+        self.mark_ephemerons(&mut scratch, clear_soft);
+        unsafe {
+            ephemeron::clear_dead_keys();
+            intern::clear_unmarked();
+            unrooted::check();
+        }
+        let dead = self.find_dead_drops(&mut scratch);
+        self.mark_ephemerons(&mut scratch, clear_soft);
+        unsafe { ephemeron::clear_phantoms() };
+        let t = chrome_trace::span("mark", t);
+        crash::set_phase(Phase::Sweeping);
+        set_mark_ranges(&[]);
+        unsafe { ephemeron::note_unmarked() };
+        let mut stats = self.sweep(&scratch, census);
+        let _t = chrome_trace::span("sweep", t);
+        self.note_pressure(stats.survivor_bytes, clear_soft);
+        if leaks::enabled() {
+            // The sweep has cleared the object-start bits of everything it
+            // freed, so this doesn't need a set of the survivors.
+            let ranges = &scratch.ranges;
+            leaks::retain_live(|obj| unsafe { find_header_in(ranges, obj) }.is_some());
+        }
+        #[cfg(feature = "verify")]
+        unsafe {
+            crash::set_phase(Phase::Verifying);
+            verify::verify_heap(&scratch.ranges);
+            verify::verify_barriers(&scratch.ranges);
+            chrome_trace::span("verify", _t);
+        }
+        threads::resume_the_world();
+        unrooted::report();
+        ephemeron::after_collection();
+        intern::purge();
+        crash::set_phase(Phase::Dropping);
+        run_destructors(dead);
+        crash::set_phase(Phase::Idle);
+        self.scratch.replace(scratch);
+        chrome_trace::collection_end(trace_start);
+        let duration = start.elapsed();
+        info!(
+            "collection {} ({:?}) reclaimed {} bytes in {:?}, {} objects ({} bytes) survived",
+            self.collections.get() + 1,
+            reason,
+            stats.bytes_reclaimed,
+            duration,
+            stats.survivors,
+            stats.survivor_bytes
+        );
+        if log::enabled(Level::Debug) {
+            self.log_summary(reason, duration, &stats);
+        }
+        self.last_info.set(Some(GcInfo {
+            kind: GcKind::Major,
+            reason,
+            duration,
+            bytes_reclaimed: stats.bytes_reclaimed,
+            survivors: stats.survivors,
+            survivor_bytes: stats.survivor_bytes
+        }));
+        self.pauses.borrow_mut().record(duration);
+        replay::collection_finished(
+            self.collections.get() + 1,
+            stats.bytes_reclaimed,
+            stats.survivors
+        );
+        #[cfg(feature = "tracing")]
+        {
+            tracing::info!(
+                duration = ?duration,
+                bytes_reclaimed = stats.bytes_reclaimed,
+                survivors = stats.survivors,
+                survivor_bytes = stats.survivor_bytes,
+                "gcrt collection finished"
+            );
+            drop(span);
+        }
+        self.allocated_since_gc.set(0);
+        self.external_since_gc.set(0);
+        UNLOCKED_ALLOCATED.store(0, Ordering::Relaxed);
+        self.alloc_rate.borrow_mut().restart(Instant::now());
+        self.check_trigger();
+        self.collections.set(self.collections.get() + 1);
+        stats.census.take().unwrap_or_default()
+    }
+
+    /// Logs one line of `key=value` pairs summarising the collection which has
+    /// just finished, for analysing long runs with `grep` and friends.
+    ///
+    /// Fragmentation is estimated as the fraction of free space (free blocks
+    /// and the unused ends of chunks) which lies outside the largest free
+    /// block: 0 means all free space is contiguous.
+    fn log_summary(&self, reason: GcReason, duration: Duration, stats: &SweepStats) {
+        let chunks = self.chunks.borrow();
+        let heap = chunks.iter().map(|c| c.end - c.start()).sum::<usize>();
+        let free_blocks = self.free.borrow();
+        let free_sizes = free_blocks
+            .iter()
+            .map(|&b| unsafe { (*heap_ptr::<Header>(b)).size })
+            .chain(chunks.iter().map(|c| c.end - c.top()));
+        let (free, largest_free) = free_sizes.fold((0, 0), |(t, m), s| (t + s, m.max(s)));
+        let fragmentation = if free == 0 {
+            0.0
+        } else {
+            1.0 - largest_free as f64 / free as f64
+        };
+        debug!(
+            "gc collection={} reason={:?} duration_us={} reclaimed={} survivors={} \
+             occupancy_before={} occupancy_after={} heap={} free={} largest_free={} \
+             fragmentation={:.3} alloc_rate={:.0}",
+            self.collections.get() + 1,
+            reason,
+            duration.as_micros(),
+            stats.bytes_reclaimed,
+            stats.survivors,
+            stats.survivor_bytes + stats.bytes_reclaimed,
+            stats.survivor_bytes,
+            heap,
+            free,
+            largest_free,
+            fragmentation,
+            self.alloc_rate.borrow().sampled()
+        );
+    }
+
+    /// Reports every root to `tracer`: the slots recorded in the safepoint table
+    /// on the calling thread's stack and on the stacks of the `stopped`
+    /// threads, anything which looks like a pointer on the parts of suspended
+    /// threads' stacks which aren't at a safepoint, and the raw roots.
+    fn scan_roots(&self, scratch: &mut Scratch) {
+        let Scratch {
+            stopped,
+            safepoints,
+            tracer,
+            ..
+        } = scratch;
+        safepoints.clear();
+        safepoints.push(frames::with_current(|top| {
+            self.scan_stack(unsafe { Frames::new(top) }, tracer)
+        }));
+        debug!("{} roots on the collecting thread's stack", tracer.len());
+        for &t in stopped.iter() {
+            let before = tracer.len();
+            match t {
+                StoppedThread::Precise { top } => {
+                    safepoints.push(self.scan_stack(unsafe { Frames::new(top) }, tracer));
+                    debug!(
+                        "{} roots on the stack of a thread stopped at a safepoint",
+                        tracer.len() - before
+                    );
+                }
+                StoppedThread::Suspended {
+                    top,
+                    sp,
+                    stack_end,
+                    regs
+                } => {
+                    for r in regs.iter() {
+                        tracer.trace(*r as *const u8);
+                    }
+                    let walk = || unsafe { Frames::suspended(top, sp, stack_end) };
+                    self.scan_conservative(walk(), sp, stack_end, tracer);
+                    safepoints.push(self.scan_stack(walk(), tracer));
+                    debug!(
+                        "{} possible roots on the stack of a suspended thread",
+                        tracer.len() - before
+                    );
+                }
+            }
+        }
+        for &addr in self.raw_roots.borrow().keys() {
+            tracer.trace(addr as *const u8);
+        }
+        debug!("{} raw roots", self.raw_roots.borrow().len());
+        for &(obj, _, _) in pending().iter() {
+            tracer.trace(heap_ptr::<u8>(obj));
+        }
+        #[cfg(feature = "capi")]
+        embed::trace_handles(tracer);
+        safepoints.retain(|&ret| ret != 0);
+        crash::record_safepoints(safepoints);
+    }
+
+    /// Fills `ranges` with the allocated part of each chunk.
+    fn fill_ranges(&self, ranges: &mut Vec<(usize, usize)>) {
+        ranges.clear();
+        ranges.extend(self.chunks.borrow().iter().map(|c| (c.start(), c.top())));
+    }
+
+    /// Grows the buffers in `scratch` to what the next pause will need, and
+    /// starts the worker threads it can use. Must be called before the world
+    /// is stopped.
+    fn prepare_pause(&self, scratch: &mut Scratch) {
+        let chunks = self.chunks.borrow();
+        scratch.parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+        // How many roots there will be isn't known until the world has stopped,
+        // so go by the last collection.
+        let mut wanted = (chunks.len() / SWEEP_CHUNKS_PER_WORKER).min(scratch.parallelism);
+        if scratch.roots / MARK_ROOTS_PER_WORKER > 1 {
+            wanted = scratch.parallelism;
+        }
+        if wanted > 1 {
+            workers::ensure(wanted - 1);
+        }
+        let available = workers::available().min(scratch.parallelism);
+
+        let threads = threads::count();
+        make_room(&mut scratch.stopped, threads);
+        make_room(&mut scratch.safepoints, threads + 1);
+        make_room(&mut scratch.ranges, chunks.len());
+
+        if scratch.overflowed {
+            scratch.mark_stack *= 2;
+            scratch.overflowed = false;
+        }
+        let mark_stack = scratch.mark_stack;
+        scratch.tracer.clear();
+        scratch.tracer.reserve(mark_stack);
+        for d in scratch.deques.iter_mut() {
+            if d.capacity() < mark_stack {
+                *d = Deque::new(mark_stack);
+            }
+        }
+        for t in scratch.tracers.iter_mut() {
+            t.get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .reserve(mark_stack);
+        }
+        while scratch.deques.len() < available {
+            scratch.deques.push(Deque::new(mark_stack));
+            let mut tracer = Tracer::bounded();
+            tracer.reserve(mark_stack);
+            scratch.tracers.push(Mutex::new(tracer));
+        }
+
+        scratch.sweepers = (chunks.len() / SWEEP_CHUNKS_PER_WORKER).clamp(1, available);
+        while scratch.swept.len() < scratch.sweepers {
+            scratch.swept.push(Mutex::default());
+        }
+        // The first sweeper's buffer becomes the free list, so it needs room
+        // for every free block.
+        let per_sweeper = chunks.len().div_ceil(scratch.sweepers).max(1);
+        let total = chunks
+            .iter()
+            .map(|c| max_free_blocks(c.start(), c.end))
+            .sum();
+        for (i, part) in chunks.chunks(per_sweeper).enumerate() {
+            let n = match i {
+                0 => total,
+                _ => part.iter().map(|c| max_free_blocks(c.start(), c.end)).sum()
+            };
+            make_room(&mut lock(&scratch.swept[i]).0, n);
+        }
+    }
+
+    /// Returns a snapshot of every object reachable from the roots, stopping
+    /// the world while it is taken. The heap is left unchanged.
+    pub(crate) fn snapshot(&self) -> HeapDump {
+        let _collecting = Collecting::enter("a heap snapshot was started");
+        // A snapshot allocates as it goes, so it doesn't use the collector's
+        // buffers.
+        let mut scratch = Scratch::new();
+        scratch.tracer = Tracer::new();
+        threads::stop_at_safepoints(&mut scratch.stopped);
+        self.scan_roots(&mut scratch);
+        self.fill_ranges(&mut scratch.ranges);
+        let ranges = scratch.ranges.clone();
+
+        let mut dump = HeapDump::default();
+        for &r in scratch.tracer.pending() {
+            if unsafe { find_header_in(&ranges, r) }.is_some() {
+                dump.roots.push(r as u64);
+            }
+        }
+        dump.roots.sort_unstable();
+        dump.roots.dedup();
+        self.mark(&mut scratch);
+
+        let tracer = &mut scratch.tracer;
+        let mut type_ids: HashMap<*const TypeInfo, u32> = HashMap::new();
+        for &(start, top) in &ranges {
+            let mut cur = start;
+            while cur < top {
+                let hdr = heap_ptr::<Header>(cur);
+                unsafe {
+                    if (*hdr).marked.swap(false, Ordering::Relaxed) {
+                        let obj = cur + HDR;
+                        let ty = (*hdr).ty.unwrap();
+                        let id = *type_ids.entry(ty).or_insert_with(|| {
+                            dump.types.push(ty.name().to_string());
+                            dump.types.len() as u32 - 1
+                        });
+                        let mut refs = Vec::new();
+                        if let Some(trace) = (*hdr).trace_fn() {
+                            trace(heap_ptr(obj), tracer);
+                            while let Some(r) = tracer.pop() {
+                                refs.push(r.addr() as u64);
+                            }
+                        }
+                        dump.objects.push(DumpObject {
+                            addr: obj as u64,
+                            size: (*hdr).size as u64,
+                            ty: id,
+                            refs
+                        });
+                    }
+                    cur += (*hdr).size;
+                }
+            }
+        }
+        threads::resume_the_world();
+        dump
+    }
+
+    /// Searches breadth-first from the roots for the object at `obj`, stopping
+    /// the world while it does so, and returns the shortest chain of
+    /// references which keeps it alive. Returns `None` if `obj` is not
+    /// reachable.
+    pub(crate) fn why_alive(&self, obj: usize) -> Option<RetainingPath> {
+        let _collecting = Collecting::enter("why_alive was called");
+        let mut stopped = Vec::new();
+        threads::stop_at_safepoints(&mut stopped);
+        let mut roots = Vec::new();
+        let stack = |thread, frames, roots: &mut Vec<(Root, usize)>| {
+            self.stack_roots(frames, |slot, ret| {
+                let root = Root::Stack {
+                    thread,
+                    slot,
+                    ret: ret as usize
+                };
+                roots.push((root, unsafe { *(slot as *const usize) }));
+            });
+        };
+        frames::with_current(|top| stack(0, unsafe { Frames::new(top) }, &mut roots));
+        for (i, t) in stopped.into_iter().enumerate() {
+            let thread = i + 1;
+            match t {
+                StoppedThread::Precise { top } => {
+                    stack(thread, unsafe { Frames::new(top) }, &mut roots)
+                }
+                StoppedThread::Suspended {
+                    top,
+                    sp,
+                    stack_end,
+                    regs
+                } => {
+                    roots.extend(regs.iter().map(|&r| (Root::Register { thread }, r)));
+                    let walk = || unsafe { Frames::suspended(top, sp, stack_end) };
+                    let mut tracer = Tracer::new();
+                    self.scan_conservative(walk(), sp, stack_end, &mut tracer);
+                    while let Some(p) = tracer.pop() {
+                        roots.push((Root::Conservative { thread }, p.addr()));
+                    }
+                    stack(thread, walk(), &mut roots);
+                }
+            }
+        }
+        roots.extend(self.raw_roots.borrow().keys().map(|&a| (Root::Raw, a)));
+
+        // Each object reached maps to the object which first referred to it, or
+        // to the root which did.
+        let ranges = self.chunk_ranges();
+        let mut parents: HashMap<usize, Result<usize, Root>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        for (root, p) in roots {
+            if unsafe { find_header_in(&ranges, p) }.is_some() {
+                parents.entry(p).or_insert_with(|| {
+                    queue.push_back(p);
+                    Err(root)
+                });
+            }
+        }
+        let mut tracer = Tracer::new();
+        while let Some(cur) = queue.pop_front() {
+            if cur == obj {
+                break;
+            }
+            let hdr = heap_ptr::<Header>(cur - HDR);
+            if let Some(trace) = unsafe { (*hdr).trace_fn() } {
+                unsafe { trace(heap_ptr(cur), &mut tracer) };
+            }
+            while let Some(p) = tracer.pop() {
+                let p = p.addr();
+                if !parents.contains_key(&p) && unsafe { find_header_in(&ranges, p) }.is_some() {
+                    parents.insert(p, Ok(cur));
+                    queue.push_back(p);
+                }
+            }
+        }
+
+        let path = parents.contains_key(&obj).then(|| {
+            let mut objects = Vec::new();
+            let mut cur = obj;
+            let root = loop {
+                let hdr = heap_ptr::<Header>(cur - HDR);
+                objects.push(RetainedObject {
+                    addr: cur,
+                    type_name: unsafe { (*hdr).ty }.map_or("?", |ty| ty.name()),
+                    size: unsafe { (*hdr).size }
+                });
+                match parents[&cur] {
+                    Ok(p) => cur = p,
+                    Err(root) => break root
+                }
+            };
+            objects.reverse();
+            RetainingPath { root, objects }
+        });
+        threads::resume_the_world();
+        path
+    }
+
+    /// Walks a stack, reporting the contents of every root slot recorded in
+    /// the safepoint table. Returns the return address of the innermost frame
+    /// at a safepoint, or 0 if there is none.
+    fn scan_stack(&self, frames: Frames, tracer: &mut Tracer) -> usize {
+        self.stack_roots(frames, |slot, ret| {
+            let ptr = unsafe { *(slot as *const *const u8) };
+            trace!(
+                "root {:p} in stack slot {:#x} (return address {:#x})",
+                ptr,
+                slot,
+                ret
+            );
+            tracer.trace(ptr);
+        })
+    }
+
+    /// Calls `f` with the address of every root slot in `frames`, and the
+    /// return address of its frame. Returns the return address of the
+    /// innermost frame at a safepoint, or 0 if there is none.
+    fn stack_roots<F: FnMut(usize, u64)>(&self, frames: Frames, mut f: F) -> usize {
+        let roots = match unsafe { &*self.roots.get() } {
+            Some(roots) => roots,
+            None => return 0
+        };
+        let mut innermost = 0;
+        for frame in frames {
+            if let Some(sp_roots) = roots.get(&ReturnAddress(frame.ret)) {
+                if innermost == 0 {
+                    innermost = frame.ret as usize;
+                }
+                for slot in sp_roots.stack_slots(frame.sp, frame.fp) {
+                    f(slot, frame.ret);
+                }
+            }
+        }
+        innermost
+    }
+
+    /// Conservatively scans the stack of a thread suspended at an arbitrary
+    /// instruction, from `sp` up to the first of its `frames` which is at a
+    /// safepoint (or `stack_end`, if none is). Every word in that range is
+    /// treated as a potential GC pointer. The rest of the stack can then be
+    /// scanned precisely.
+    fn scan_conservative(
+        &self,
+        mut frames: Frames,
+        sp: usize,
+        stack_end: usize,
+        tracer: &mut Tracer
+    ) {
+        let roots = unsafe { &*self.roots.get() };
+        let limit = frames
+            .find(|f| {
+                roots
+                    .as_ref()
+                    .is_some_and(|r| r.contains_key(&ReturnAddress(f.ret)))
+            })
+            .map(|f| f.sp)
+            .unwrap_or(stack_end);
+        let mut slot = round_up(sp, mem::size_of::<usize>());
+        while slot + mem::size_of::<usize>() <= limit {
+            tracer.trace(unsafe { *(slot as *const *const u8) });
+            slot += mem::size_of::<usize>();
+        }
+    }
+
+    /// Marks everything reachable from the roots in the scratch tracer,
+    /// rescanning the heap for as long as the mark stack overflows.
+    fn mark(&self, scratch: &mut Scratch) {
+        loop {
+            self.drain(scratch);
+            let from = OVERFLOW_FROM.swap(usize::MAX, Ordering::AcqRel);
+            if from == usize::MAX {
+                return;
+            }
+            scratch.overflowed = true;
+            debug!("the mark stack overflowed; rescanning from {:#x}", from);
+            self.rescan(scratch, from);
+        }
+    }
+
+    /// Marks, then traces the values of the ephemerons whose keys were marked
+    /// and marks again, until no more ephemerons have their keys marked. Soft
+    /// references are traced like strong ones unless `clear_soft` is set.
+    fn mark_ephemerons(&self, scratch: &mut Scratch, clear_soft: bool) {
+        self.mark(scratch);
+        while unsafe { ephemeron::trace_values(&mut scratch.tracer, clear_soft) } {
+            self.mark(scratch);
+        }
+    }
+
+    /// Finds the objects with destructors which marking found to be unreachable,
+    /// and moves those whose destructors can run now to the end of `DROPS`,
+    /// marking them and everything they refer to so that they are kept until
+    /// the destructors have run. Returns the range of `DROPS` which they occupy.
+    ///
+    /// Destructors run in dependency order: an object which is reachable from
+    /// another unreachable object with a destructor is marked as if it were
+    /// live, and left for a later collection, so that its destructor runs after
+    /// the other's. Objects in a cycle can't be ordered. A member of a cycle is
+    /// found when marking from its children reaches it. Its destructor runs
+    /// now, after those of the objects which refer to it but not the other way
+    /// round, and what it refers to is dropped in order by later collections.
+    fn find_dead_drops(&self, scratch: &mut Scratch) -> Range<usize> {
+        let mut drops = drops();
+        let live = partition(&mut drops, |&(obj, _, _)| is_marked(obj));
+        // Mark from the children, but not the object itself, of each
+        // unreachable object in turn, moving those which turn out to be
+        // reachable from themselves to the start of `cycles`.
+        let mut cycles = live;
+        for i in live..drops.len() {
+            let obj = drops[i].0;
+            let unmarked = !is_marked(obj);
+            unsafe {
+                if let Some(trace) = (*heap_ptr::<Header>(obj - HDR)).trace_fn() {
+                    trace(heap_ptr(obj), &mut scratch.tracer);
+                }
+            }
+            self.mark(scratch);
+            if unmarked && is_marked(obj) {
+                drops.swap(cycles, i);
+                cycles += 1;
+            }
+        }
+        // Arrange the rest as the deferred objects, which are now marked, then
+        // the objects no other unreachable object depends on, then the cycles.
+        let deferred = partition(&mut drops[cycles..], |&(obj, _, _)| is_marked(obj));
+        let ready = drops.len() - cycles - deferred;
+        drops[live..].rotate_left(cycles - live);
+        let dead = live + deferred..drops.len();
+        if deferred > 0 || cycles > live {
+            debug!(
+                "{} destructors deferred, {} of objects in cycles",
+                deferred,
+                cycles - live
+            );
+        }
+        for &(obj, _, _) in &drops[dead.start..dead.start + ready] {
+            scratch.tracer.trace(heap_ptr::<u8>(obj));
+        }
+        dead
+    }
+
+    /// Traces every marked object at or above `from`, which includes each
+    /// object that `mark_overflowed` marked without tracing.
+    fn rescan(&self, scratch: &mut Scratch, from: usize) {
+        let Scratch { tracer, ranges, .. } = scratch;
+        for &(start, top) in ranges.iter().filter(|&&(_, top)| top > from) {
+            let mut cur = start;
+            while cur < top {
+                let hdr = heap_ptr::<Header>(cur);
+                let obj = cur + HDR;
+                unsafe {
+                    if obj >= from && (*hdr).live.get() && (*hdr).marked.load(Ordering::Relaxed) {
+                        if let Some(trace) = (*hdr).trace_fn() {
+                            trace(heap_ptr(obj), tracer);
+                        }
+                        while let Some(child) = tracer.pop() {
+                            mark_obj(ranges, child, tracer);
+                        }
+                    }
+                    cur += (*hdr).size;
+                }
+            }
+        }
+    }
+
+    /// Marks everything reachable from the pointers in the scratch tracer,
+    /// until the mark stack is empty.
+    ///
+    /// With enough roots, marking is spread across worker threads, each with
+    /// its own work-stealing deque of grey objects. Workers which run out of
+    /// work steal from the others until the termination protocol finds them
+    /// all idle.
+    fn drain(&self, scratch: &mut Scratch) {
+        let Scratch {
+            tracer,
+            ranges,
+            parallelism,
+            deques,
+            tracers,
+            ..
+        } = scratch;
+        let n = (*parallelism)
+            .min(tracer.len() / MARK_ROOTS_PER_WORKER)
+            .min(deques.len());
+        if n <= 1 {
+            while let Some(obj) = tracer.pop() {
+                unsafe { mark_obj(ranges, obj, tracer) };
+            }
+            return;
+        }
+
+        let roots = tracer.pending();
+        let per_worker = roots.len().div_ceil(n);
+        let n = roots.len().div_ceil(per_worker);
+        let (deques, ranges) = (&deques[..n], &ranges[..]);
+        let term = Terminator::new(n);
+        workers::run(n, &|id| {
+            // Id 0 runs on the collecting thread, which is already collecting.
+            let _collecting = (id > 0).then(|| Collecting::enter("a marking worker was started"));
+            let part = &roots[id * per_worker..roots.len().min((id + 1) * per_worker)];
+            mark_worker(id, part, deques, &term, ranges, &mut lock(&tracers[id]));
+        });
+        tracer.clear();
+    }
+
+    /// Frees every unmarked object and rebuilds the free list, coalescing
+    /// adjacent free blocks.
+    ///
+    /// Chunks are independent of one another, so on a large heap they are
+    /// divided among worker threads and swept concurrently, each producing a
+    /// free list for its own chunks. The lists are then concatenated.
+    fn sweep(&self, scratch: &Scratch, census: bool) -> SweepStats {
+        let Scratch {
+            ranges,
+            sweepers,
+            swept,
+            ..
+        } = scratch;
+        let per_sweeper = ranges.len().div_ceil(*sweepers).max(1);
+        workers::run(*sweepers, &|id| {
+            let mut out = lock(&swept[id]);
+            let (free, stats) = &mut *out;
+            free.clear();
+            for &(start, top) in ranges.chunks(per_sweeper).nth(id).unwrap_or(&[]) {
+                stats.add(unsafe { sweep_chunk(start, top, census, free) });
+            }
+        });
+
+        // The first sweeper's blocks become the free list, and the old free
+        // list becomes its buffer for the next collection.
+        let mut stats = SweepStats::default();
+        let mut free = self.free.borrow_mut();
+        for (id, out) in swept[..*sweepers].iter().enumerate() {
+            let mut out = lock(out);
+            if id == 0 {
+                mem::swap(&mut *free, &mut out.0);
+            } else {
+                free.extend_from_slice(&out.0);
+            }
+            stats.add(mem::take(&mut out.1));
+        }
+        stats
+    }
+
+    /// Finds room for a block of `size` bytes (including its header), first in
+    /// the free list, then at the end of the newest chunk. Returns `None` if
+    /// neither has space.
+    fn reserve_block(&self, size: usize) -> Option<*mut Header> {
+        let mut free = self.free.borrow_mut();
+        if let Some(i) = free
+            .iter()
+            .position(|&b| unsafe { (*heap_ptr::<Header>(b)).size } >= size)
+        {
+            let block = heap_ptr::<Header>(free.swap_remove(i));
+            let remaining = unsafe { (*block).size } - size;
+            if remaining >= MIN_BLOCK {
+                unsafe {
+                    let rest = block.byte_add(size);
+                    Header::init_free(rest, remaining);
+                    (*block).size = size;
+                    free.push(rest.addr());
+                }
+            }
+            return Some(block);
+        }
+
+        // The newest chunk.
+        let block = unsafe { BUMP_CHUNK.load(Ordering::Acquire).as_ref() }?.bump(size)?;
+        unsafe { Header::init_free(block, size) };
+        Some(block)
+    }
+
+    /// Allocates an uninitialised object in a block of `bsize` bytes (as given
+    /// by `block_size()`), aligned to `HALIGN`, from the shared heap. Most
+    /// allocations are satisfied from the calling thread's TLAB instead; this
+    /// is the slow path.
+    pub(crate) fn alloc_block(
+        &self,
+        bsize: usize,
+        ty: &'static TypeInfo,
+        policy: AllocPolicy
+    ) -> Result<*mut u8, GcErr> {
+        let block = self.obtain_block(bsize, policy)?;
+        unsafe {
+            Header::init(block, (*block).size, ty);
+            set_object_start(self.chunk_start(block.addr()), block.addr());
+            Ok(block.cast::<u8>().add(HDR))
+        }
+    }
+
+    /// Gives the calling thread a fresh TLAB carved from the shared heap.
+    pub(crate) fn refill_tlab(&self, policy: AllocPolicy) -> Result<(), GcErr> {
+        let block = self.obtain_block(TLAB_SIZE, policy)?;
+        let chunk = self.chunk_start(block.addr());
+        unsafe { tlab::refill(block, block.addr() + (*block).size, chunk) };
+        Ok(())
+    }
+
+    /// Reserves a block of `size` bytes, collecting and then growing the heap
+    /// if necessary (as allowed by `policy`). The block is formatted as a free
+    /// block; the caller initialises it further.
+    fn obtain_block(&self, size: usize, policy: AllocPolicy) -> Result<*mut Header, GcErr> {
+        let block = match self.reserve_block(size) {
+            Some(block) => block,
+            None if policy == AllocPolicy::NoCollect => return Err(GcErr::HeapFull),
+            None => {
+                if !self.is_disabled() {
+                    self.reclaim(GcReason::HeapFull);
+                }
+                match self.reserve_block(size) {
+                    Some(block) => block,
+                    None => match self.mk_chunk(size) {
+                        Ok(()) => self.reserve_block(size).unwrap(),
+                        // Soft references are the last thing to go before
+                        // the allocation fails.
+                        Err(e) if self.is_disabled() => return Err(e),
+                        Err(e) => {
+                            self.clear_soft.set(true);
+                            self.reclaim(GcReason::HeapFull);
+                            self.reserve_block(size).ok_or(e)?
+                        }
+                    }
+                }
+            }
+        };
+
+        unsafe {
+            let size = (*block).size;
+            self.allocated_since_gc
+                .set(self.allocated_since_gc.get() + size);
+            self.check_trigger();
+            Header::init_free(block, size);
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc
+    };
+
+    use super::*;
+    use crate::{force_collect, last_gc_info, tests::run, Gc, COLLECTOR};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Adds its value to `DROPS` when dropped.
+    struct Counted(usize);
+
+    impl Scan for Counted {}
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(self.0, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn objects_are_dropped_once() {
+        run(|| {
+            DROPS.store(0, Ordering::SeqCst);
+            let obj = Gc::into_raw(Gc::new(Counted(1)));
+            // Moving the value into the heap mustn't drop it.
+            assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+            force_collect();
+            assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+            unsafe { Gc::from_raw(obj) };
+            force_collect();
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+            force_collect();
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    /// Records the thread which dropped it in `DROPPED_ON`. Not `Send`.
+    struct Local(PhantomData<*const ()>);
+
+    static DROPPED_ON: Mutex<Option<thread::ThreadId>> = Mutex::new(None);
+
+    impl Scan for Local {}
+
+    impl Drop for Local {
+        fn drop(&mut self) {
+            *DROPPED_ON.lock().unwrap() = Some(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn destructors_run_on_the_allocating_thread() {
+        run(|| {
+            *DROPPED_ON.lock().unwrap() = None;
+            let (collected, wait) = mpsc::channel();
+            let (allocated, wait_alloc) = mpsc::channel();
+            let other = thread::spawn(move || {
+                crate::register_thread();
+                Gc::new(Local(PhantomData));
+                allocated.send(()).unwrap();
+                crate::native(|| wait.recv().unwrap());
+                assert_eq!(*DROPPED_ON.lock().unwrap(), None);
+                crate::safepoint_poll();
+                assert_eq!(*DROPPED_ON.lock().unwrap(), Some(thread::current().id()));
+                crate::unregister_thread();
+            });
+            crate::native(|| wait_alloc.recv().unwrap());
+            force_collect();
+            assert_eq!(*DROPPED_ON.lock().unwrap(), None);
+            collected.send(()).unwrap();
+            crate::native(|| other.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn destructors_are_forgotten_if_the_allocating_thread_exits() {
+        run(|| {
+            *DROPPED_ON.lock().unwrap() = None;
+            let obj = thread::spawn(|| {
+                crate::register_thread();
+                let obj = Gc::as_ptr(&Gc::new(Local(PhantomData))).addr();
+                crate::unregister_thread();
+                obj
+            })
+            .join()
+            .unwrap();
+            force_collect();
+            force_collect();
+            assert_eq!(*DROPPED_ON.lock().unwrap(), None);
+            assert!(!crate::is_gc_ptr(obj as *const u8));
+        });
+    }
+
+    fn alloc(size: usize) -> Result<*mut u8, GcErr> {
+        COLLECTOR.alloc_block(size, TypeInfoOf::<u8>::get(), AllocPolicy::Collect)
+    }
+
+    #[test]
+    fn oversized_objects_are_too_large() {
+        run(|| {
+            assert!(matches!(
+                alloc(usize::MAX),
+                Err(GcErr::TooLarge(usize::MAX))
+            ));
+            assert!(matches!(
+                alloc(MAX_OBJECT + 1),
+                Err(GcErr::TooLarge(size)) if size == MAX_OBJECT + 1
+            ));
+            // The largest object any heap could hold is only too large for
+            // this one.
+            assert!(matches!(alloc(MAX_OBJECT), Err(GcErr::OOM(_))));
+        });
+    }
+
+    /// A list node with a leaf on the side. Marking pushes the leaf before the
+    /// next node, then pops the next node first, so the leaves pile up on the
+    /// mark stack.
+    struct Spine {
+        next: Cell<Option<Gc<Spine>>>,
+        leaf: Gc<usize>
+    }
+
+    impl Scan for Spine {
+        fn scan(&self, tracer: &mut Tracer) {
+            self.leaf.scan(tracer);
+            self.next.get().scan(tracer);
+        }
+    }
+
+    #[test]
+    fn marking_completes_after_mark_stack_overflow() {
+        const LEN: usize = 1_000_000;
+        run(|| {
+            // Each node is linked to the next one allocated, so that a rescan
+            // finds the nodes overflowed above it in the same pass.
+            crate::disable();
+            let new = |i| {
+                Gc::new(Spine {
+                    next: Cell::new(None),
+                    leaf: Gc::new(i)
+                })
+            };
+            let head = new(0);
+            let mut tail = head;
+            for i in 1..LEN {
+                let node = new(i);
+                tail.next.set(Some(node));
+                tail = node;
+            }
+            let root = Gc::into_raw(head);
+            crate::enable();
+
+            force_collect();
+            assert!(COLLECTOR.with(|c| c.scratch.borrow().overflowed));
+            let mut node = Some(root);
+            let mut len = 0;
+            while let Some(n) = node {
+                let n = unsafe { &*n };
+                assert!(crate::is_gc_ptr(Gc::as_ptr(&n.leaf)));
+                assert_eq!(*n.leaf, len);
+                len += 1;
+                node = n.next.get().as_ref().map(Gc::as_ptr);
+            }
+            assert_eq!(len, LEN);
+
+            unsafe { Gc::from_raw(root) };
+            force_collect();
+            assert_eq!(last_gc_info().unwrap().survivors, 0);
+        });
+    }
+
+    /// A xorshift generator, so that a failing sequence can be replayed from
+    /// its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// Checks the heap just after a collection: every chunk is tiled by
+    /// well-formed blocks, only live ones are in the object-start bitmap, and
+    /// they add up to no more than the heap. Returns the live blocks.
+    fn check_heap(c: &Collector) -> Vec<(usize, usize)> {
+        let mut live = Vec::new();
+        let ranges = c.chunk_ranges();
+        for (chunk, &(start, top)) in c.chunks.borrow().iter().zip(&ranges) {
+            assert!(top <= chunk.end);
+            let mut cur = start;
+            while cur < top {
+                let hdr = unsafe { &*heap_ptr::<Header>(cur) };
+                assert!(
+                    hdr.size >= HDR && hdr.size % HALIGN == 0,
+                    "bad block size at {:#x}",
+                    cur
+                );
+                assert!(
+                    hdr.size <= top - cur,
+                    "block at {:#x} overruns its chunk",
+                    cur
+                );
+                assert!(!hdr.is_marked(), "block at {:#x} is still marked", cur);
+                let found = unsafe { find_header_in(&ranges, cur + HDR) };
+                assert_eq!(
+                    found.is_some(),
+                    hdr.live.get(),
+                    "bitmap is wrong at {:#x}",
+                    cur
+                );
+                if hdr.live.get() {
+                    assert!(hdr.ty().is_some());
+                    live.push((cur, hdr.size));
+                }
+                cur += hdr.size;
+            }
+        }
+        let used = live.iter().map(|&(_, size)| size).sum::<usize>();
+        assert!(used <= c.heap_size());
+        live
     }
 
-    pub(crate) fn alloc_obj<T: Scan>(&self, object: T) -> Result<*mut T, GcErr> {
-        unimplemented!()
+    #[test]
+    fn allocator_invariants_hold_for_random_sequences() {
+        run(|| {
+            for seed in 1..=20 {
+                let mut rng = Rng(seed);
+                // The rooted objects, with their size and the byte they are
+                // filled with.
+                let mut kept: Vec<(*mut u8, usize, u8)> = Vec::new();
+                for i in 0..1000 {
+                    match rng.below(16) {
+                        0 => {
+                            force_collect();
+                            let live = COLLECTOR.with(check_heap);
+                            for w in live.windows(2) {
+                                assert!(
+                                    w[0].0 + w[0].1 <= w[1].0,
+                                    "blocks overlap (seed {})",
+                                    seed
+                                );
+                            }
+                            for &(obj, size, fill) in &kept {
+                                assert!(live.iter().any(|&(b, _)| b + HDR == obj as usize));
+                                let bytes = unsafe { slice::from_raw_parts(obj, size) };
+                                assert!(
+                                    bytes.iter().all(|&b| b == fill),
+                                    "object overwritten (seed {})",
+                                    seed
+                                );
+                            }
+                        }
+                        1..=3 if !kept.is_empty() => {
+                            let (obj, ..) = kept.swap_remove(rng.below(kept.len()));
+                            COLLECTOR.with(|c| c.remove_raw_root(obj));
+                        }
+                        _ => {
+                            let size = match rng.below(20) {
+                                0 => rng.below(64 * 1024),
+                                _ => rng.below(256)
+                            };
+                            let obj = alloc(size).unwrap();
+                            let fill = i as u8;
+                            unsafe { ptr::write_bytes(obj, fill, size) };
+                            COLLECTOR.with(|c| c.add_raw_root(obj));
+                            kept.push((obj, size, fill));
+                        }
+                    }
+                }
+                for (obj, ..) in kept {
+                    COLLECTOR.with(|c| c.remove_raw_root(obj));
+                }
+            }
+        });
     }
 }
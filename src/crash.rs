@@ -0,0 +1,217 @@
+//! An optional handler for fatal signals which prints the collector's state to
+//! stderr before the process dies, so that a crash report from the field says
+//! where the heap was, what the collector was doing, and where threads last
+//! stopped for it.
+//!
+//! The handler is installed by `install_crash_handler()`, or at `init()` if
+//! `RGCRT_CRASH_REPORT` is set. It reads the collector's state without taking
+//! any locks, since the crashing thread may hold them, and formats the report
+//! into a buffer on its own stack rather than allocating. Once the report is
+//! written it reinstates whichever handler was there before, and re-raises the
+//! signal.
+
+use std::{
+    cell::UnsafeCell,
+    env,
+    fmt::{self, Write},
+    mem::{self, MaybeUninit},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Once
+    }
+};
+
+use crate::{threads, COLLECTOR};
+
+const SIGNALS: [libc::c_int; 5] = [
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGILL,
+    libc::SIGFPE,
+    libc::SIGABRT
+];
+
+/// What the collector is doing, as far as the crash report is concerned.
+#[derive(Clone, Copy)]
+pub(crate) enum Phase {
+    Idle,
+    StoppingWorld,
+    Marking,
+    Dropping,
+    Sweeping,
+    Verifying
+}
+
+static PHASE: AtomicU8 = AtomicU8::new(Phase::Idle as u8);
+
+/// The number of return addresses kept by `record_safepoints`.
+const MAX_SAFEPOINTS: usize = 16;
+
+/// The return addresses of the innermost safepoints at which the stacks were
+/// scanned in the most recent collection.
+static SAFEPOINTS: [AtomicUsize; MAX_SAFEPOINTS] = [NO_SAFEPOINT; MAX_SAFEPOINTS];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SAFEPOINT: AtomicUsize = AtomicUsize::new(0);
+
+struct OldActions(UnsafeCell<MaybeUninit<[libc::sigaction; SIGNALS.len()]>>);
+
+unsafe impl Sync for OldActions {}
+
+/// The handlers which were installed before ours. Written once, before our
+/// handler is installed, and only read afterwards.
+static OLD_ACTIONS: OldActions = OldActions(UnsafeCell::new(MaybeUninit::uninit()));
+
+static INSTALL: Once = Once::new();
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub(crate) fn set_phase(phase: Phase) {
+    PHASE.store(phase as u8, Ordering::Relaxed);
+}
+
+/// Remembers `rets` as the last safepoints stacks were stopped at. Only the
+/// first `MAX_SAFEPOINTS` are kept.
+pub(crate) fn record_safepoints(rets: &[usize]) {
+    for (i, slot) in SAFEPOINTS.iter().enumerate() {
+        slot.store(rets.get(i).cloned().unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn install() {
+    INSTALL.call_once(|| unsafe {
+        let old = (*OLD_ACTIONS.0.get()).as_mut_ptr() as *mut libc::sigaction;
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = crash_handler as extern "C" fn(_, _, _) as usize;
+        // The standard library runs its stack overflow handler on an alternate
+        // stack, which we share.
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut sa.sa_mask);
+        for (i, &sig) in SIGNALS.iter().enumerate() {
+            if libc::sigaction(sig, &sa, old.add(i)) != 0 {
+                panic!("Can't install the crash handler.");
+            }
+        }
+    });
+}
+
+pub(crate) fn install_from_env() {
+    match env::var("RGCRT_CRASH_REPORT") {
+        Ok(v) if v != "0" => install(),
+        _ => ()
+    }
+}
+
+extern "C" fn crash_handler(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    if !CRASHED.swap(true, Ordering::SeqCst) {
+        let mut buf = StackBuf::new();
+        let _ = unsafe { write_report(&mut buf, sig, info, ctx) };
+        buf.flush();
+    }
+    unsafe {
+        let old = (*OLD_ACTIONS.0.get()).as_ptr() as *const libc::sigaction;
+        let i = SIGNALS.iter().position(|&s| s == sig).unwrap();
+        libc::sigaction(sig, old.add(i), ptr::null_mut());
+        // The signal stays blocked until we return, at which point the previous
+        // handler (or the default action) gets it.
+        libc::raise(sig);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn fault_pc(ctx: &libc::ucontext_t) -> usize {
+    ctx.uc_mcontext.gregs[libc::REG_RIP as usize] as usize
+}
+
+#[cfg(target_arch = "aarch64")]
+fn fault_pc(ctx: &libc::ucontext_t) -> usize {
+    ctx.uc_mcontext.pc as usize
+}
+
+/// Simulated builds on other architectures report no pc.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn fault_pc(_: &libc::ucontext_t) -> usize {
+    0
+}
+
+unsafe fn write_report(
+    w: &mut StackBuf,
+    sig: libc::c_int,
+    info: *mut libc::siginfo_t,
+    ctx: *mut libc::c_void
+) -> fmt::Result {
+    let name = match sig {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGILL => "SIGILL",
+        libc::SIGFPE => "SIGFPE",
+        _ => "SIGABRT"
+    };
+    let ctx = &*(ctx as *const libc::ucontext_t);
+    let pc = fault_pc(ctx);
+    let fault = match sig {
+        libc::SIGSEGV | libc::SIGBUS => (*info).si_addr() as usize,
+        _ => 0
+    };
+    writeln!(w, "rgcrt: fatal signal {} at pc {:#x}", name, pc)?;
+    if fault != 0 {
+        writeln!(w, "rgcrt: faulting address {:#x}", fault)?;
+    }
+    let phase = match PHASE.load(Ordering::Relaxed) {
+        p if p == Phase::StoppingWorld as u8 => "stopping the world",
+        p if p == Phase::Marking as u8 => "marking",
+        p if p == Phase::Dropping as u8 => "running destructors",
+        p if p == Phase::Sweeping as u8 => "sweeping",
+        p if p == Phase::Verifying as u8 => "verifying the heap",
+        _ => "idle"
+    };
+    writeln!(w, "rgcrt: collector: {}", phase)?;
+    threads::write_crash_state(w)?;
+    COLLECTOR.write_crash_state(w, fault)?;
+    w.write_str("rgcrt: last safepoints:")?;
+    for ret in SAFEPOINTS.iter().map(|s| s.load(Ordering::Relaxed)) {
+        if ret != 0 {
+            write!(w, " {:#x}", ret)?;
+        }
+    }
+    w.write_str("\n")
+}
+
+/// A fixed-size buffer for the report, which is truncated if it doesn't fit.
+struct StackBuf {
+    buf: [u8; 4096],
+    len: usize
+}
+
+impl StackBuf {
+    fn new() -> Self {
+        StackBuf {
+            buf: [0; 4096],
+            len: 0
+        }
+    }
+
+    fn flush(&self) {
+        let mut buf = &self.buf[..self.len];
+        while !buf.is_empty() {
+            let n = unsafe { libc::write(2, buf.as_ptr() as *const libc::c_void, buf.len()) };
+            if n <= 0 {
+                return;
+            }
+            buf = &buf[n as usize..];
+        }
+    }
+}
+
+impl fmt::Write for StackBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        if n < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! A Chase-Lev work-stealing deque of grey objects, and the termination
+//! protocol used by parallel marking.
+//!
+//! Each marking worker owns one `Deque`. The owner pushes and pops at the
+//! bottom without contention; other workers steal from the top when they run
+//! out of work. A deque doesn't grow while it is in use, since the world is
+//! stopped: a push to a full one fails, and the caller deals with the overflow. See "Dynamic Circular Work-Stealing Deque" (Chase and Lev,
+//! SPAA 2005) and "Correct and Efficient Work-Stealing for Weak Memory Models"
+//! (Lê et al., PPoPP 2013), which this follows.
+
+use std::{
+    sync::atomic::{fence, AtomicIsize, AtomicUsize, Ordering},
+    thread
+};
+
+struct Buffer {
+    /// Always a power of two.
+    cap: usize,
+    data: Box<[AtomicUsize]>
+}
+
+impl Buffer {
+    fn new(cap: usize) -> Box<Buffer> {
+        Box::new(Buffer {
+            cap,
+            data: (0..cap).map(|_| AtomicUsize::new(0)).collect()
+        })
+    }
+
+    #[inline]
+    fn get(&self, i: isize) -> usize {
+        self.data[i as usize & (self.cap - 1)].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn put(&self, i: isize, v: usize) {
+        self.data[i as usize & (self.cap - 1)].store(v, Ordering::Relaxed);
+    }
+}
+
+pub(crate) enum Steal {
+    Empty,
+    Success(usize),
+    /// Lost a race with the owner or another thief; the deque may still have
+    /// work in it.
+    Retry
+}
+
+pub(crate) struct Deque {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    buf: Box<Buffer>
+}
+
+impl Deque {
+    /// Creates a deque with room for `cap` elements, rounded up to a power of
+    /// two.
+    pub(crate) fn new(cap: usize) -> Self {
+        Deque {
+            top: AtomicIsize::new(0),
+            bottom: AtomicIsize::new(0),
+            buf: Buffer::new(cap.next_power_of_two())
+        }
+    }
+
+    /// The number of elements the deque can hold.
+    pub(crate) fn capacity(&self) -> usize {
+        self.buf.cap - 1
+    }
+
+    /// Pushes `v` onto the bottom of the deque, or returns `false` if it is
+    /// full. Must only be called by the owner.
+    pub(crate) fn push(&self, v: usize) -> bool {
+        let b = self.bottom.load(Ordering::Relaxed);
+        let t = self.top.load(Ordering::Acquire);
+        if (b - t) as usize >= self.capacity() {
+            return false;
+        }
+        self.buf.put(b, v);
+        fence(Ordering::Release);
+        self.bottom.store(b + 1, Ordering::Relaxed);
+        true
+    }
+
+    /// Pops from the bottom of the deque. Must only be called by the owner.
+    pub(crate) fn pop(&self) -> Option<usize> {
+        let b = self.bottom.load(Ordering::Relaxed) - 1;
+        let buf = &self.buf;
+        self.bottom.store(b, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let t = self.top.load(Ordering::Relaxed);
+        if t > b {
+            self.bottom.store(b + 1, Ordering::Relaxed);
+            return None;
+        }
+        let v = buf.get(b);
+        if t == b {
+            // The last element: race any thieves for it.
+            let won = self
+                .top
+                .compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok();
+            self.bottom.store(b + 1, Ordering::Relaxed);
+            return if won { Some(v) } else { None };
+        }
+        Some(v)
+    }
+
+    /// Steals from the top of the deque. May be called by any thread.
+    pub(crate) fn steal(&self) -> Steal {
+        let t = self.top.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let b = self.bottom.load(Ordering::Acquire);
+        if t >= b {
+            return Steal::Empty;
+        }
+        let v = self.buf.get(t);
+        match self
+            .top
+            .compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::Relaxed)
+        {
+            Ok(_) => Steal::Success(v),
+            Err(_) => Steal::Retry
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        let t = self.top.load(Ordering::Acquire);
+        let b = self.bottom.load(Ordering::Acquire);
+        t >= b
+    }
+}
+
+/// Detects when every worker has run out of work.
+///
+/// A worker with nothing to do declares itself idle. Marking is over once all
+/// workers are idle at the same time, since only a busy worker can create new
+/// work. An idle worker which sees work appear in any deque withdraws its
+/// declaration and goes back to stealing.
+pub(crate) struct Terminator {
+    workers: usize,
+    idle: AtomicUsize
+}
+
+impl Terminator {
+    pub(crate) fn new(workers: usize) -> Self {
+        Terminator {
+            workers,
+            idle: AtomicUsize::new(0)
+        }
+    }
+
+    /// Called by a worker which has found no work anywhere. Returns `true` if
+    /// marking has terminated, or `false` if there may be work to steal again.
+    pub(crate) fn offer_termination(&self, deques: &[Deque]) -> bool {
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        loop {
+            if self.idle.load(Ordering::SeqCst) == self.workers {
+                return true;
+            }
+            if deques.iter().any(|d| !d.is_empty()) {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+            thread::yield_now();
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Heap dumps, as written by `dump_heap()`, and a reader for them.
+//!
+//! A dump records every object reachable at the time it was taken. All
+//! integers are little-endian:
+//!
+//! ```text
+//! magic     8 bytes      "RGCDUMP\0"
+//! version   u32          1
+//! ntypes    u32
+//!   name    u32 length, then that many bytes of UTF-8    (ntypes times)
+//! nroots    u64
+//!   addr    u64                                           (nroots times)
+//! nobjects  u64
+//!   addr    u64          the address of the object
+//!   size    u64          the size of its block, including the header
+//!   type    u32          an index into the type names
+//!   nrefs   u32
+//!   refs    u64          the GC pointers it holds         (nrefs times)
+//! ```
+//!
+//! Dumps can also be written as Graphviz graphs with `write_dot()`, which is
+//! what `dump_object_graph()` does.
+//!
+//! Roots are the addresses of the objects referenced directly from thread
+//! stacks or registers, or registered with `Gc::into_raw`. Types are recorded
+//! with the names given by `std::any::type_name`, which are not guaranteed to
+//! be stable between compiler versions.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path
+};
+
+const MAGIC: &[u8; 8] = b"RGCDUMP\0";
+const VERSION: u32 = 1;
+
+/// The contents of a heap dump.
+#[derive(Debug, Default)]
+pub struct HeapDump {
+    /// The names of the types of the objects in the dump.
+    pub types: Vec<String>,
+    /// The addresses of the objects referenced directly by roots, in ascending
+    /// order.
+    pub roots: Vec<u64>,
+    /// Every reachable object, in address order.
+    pub objects: Vec<DumpObject>
+}
+
+/// An object in a heap dump.
+#[derive(Debug)]
+pub struct DumpObject {
+    pub addr: u64,
+    /// The size of the object's block, including the header.
+    pub size: u64,
+    /// An index into `HeapDump::types`.
+    pub ty: u32,
+    /// The GC pointers held by the object.
+    pub refs: Vec<u64>
+}
+
+impl HeapDump {
+    /// Reads the dump at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        HeapDump::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a heap dump"));
+        }
+        let version = read_u32(r)?;
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "unsupported heap dump version {}",
+                version
+            )));
+        }
+
+        let mut dump = HeapDump::default();
+        for _ in 0..read_u32(r)? {
+            let mut name = vec![0; read_u32(r)? as usize];
+            r.read_exact(&mut name)?;
+            dump.types
+                .push(String::from_utf8(name).map_err(|_| invalid("type name is not UTF-8"))?);
+        }
+        for _ in 0..read_u64(r)? {
+            dump.roots.push(read_u64(r)?);
+        }
+        for _ in 0..read_u64(r)? {
+            let addr = read_u64(r)?;
+            let size = read_u64(r)?;
+            let ty = read_u32(r)?;
+            if ty as usize >= dump.types.len() {
+                return Err(invalid("object has an unknown type"));
+            }
+            let nrefs = read_u32(r)?;
+            let refs = (0..nrefs).map(|_| read_u64(r)).collect::<io::Result<_>>()?;
+            dump.objects.push(DumpObject {
+                addr,
+                size,
+                ty,
+                refs
+            });
+        }
+        Ok(dump)
+    }
+
+    /// Writes the dump to `path`, replacing any existing file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(self.types.len() as u32).to_le_bytes())?;
+        for name in &self.types {
+            w.write_all(&(name.len() as u32).to_le_bytes())?;
+            w.write_all(name.as_bytes())?;
+        }
+        w.write_all(&(self.roots.len() as u64).to_le_bytes())?;
+        for root in &self.roots {
+            w.write_all(&root.to_le_bytes())?;
+        }
+        w.write_all(&(self.objects.len() as u64).to_le_bytes())?;
+        for obj in &self.objects {
+            w.write_all(&obj.addr.to_le_bytes())?;
+            w.write_all(&obj.size.to_le_bytes())?;
+            w.write_all(&obj.ty.to_le_bytes())?;
+            w.write_all(&(obj.refs.len() as u32).to_le_bytes())?;
+            for r in &obj.refs {
+                w.write_all(&r.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the object graph in Graphviz's DOT format: a node for each object,
+    /// labelled with its type and size, an edge for each GC pointer, and a
+    /// `roots` node with an edge to each object referenced by a root.
+    ///
+    /// If `max_objects` is given, only that many objects are written, chosen
+    /// breadth-first from the roots so that the structure nearest the roots is
+    /// kept. Edges to objects which were left out are dropped.
+    pub fn write_dot<W: Write>(&self, w: &mut W, max_objects: Option<usize>) -> io::Result<()> {
+        let limit = max_objects.unwrap_or(usize::MAX);
+        let mut included = HashSet::new();
+        let mut order = Vec::new();
+        let mut queue: VecDeque<u64> = self.roots.iter().cloned().collect();
+        while let Some(addr) = queue.pop_front() {
+            if order.len() == limit {
+                break;
+            }
+            if let Some(obj) = self.object(addr) {
+                if included.insert(addr) {
+                    order.push(obj);
+                    queue.extend(obj.refs.iter().cloned());
+                }
+            }
+        }
+
+        writeln!(w, "digraph heap {{")?;
+        if order.len() < self.objects.len() {
+            writeln!(
+                w,
+                "  label=\"{} of {} objects\";",
+                order.len(),
+                self.objects.len()
+            )?;
+        }
+        writeln!(w, "  node [shape=box, fontname=monospace];")?;
+        writeln!(w, "  roots [shape=ellipse];")?;
+        for obj in &order {
+            let name = self
+                .type_name(obj)
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"");
+            writeln!(
+                w,
+                "  o{:x} [label=\"{}\\n{:#x}, {} bytes\"];",
+                obj.addr, name, obj.addr, obj.size
+            )?;
+        }
+        for root in self.roots.iter().filter(|r| included.contains(r)) {
+            writeln!(w, "  roots -> o{:x};", root)?;
+        }
+        for obj in &order {
+            for r in obj.refs.iter().filter(|r| included.contains(r)) {
+                writeln!(w, "  o{:x} -> o{:x};", obj.addr, r)?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Returns the object at `addr`, if there is one in the dump.
+    pub fn object(&self, addr: u64) -> Option<&DumpObject> {
+        self.objects
+            .binary_search_by_key(&addr, |o| o.addr)
+            .ok()
+            .map(|i| &self.objects[i])
+    }
+
+    /// Returns the name of the type of `obj`.
+    pub fn type_name(&self, obj: &DumpObject) -> &str {
+        &self.types[obj.ty as usize]
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
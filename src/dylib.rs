@@ -0,0 +1,93 @@
+//! The safepoints of shared libraries loaded after `init()`, which only reads
+//! the executable's stackmaps.
+//!
+//! A library's stackmaps hold addresses relative to wherever it was loaded, so
+//! they are moved there before being merged into the safepoint table. Its
+//! safepoints are removed once the dynamic linker has unmapped it, which may be
+//! later than the `dlclose` which asked it to if something else still uses the
+//! library.
+
+use std::{
+    collections::HashSet,
+    ffi::{CStr, OsStr},
+    io,
+    os::{
+        raw::{c_char, c_int, c_void},
+        unix::ffi::OsStrExt
+    },
+    path::Path,
+    ptr
+};
+
+use crate::COLLECTOR;
+
+/// The `dlinfo` request for a library's `link_map`.
+const RTLD_DI_LINKMAP: c_int = 2;
+
+/// The fields which glibc's and musl's `struct link_map` start with.
+#[repr(C)]
+struct LinkMap {
+    l_addr: usize,
+    l_name: *const c_char
+}
+
+extern "C" {
+    fn dlinfo(handle: *mut c_void, request: c_int, info: *mut c_void) -> c_int;
+}
+
+/// Merges the safepoints of the library which `handle` (from `dlopen`) refers
+/// to into the safepoint table, and returns how many there were.
+pub(crate) unsafe fn loaded(handle: *mut c_void) -> io::Result<usize> {
+    let mut map: *const LinkMap = ptr::null();
+    if dlinfo(handle, RTLD_DI_LINKMAP, &mut map as *mut _ as *mut c_void) != 0 || map.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a handle to a loaded library"
+        ));
+    }
+    let path = Path::new(OsStr::from_bytes(CStr::from_ptr((*map).l_name).to_bytes()));
+    COLLECTOR
+        .with(|c| c.add_library_safepoints(path, (*map).l_addr))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Removes the safepoints of every library which has been unmapped.
+pub(crate) fn forget_unloaded() {
+    let bases = mapped_bases();
+    COLLECTOR.with(|c| c.retain_libraries(|base| bases.contains(&base)));
+}
+
+/// Returns the load addresses of every object the dynamic linker has mapped.
+fn mapped_bases() -> HashSet<usize> {
+    unsafe extern "C" fn add(info: *mut libc::dl_phdr_info, _: usize, data: *mut c_void) -> c_int {
+        (*(data as *mut HashSet<usize>)).insert((*info).dlpi_addr as usize);
+        0
+    }
+    let mut bases = HashSet::new();
+    unsafe { libc::dl_iterate_phdr(Some(add), &mut bases as *mut _ as *mut c_void) };
+    bases
+}
+
+pub(crate) unsafe fn dlopen(filename: &CStr, flags: c_int) -> *mut c_void {
+    let handle = libc::dlopen(filename.as_ptr(), flags);
+    if !handle.is_null() {
+        if let Err(e) = loaded(handle) {
+            error!(
+                "no safepoints loaded from {}: {}",
+                filename.to_string_lossy(),
+                e
+            );
+        }
+    }
+    handle
+}
+
+pub(crate) unsafe fn dlclose(handle: *mut c_void) -> c_int {
+    // Hold the collector lock so that nothing else can be loaded at the
+    // library's addresses before its safepoints are removed.
+    COLLECTOR.with(|_| {
+        let r = libc::dlclose(handle);
+        forget_unloaded();
+        r
+    })
+}
@@ -0,0 +1,249 @@
+//! The C embedding API: typed objects traced by callbacks, and handles through
+//! which C and C++ hosts hold on to objects.
+//!
+//! A host's own stack isn't described by stackmaps, so the collector can't see
+//! GC pointers in its local variables. Instead, the host keeps them in handles:
+//!
+//!   * Local handles live in the calling thread's handle stack, and are
+//!     released together when the handle scope they were created in is
+//!     closed.
+//!   * Roots are created and freed individually, and may be passed between
+//!     threads.
+//!
+//! Both are slots holding an object's address, which the collector treats as
+//! roots. A host should read an object's address from its handle again after
+//! anything which might collect.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    collections::HashSet,
+    ffi::CStr,
+    os::raw::{c_char, c_void},
+    sync::{Arc, Mutex, MutexGuard}
+};
+
+use crate::{
+    capi::alloc_cleared,
+    collector::{Header, TypeInfo, HDR},
+    threads, Tracer, COLLECTOR
+};
+
+/// The number of slots in each block of a thread's handle stack. Blocks are
+/// never moved, so a handle's address stays valid until its scope is closed.
+const HANDLE_BLOCK: usize = 256;
+
+pub type TraceFn = unsafe extern "C" fn(obj: *const c_void, tracer: *mut Tracer);
+
+/// A type registered with `rgcrt_register_type`. The `TypeInfo` comes first
+/// so that an object's header, which points to it, also points to this.
+#[repr(C)]
+pub struct CType {
+    info: TypeInfo,
+    name: &'static str,
+    trace: Option<TraceFn>
+}
+
+unsafe fn trace_c(obj: *const u8, tracer: &mut Tracer) {
+    let hdr = &*((obj as usize - HDR) as *const Header);
+    let ty = &*(hdr.ty().unwrap() as *const TypeInfo as *const CType);
+    if let Some(trace) = ty.trace {
+        trace(obj as *const c_void, tracer);
+    }
+}
+
+/// A thread's local handles. Only the owning thread changes it, and only with
+/// suspension deferred, so the collector sees a consistent stack.
+struct HandleStack {
+    // The blocks are boxed so that they don't move when the vector grows.
+    #[allow(clippy::vec_box)]
+    blocks: UnsafeCell<Vec<Box<[Cell<usize>; HANDLE_BLOCK]>>>,
+    len: Cell<usize>
+}
+
+unsafe impl Send for HandleStack {}
+unsafe impl Sync for HandleStack {}
+
+impl HandleStack {
+    fn push(&self, obj: usize) -> *mut *mut c_void {
+        let blocks = unsafe { &mut *self.blocks.get() };
+        let len = self.len.get();
+        if len == blocks.len() * HANDLE_BLOCK {
+            blocks.push(Box::new([(); HANDLE_BLOCK].map(|_| Cell::new(0))));
+        }
+        let slot = &blocks[len / HANDLE_BLOCK][len % HANDLE_BLOCK];
+        slot.set(obj);
+        self.len.set(len + 1);
+        slot.as_ptr() as *mut *mut c_void
+    }
+
+    fn truncate(&self, len: usize) {
+        let blocks = unsafe { &*self.blocks.get() };
+        for i in len..self.len.get() {
+            blocks[i / HANDLE_BLOCK][i % HANDLE_BLOCK].set(0);
+        }
+        self.len.set(len);
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        let blocks = unsafe { &*self.blocks.get() };
+        for i in 0..self.len.get() {
+            tracer.trace(blocks[i / HANDLE_BLOCK][i % HANDLE_BLOCK].get() as *const u8);
+        }
+    }
+}
+
+/// Removes the thread's handle stack from `HANDLE_STACKS` when it exits.
+struct LocalHandles(Arc<HandleStack>);
+
+impl Drop for LocalHandles {
+    fn drop(&mut self) {
+        threads::no_suspend(|| handle_stacks().retain(|s| !Arc::ptr_eq(s, &self.0)));
+    }
+}
+
+static HANDLE_STACKS: Mutex<Vec<Arc<HandleStack>>> = Mutex::new(Vec::new());
+
+/// The addresses of the slots of every live root.
+static ROOTS: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+thread_local!(static LOCAL: LocalHandles = {
+    let stack = Arc::new(HandleStack {
+        blocks: UnsafeCell::new(Vec::new()),
+        len: Cell::new(0)
+    });
+    threads::no_suspend(|| handle_stacks().push(stack.clone()));
+    LocalHandles(stack)
+});
+
+fn handle_stacks() -> MutexGuard<'static, Vec<Arc<HandleStack>>> {
+    HANDLE_STACKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn roots() -> MutexGuard<'static, Option<HashSet<usize>>> {
+    ROOTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reports the objects held by every handle and root. Called with the world
+/// stopped.
+pub(crate) fn trace_handles(tracer: &mut Tracer) {
+    for stack in handle_stacks().iter() {
+        stack.trace(tracer);
+    }
+    for &slot in roots().iter().flatten() {
+        tracer.trace(unsafe { *(slot as *const *const u8) });
+    }
+}
+
+/// Registers a type whose objects are traced by calling `trace`, or never
+/// traced if `trace` is null. Types live for the rest of the process.
+///
+/// # Safety
+///
+/// `name` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_register_type(
+    name: *const c_char,
+    trace: Option<TraceFn>
+) -> *const CType {
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    Box::into_raw(Box::new(CType {
+        info: TypeInfo {
+            name: |ty| unsafe { (*(ty as *const TypeInfo as *const CType)).name },
+            trace: trace.map(|_| trace_c as unsafe fn(*const u8, &mut Tracer)),
+            conservative: false
+        },
+        name: Box::leak(name.into_boxed_str()),
+        trace
+    }))
+}
+
+/// Allocates a zeroed object of type `ty` with room for `size` bytes, or
+/// returns null if the heap is exhausted.
+///
+/// # Safety
+///
+/// `ty` must have come from `rgcrt_register_type`.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_alloc_typed(ty: *const CType, size: usize) -> *mut c_void {
+    alloc_cleared(size, &(*ty).info)
+}
+
+/// Reports a GC pointer from a trace callback.
+///
+/// # Safety
+///
+/// `tracer` must be the tracer passed to the callback.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_trace(tracer: *mut Tracer, obj: *const c_void) {
+    (*tracer).trace(obj);
+}
+
+/// Opens a handle scope, returning a token to pass to `rgcrt_close_scope`.
+#[no_mangle]
+pub extern "C" fn rgcrt_open_scope() -> usize {
+    LOCAL.with(|l| l.0.len.get())
+}
+
+/// Releases every local handle created since `scope` was opened.
+#[no_mangle]
+pub extern "C" fn rgcrt_close_scope(scope: usize) {
+    LOCAL.with(|l| {
+        assert!(scope <= l.0.len.get(), "handle scopes closed out of order");
+        threads::no_suspend(|| l.0.truncate(scope));
+    });
+}
+
+/// Creates a local handle holding `obj` in the current scope.
+#[no_mangle]
+pub extern "C" fn rgcrt_handle(obj: *mut c_void) -> *mut *mut c_void {
+    LOCAL.with(|l| threads::no_suspend(|| l.0.push(obj as usize)))
+}
+
+/// Creates a root holding `obj`, which keeps it alive until the root is freed
+/// or changed to hold another object.
+#[no_mangle]
+pub extern "C" fn rgcrt_root_new(obj: *mut c_void) -> *mut *mut c_void {
+    let slot = Box::into_raw(Box::new(obj));
+    threads::no_suspend(|| {
+        roots()
+            .get_or_insert_with(HashSet::new)
+            .insert(slot as usize)
+    });
+    slot
+}
+
+/// Frees a root created by `rgcrt_root_new`.
+///
+/// # Safety
+///
+/// `root` must have come from `rgcrt_root_new`, and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rgcrt_root_free(root: *mut *mut c_void) {
+    let removed =
+        threads::no_suspend(|| roots().as_mut().is_some_and(|r| r.remove(&(root as usize))));
+    assert!(
+        removed,
+        "rgcrt_root_free called on something which isn't a root"
+    );
+    drop(Box::from_raw(root));
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_collection_count() -> u64 {
+    crate::collection_count()
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_heap_size() -> usize {
+    COLLECTOR.with(|c| c.heap_size())
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_set_max_heap_size(bytes: usize) {
+    crate::set_max_heap_size(bytes);
+}
+
+#[no_mangle]
+pub extern "C" fn rgcrt_set_gc_trigger_threshold(bytes: usize) {
+    crate::set_gc_trigger_threshold(bytes);
+}
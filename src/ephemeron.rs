@@ -0,0 +1,282 @@
+//! Ephemerons: pairs whose value is kept alive only as long as their key is.
+//!
+//! An ephemeron's `scan()` reports nothing, so neither its key nor its value is
+//! reached through it by ordinary marking. Instead, every ephemeron is
+//! registered when it is created, and once marking has run out of work the
+//! collector visits each reachable ephemeron whose key has been marked and
+//! traces its value. Tracing a value can mark the key of another ephemeron, so
+//! this is repeated until a pass traces nothing new. Reachable ephemerons whose
+//! keys are still unmarked are then cleared.
+//!
+//! A value which refers to its own key therefore doesn't keep the key alive,
+//! which is what makes ephemerons suitable for weak-keyed caches and
+//! side tables.
+//!
+//! Soft and phantom references are registered the same way. The keys of soft
+//! references are traced as if they were strong, unless the collector is under
+//! memory pressure. Phantom references are only cleared once their key is
+//! about to be swept, which for an object with a destructor is the collection
+//! after the one which ran it.
+//!
+//! Clearing and forgetting dead ephemerons are only noted while the world is
+//! stopped. Tokens are posted to queues, and the registry pruned, once it has
+//! resumed, as either may allocate or free.
+
+use std::{
+    cell::Cell,
+    fmt,
+    sync::{Mutex, MutexGuard}
+};
+
+use crate::{
+    collector::{heap_ptr, Header, HDR},
+    threads, Gc, RefQueue, Scan, Tracer
+};
+
+/// A key-weak pair in the GC heap. The value is reachable only while both the
+/// ephemeron and its key are reachable by other means: once the key becomes
+/// unreachable, the collector clears the ephemeron, and the key and value are
+/// reclaimed (unless something else refers to them).
+///
+/// Keys are compared by identity, so the key is a `Gc`.
+pub struct Ephemeron<K: ?Sized, V> {
+    key: Cell<Option<Gc<K>>>,
+    value: V
+}
+
+impl<K: ?Sized, V: Scan> Ephemeron<K, V> {
+    /// Allocates an ephemeron mapping `key` to `value`. Panics if the heap is
+    /// exhausted.
+    pub fn new(key: Gc<K>, value: V) -> Gc<Self> {
+        Self::register(key, value, None, Strength::Weak)
+    }
+
+    /// Like `new()`, but `token` is posted to `queue` when the ephemeron is
+    /// cleared. Nothing is posted if the ephemeron itself becomes unreachable
+    /// first.
+    pub fn with_queue(key: Gc<K>, value: V, queue: &RefQueue, token: usize) -> Gc<Self> {
+        Self::register(key, value, Some((queue.clone(), token)), Strength::Weak)
+    }
+
+    /// Allocates an ephemeron whose key is held with the given strength.
+    pub(crate) fn register(
+        key: Gc<K>,
+        value: V,
+        notify: Option<(RefQueue, usize)>,
+        strength: Strength
+    ) -> Gc<Self> {
+        // The collector takes the lock while the world is stopped. Allocating
+        // in the same critical section means no collection can come between
+        // the two and find the ephemeron unregistered, when nothing would
+        // trace its key or value.
+        threads::no_suspend(|| {
+            let e = Gc::new(Ephemeron {
+                key: Cell::new(Some(key)),
+                value
+            });
+            registry().push(Entry {
+                obj: Gc::as_ptr(&e).addr(),
+                key: key_of::<K, V>,
+                trace_value: trace_value::<K, V>,
+                clear: clear::<K, V>,
+                traced: false,
+                cleared: false,
+                dead: false,
+                notify,
+                strength
+            });
+            e
+        })
+    }
+}
+
+impl<K: ?Sized, V> Ephemeron<K, V> {
+    /// Returns the key, or `None` if the ephemeron has been cleared.
+    pub fn key(&self) -> Option<Gc<K>> {
+        self.key.get()
+    }
+
+    /// Returns the value, or `None` if the ephemeron has been cleared: the
+    /// value is no longer traced then, so any GC pointers it holds may dangle.
+    pub fn value(&self) -> Option<&V> {
+        self.key.get().map(|_| &self.value)
+    }
+
+    /// Returns the key and value, or `None` if the ephemeron has been cleared.
+    pub fn get(&self) -> Option<(Gc<K>, &V)> {
+        self.key.get().map(|k| (k, &self.value))
+    }
+}
+
+/// The key and value are traced by the collector, not by `scan()`.
+impl<K: ?Sized, V> Scan for Ephemeron<K, V> {
+    fn needs_trace() -> bool {
+        false
+    }
+}
+
+impl<K: ?Sized + fmt::Debug, V: fmt::Debug> fmt::Debug for Ephemeron<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some((k, v)) => f
+                .debug_struct("Ephemeron")
+                .field("key", &k)
+                .field("value", v)
+                .finish(),
+            None => f.write_str("Ephemeron(<cleared>)")
+        }
+    }
+}
+
+/// A registered ephemeron, with functions to get at its fields without knowing
+/// their types.
+struct Entry {
+    obj: usize,
+    key: unsafe fn(usize) -> Option<usize>,
+    trace_value: unsafe fn(usize, &mut Tracer),
+    clear: unsafe fn(usize),
+    /// Set once the value has been traced by the current collection.
+    traced: bool,
+    /// Set when the current collection has cleared the ephemeron.
+    cleared: bool,
+    /// Set when the ephemeron wasn't marked, and is about to be swept.
+    dead: bool,
+    /// The queue to post to, and what, when the ephemeron is cleared.
+    notify: Option<(RefQueue, usize)>,
+    strength: Strength
+}
+
+/// How strongly an ephemeron holds its key.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Strength {
+    Weak,
+    /// Strong unless the collector is under memory pressure.
+    Soft,
+    /// Weak, and not cleared while the key has a destructor still to run.
+    Phantom
+}
+
+static EPHEMERONS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn registry() -> MutexGuard<'static, Vec<Entry>> {
+    EPHEMERONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+unsafe fn key_of<K: ?Sized, V>(obj: usize) -> Option<usize> {
+    (*heap_ptr::<Ephemeron<K, V>>(obj))
+        .key
+        .get()
+        .map(|k| (Gc::as_ptr(&k) as *const u8).addr())
+}
+
+unsafe fn trace_value<K: ?Sized, V: Scan>(obj: usize, tracer: &mut Tracer) {
+    (*heap_ptr::<Ephemeron<K, V>>(obj)).value.scan(tracer);
+}
+
+unsafe fn clear<K: ?Sized, V>(obj: usize) {
+    (*heap_ptr::<Ephemeron<K, V>>(obj)).key.set(None);
+}
+
+unsafe fn is_marked(obj: usize) -> bool {
+    (*heap_ptr::<Header>(obj - HDR)).is_marked()
+}
+
+/// Traces the value of every marked ephemeron whose key is marked, and which
+/// hasn't been traced already. Unless `clear_soft` is set, the keys of soft
+/// references are traced as well, whether marked or not. Returns `false` if
+/// there was nothing to trace, in which case marking has reached its fixpoint.
+///
+/// # Safety
+///
+/// The world must be stopped, and nothing may have been swept.
+pub(crate) unsafe fn trace_values(tracer: &mut Tracer, clear_soft: bool) -> bool {
+    let mut traced = false;
+    for e in registry().iter_mut() {
+        if e.traced || !is_marked(e.obj) {
+            continue;
+        }
+        match (e.key)(e.obj) {
+            Some(key) if is_marked(key) || (e.strength == Strength::Soft && !clear_soft) => {
+                tracer.trace(heap_ptr::<u8>(key));
+                (e.trace_value)(e.obj, tracer);
+                e.traced = true;
+                traced = true;
+            }
+            _ => ()
+        }
+    }
+    traced
+}
+
+/// Clears every marked ephemeron, other than phantom references, whose key is
+/// unmarked. Must be called once `trace_values()` has reached its fixpoint, and
+/// before objects awaiting destructors are traced.
+///
+/// # Safety
+///
+/// As for `trace_values()`.
+pub(crate) unsafe fn clear_dead_keys() {
+    clear_unmarked(|s| s != Strength::Phantom);
+}
+
+/// Clears every marked phantom reference whose key is unmarked. Must be called
+/// once marking has finished.
+///
+/// # Safety
+///
+/// As for `trace_values()`.
+pub(crate) unsafe fn clear_phantoms() {
+    clear_unmarked(|s| s == Strength::Phantom);
+}
+
+unsafe fn clear_unmarked(which: impl Fn(Strength) -> bool) {
+    let mut cleared = 0;
+    for e in registry().iter_mut() {
+        if !which(e.strength) || !is_marked(e.obj) {
+            continue;
+        }
+        if let Some(key) = (e.key)(e.obj) {
+            if !is_marked(key) {
+                (e.clear)(e.obj);
+                e.cleared = true;
+                cleared += 1;
+            }
+        }
+    }
+    if cleared > 0 {
+        debug!("{} ephemerons cleared", cleared);
+    }
+}
+
+/// Notes which ephemerons weren't marked, as they are about to be swept, and
+/// readies the rest for the next collection.
+///
+/// # Safety
+///
+/// As for `trace_values()`.
+pub(crate) unsafe fn note_unmarked() {
+    for e in registry().iter_mut() {
+        e.traced = false;
+        e.dead = !is_marked(e.obj);
+    }
+}
+
+/// Posts the tokens of the ephemerons cleared by the last collection, and
+/// forgets the ephemerons it reclaimed. Must be called once the world has
+/// resumed, and before the next collection.
+pub(crate) fn after_collection() {
+    let mut posted = 0;
+    registry().retain_mut(|e| {
+        if e.cleared {
+            e.cleared = false;
+            if let Some((queue, token)) = e.notify.take() {
+                queue.post(token);
+                posted += 1;
+            }
+        }
+        !e.dead
+    });
+    if posted > 0 {
+        debug!("{} tokens posted to reference queues", posted);
+    }
+}
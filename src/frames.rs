@@ -0,0 +1,220 @@
+//! Walking the frames of a stack, to find the return addresses which the
+//! safepoint table describes.
+//!
+//! By default frames are found by following the frame pointer chain, which is
+//! fast but relies on every frame keeping a frame pointer. With the
+//! `libunwind` feature they are found by libunwind from the unwind tables
+//! instead. That is slower, but copes with frames which don't keep a frame
+//! pointer (e.g. in libraries built without them) and with signal frames.
+//!
+//! Either way, a walk starts from a *stack top*, which a thread records when it
+//! stops: its frame pointer, or with libunwind, the address of a saved register
+//! context.
+
+/// A frame found by a walk.
+pub(crate) struct Frame {
+    /// The address the frame returns to.
+    pub(crate) ret: u64,
+    /// The caller's stack pointer at the call, which the caller's stack slots
+    /// in the safepoint table are relative to.
+    pub(crate) sp: usize,
+    /// The caller's frame pointer at the call, which its stack slots may be
+    /// relative to instead.
+    pub(crate) fp: usize
+}
+
+#[cfg(not(feature = "libunwind"))]
+mod imp {
+    use super::Frame;
+
+    /// The frames on a stack, innermost first.
+    pub(crate) struct Frames {
+        fp: usize,
+        lo: usize,
+        hi: usize
+    }
+
+    impl Frames {
+        /// The frames of a stack which stopped at a safepoint (or in the
+        /// runtime), starting from the frame whose frame pointer is `top`.
+        pub(crate) unsafe fn new(top: usize) -> Self {
+            Frames {
+                fp: top,
+                lo: 0,
+                hi: usize::MAX
+            }
+        }
+
+        /// The frames of a thread suspended at an arbitrary instruction, with
+        /// stack pointer `sp`. The innermost frames may not maintain a frame
+        /// pointer, so the chain is only followed while it stays within the
+        /// stack.
+        pub(crate) unsafe fn suspended(top: usize, sp: usize, stack_end: usize) -> Self {
+            Frames {
+                fp: top,
+                lo: sp,
+                hi: stack_end
+            }
+        }
+    }
+
+    impl Iterator for Frames {
+        type Item = Frame;
+
+        fn next(&mut self) -> Option<Frame> {
+            let fp = self.fp;
+            if fp == 0 || fp < self.lo || fp.saturating_add(16) > self.hi {
+                return None;
+            }
+            // With frame pointers, the return address sits just above the saved
+            // frame pointer, which is the caller's, and the caller's stack
+            // pointer at the call site is just above that.
+            let ret = unsafe { *((fp + 8) as *const u64) };
+            let next = unsafe { *(fp as *const usize) };
+            self.fp = if next <= fp { 0 } else { next };
+            Some(Frame {
+                ret,
+                sp: fp + 16,
+                fp: next
+            })
+        }
+    }
+
+    /// Calls `f` with the stack top of the calling function.
+    #[inline(always)]
+    pub(crate) fn with_current<R, F: FnOnce(usize) -> R>(f: F) -> R {
+        f(crate::threads::frame_pointer())
+    }
+}
+
+#[cfg(feature = "libunwind")]
+mod imp {
+    use std::{mem, os::raw::c_int};
+
+    use super::Frame;
+
+    /// libunwind's `unw_context_t`, which on x86_64 Linux is a `ucontext_t`.
+    pub(crate) type Context = libc::ucontext_t;
+
+    /// `UNW_TDEP_CURSOR_LEN` words.
+    #[repr(C)]
+    struct Cursor([u64; 127]);
+
+    const UNW_REG_IP: c_int = 16;
+    const UNW_REG_SP: c_int = 7;
+    const UNW_X86_64_RBP: c_int = 6;
+    const UNW_INIT_SIGNAL_FRAME: c_int = 1;
+
+    #[link(name = "unwind")]
+    extern "C" {
+        #[link_name = "_Ux86_64_getcontext"]
+        fn unw_getcontext(ctx: *mut Context) -> c_int;
+        #[link_name = "_ULx86_64_init_local2"]
+        fn unw_init_local2(cursor: *mut Cursor, ctx: *mut Context, flag: c_int) -> c_int;
+        #[link_name = "_ULx86_64_step"]
+        fn unw_step(cursor: *mut Cursor) -> c_int;
+        #[link_name = "_ULx86_64_get_reg"]
+        fn unw_get_reg(cursor: *mut Cursor, reg: c_int, val: *mut u64) -> c_int;
+    }
+
+    /// Saves the calling function's registers in `ctx`. Must be inlined, so
+    /// that the context is that of the function which records it.
+    #[inline(always)]
+    pub(crate) unsafe fn getcontext(ctx: *mut Context) {
+        unw_getcontext(ctx);
+    }
+
+    /// The frames on a stack, innermost first.
+    ///
+    /// libunwind's cursor points to itself, so it is only initialised by the
+    /// first call to `next()`, and a walk mustn't be moved once it has started.
+    /// Keeping it inline means a walk doesn't allocate, which it mustn't while
+    /// the world is stopped.
+    pub(crate) struct Frames {
+        cursor: Cursor,
+        top: usize,
+        flag: c_int,
+        lo: usize,
+        hi: usize,
+        started: bool,
+        done: bool
+    }
+
+    impl Frames {
+        unsafe fn init(top: usize, flag: c_int, lo: usize, hi: usize) -> Self {
+            Frames {
+                cursor: Cursor([0; 127]),
+                top,
+                flag,
+                lo,
+                hi,
+                started: false,
+                done: top == 0
+            }
+        }
+
+        /// The frames of a stack which stopped at a safepoint (or in the
+        /// runtime), starting from the frame whose registers were saved in the
+        /// context at `top`.
+        pub(crate) unsafe fn new(top: usize) -> Self {
+            Frames::init(top, 0, 0, usize::MAX)
+        }
+
+        /// The frames of a thread suspended at an arbitrary instruction, whose
+        /// registers a signal handler saved in the context at `top`. The walk
+        /// stops if it leaves the stack between `sp` and `stack_end`.
+        pub(crate) unsafe fn suspended(top: usize, sp: usize, stack_end: usize) -> Self {
+            Frames::init(top, UNW_INIT_SIGNAL_FRAME, sp, stack_end)
+        }
+    }
+
+    impl Iterator for Frames {
+        type Item = Frame;
+
+        fn next(&mut self) -> Option<Frame> {
+            if self.done {
+                return None;
+            }
+            let (mut ret, mut sp, mut fp) = (0, 0, 0);
+            unsafe {
+                if !self.started {
+                    self.started = true;
+                    let ctx = self.top as *mut Context;
+                    if unw_init_local2(&mut self.cursor, ctx, self.flag) < 0 {
+                        self.done = true;
+                        return None;
+                    }
+                }
+                if unw_step(&mut self.cursor) <= 0
+                    || unw_get_reg(&mut self.cursor, UNW_REG_IP, &mut ret) < 0
+                    || unw_get_reg(&mut self.cursor, UNW_REG_SP, &mut sp) < 0
+                    || unw_get_reg(&mut self.cursor, UNW_X86_64_RBP, &mut fp) < 0
+                {
+                    self.done = true;
+                    return None;
+                }
+            }
+            let sp = sp as usize;
+            if sp < self.lo || sp > self.hi {
+                self.done = true;
+                return None;
+            }
+            Some(Frame {
+                ret,
+                sp,
+                fp: fp as usize
+            })
+        }
+    }
+
+    /// Calls `f` with the stack top of this function, whose frame stays live
+    /// while `f` runs.
+    #[inline(never)]
+    pub(crate) fn with_current<R, F: FnOnce(usize) -> R>(f: F) -> R {
+        let mut ctx: Context = unsafe { mem::zeroed() };
+        unsafe { getcontext(&mut ctx) };
+        f(&mut ctx as *mut Context as usize)
+    }
+}
+
+pub(crate) use imp::*;
@@ -0,0 +1,281 @@
+use std::{fmt, ops::Deref, ptr::NonNull};
+
+use crate::{
+    alloc_raw, alloc_uncollectable,
+    collector::{heap_ptr, Header, HDR},
+    try_alloc_raw, GcErr, Scan, Tracer, COLLECTOR
+};
+
+/// A pointer to an object in the GC heap.
+///
+/// `Gc<T>` is `Copy`: the collector, rather than a reference count, decides
+/// when the object is no longer reachable. A `Gc` is only safe to hold where
+/// the collector can see it -- on the stack across a safepoint, or inside
+/// another object which reports it from `Scan::scan()`.
+///
+/// When the object becomes unreachable, its destructor is run by the collection
+/// which found it, once the other threads have been resumed, so that what it
+/// owns outside the GC heap is released. As `T` need not be `Send`, that only
+/// happens if the collecting thread allocated the object: otherwise the
+/// destructor runs on the thread which did, at its next safepoint poll, and is
+/// never run if that thread exits first. Its memory is reclaimed by the next
+/// collection after the destructor has run. An unreachable object with a destructor which other unreachable
+/// objects with destructors refer to, directly or not, is only dropped by a
+/// later collection, after theirs have run, so a destructor may dereference a
+/// `Gc` unless the objects form a cycle. The members of a cycle are dropped in
+/// no particular order, and an object which refers to one may find it dropped
+/// already, in which case dereferencing it panics. A destructor may allocate on the GC heap, which then grows rather
+/// than collects: any collection it would start, including with
+/// `force_collect()`, is deferred to the next safepoint poll.
+///
+/// A destructor runs at most once. One which stores a `Gc` to its own object
+/// somewhere reachable resurrects it: the object is kept for as long as it is
+/// reachable, but its destructor doesn't run again when it next dies. As its
+/// fields have been dropped, a resurrected object is no longer traced, and
+/// dereferencing a `Gc` to it panics. `Gc::is_finalised()` tells whether an
+/// object's destructor has run.
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<T>
+}
+
+impl<T: Scan> Gc<T> {
+    /// Moves `value` into the GC heap, collecting or growing the heap if
+    /// necessary. Panics if the heap is exhausted.
+    pub fn new(value: T) -> Self {
+        match alloc_raw(value) {
+            Ok(ptr) => Gc {
+                ptr: unsafe { NonNull::new_unchecked(ptr) }
+            },
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Moves `value` into the GC heap as an object which is never reclaimed
+    /// (see `alloc_uncollectable()`). Panics if the heap is exhausted.
+    pub fn new_uncollectable(value: T) -> Self {
+        match alloc_uncollectable(value) {
+            Ok(ptr) => Gc {
+                ptr: unsafe { NonNull::new_unchecked(ptr) }
+            },
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Moves `value` into the GC heap only if there is already room for it.
+    /// This never triggers a collection.
+    pub fn try_new(value: T) -> Result<Self, GcErr> {
+        let ptr = try_alloc_raw(value)?;
+        Ok(Gc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) }
+        })
+    }
+}
+
+impl<T: ?Sized> Gc<T> {
+    /// Moves `value` into the GC heap and returns it behind a dynamically sized
+    /// pointer, typically a trait object. `coerce` performs the unsizing and is
+    /// usually just `|p| p as *mut dyn Trait`. Panics if the heap is exhausted.
+    ///
+    /// The object's header records how to trace the concrete type `U`, so a
+    /// `Gc<dyn Trait>` is traced precisely even though `dyn Trait` itself need
+    /// not implement `Scan`.
+    ///
+    /// # Safety
+    ///
+    /// As for `Gc::unsize()`.
+    pub unsafe fn new_unsized<U: Scan>(value: U, coerce: fn(*mut U) -> *mut T) -> Self {
+        Gc::unsize(Gc::new(value), coerce)
+    }
+
+    /// Converts a `Gc<U>` into a `Gc<T>` using `coerce`, which must return a
+    /// pointer to the same object (e.g. `|p| p as *mut dyn Trait`).
+    ///
+    /// # Safety
+    ///
+    /// `coerce` must be an unsizing coercion: `T` must be a type `U` coerces to,
+    /// such as a trait it implements or a slice of its elements, so that the
+    /// pointer's metadata describes the object. Only the address is checked,
+    /// so any other cast, e.g. to an unrelated trait object or to a slice
+    /// longer than the object, reinterprets the object as something it isn't.
+    pub unsafe fn unsize<U>(this: Gc<U>, coerce: fn(*mut U) -> *mut T) -> Self {
+        let ptr = coerce(this.ptr.as_ptr());
+        assert_eq!(
+            ptr as *mut u8,
+            this.ptr.as_ptr() as *mut u8,
+            "Gc::unsize must not change the object's address"
+        );
+        Gc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) }
+        }
+    }
+
+    /// Consumes the `Gc`, returning the wrapped pointer so that it can be
+    /// stored somewhere the collector cannot see (e.g. in a C data structure).
+    ///
+    /// The object is registered as an extra root and will not be collected
+    /// until the pointer is turned back into a `Gc` with `from_raw`.
+    pub fn into_raw(this: Self) -> *const T {
+        COLLECTOR.with(|c| c.add_raw_root(this.ptr.as_ptr() as *mut u8));
+        this.ptr.as_ptr()
+    }
+
+    /// Reconstructs a `Gc` from a pointer previously returned by `into_raw`,
+    /// releasing the extra root which `into_raw` registered.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `Gc::into_raw`, and each call to `into_raw`
+    /// must be paired with at most one call to `from_raw`.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        COLLECTOR.with(|c| c.remove_raw_root(ptr as *mut u8));
+        Gc {
+            ptr: NonNull::new_unchecked(ptr as *mut T)
+        }
+    }
+
+    /// Wraps a pointer to an object in the GC heap.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live object of type `T`.
+    pub(crate) unsafe fn from_heap(ptr: *mut T) -> Self {
+        Gc {
+            ptr: NonNull::new_unchecked(ptr)
+        }
+    }
+
+    /// Returns the raw pointer to the object without registering a root. The
+    /// pointer is only valid until the next safepoint.
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+
+    /// Returns `true` if the object's destructor has run, in which case it has
+    /// been resurrected.
+    pub fn is_finalised(this: &Self) -> bool {
+        let obj = (this.ptr.as_ptr() as *const u8).addr();
+        unsafe { (*heap_ptr::<Header>(obj - HDR)).is_finalised() }
+    }
+
+    /// Returns `true` if both `Gc`s point to the same object. This is object
+    /// identity, not structural equality.
+    #[inline]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr.as_ptr() as *const u8 == other.ptr.as_ptr() as *const u8
+    }
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> Deref for Gc<T> {
+    type Target = T;
+
+    /// Panics if the object's destructor has run.
+    #[inline]
+    fn deref(&self) -> &T {
+        assert!(
+            !Gc::is_finalised(self),
+            "dereferenced a Gc to an object whose destructor has run"
+        );
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Scan for Gc<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        tracer.trace(self.ptr.as_ptr());
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use std::{
+        cell::Cell,
+        panic::{self, AssertUnwindSafe},
+        sync::atomic::{AtomicUsize, Ordering}
+    };
+
+    use super::*;
+    use crate::{force_collect, is_gc_ptr, tests::run};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Where a `Phoenix` stores itself when dropped.
+    struct Nest(Cell<Option<Gc<Phoenix>>>);
+
+    impl Scan for Nest {
+        fn scan(&self, tracer: &mut Tracer) {
+            self.0.get().scan(tracer);
+        }
+    }
+
+    /// Stores itself back into `nest` when dropped.
+    struct Phoenix {
+        this: Cell<Option<Gc<Phoenix>>>,
+        nest: *const Nest
+    }
+
+    impl Scan for Phoenix {
+        fn scan(&self, tracer: &mut Tracer) {
+            self.this.get().scan(tracer);
+        }
+    }
+
+    impl Drop for Phoenix {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+            unsafe { (*self.nest).0.set(self.this.get()) };
+        }
+    }
+
+    #[test]
+    fn resurrected_objects_are_kept_and_dropped_once() {
+        run(|| {
+            DROPS.store(0, Ordering::SeqCst);
+            crate::disable();
+            let nest = Gc::into_raw(Gc::new(Nest(Cell::new(None))));
+            let phoenix = Gc::new(Phoenix {
+                this: Cell::new(None),
+                nest
+            });
+            phoenix.this.set(Some(phoenix));
+            assert!(!Gc::is_finalised(&phoenix));
+            let obj = Gc::as_ptr(&phoenix);
+            crate::enable();
+
+            force_collect();
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+            let back = unsafe { (*nest).0.get() }.unwrap();
+            assert!(Gc::ptr_eq(&back, &phoenix));
+            assert!(Gc::is_finalised(&back));
+            assert!(panic::catch_unwind(AssertUnwindSafe(|| back.nest)).is_err());
+
+            // Kept while it is reachable, and never dropped again.
+            for _ in 0..3 {
+                force_collect();
+                assert!(is_gc_ptr(obj));
+            }
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+            // Once it is unreachable again, it is reclaimed without being
+            // dropped.
+            unsafe { (*nest).0.set(None) };
+            force_collect();
+            assert!(!is_gc_ptr(obj));
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+            unsafe { Gc::from_raw(nest) };
+        });
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    borrow::Borrow,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    ops::Deref,
+    ptr::{self, NonNull},
+    slice, str
+};
+
+use crate::{
+    collector::{AllocPolicy, TypeInfo},
+    GcErr, Scan, Tracer, COLLECTOR
+};
+
+/// The string contains no GC pointers, so there is nothing to trace.
+static STR_INFO: TypeInfo = TypeInfo {
+    name: |_| "gcrt::GcStr",
+    trace: None,
+    conservative: false
+};
+
+/// An immutable UTF-8 string stored in the GC heap.
+///
+/// The object is laid out as its length in bytes followed by the bytes
+/// themselves, so a `GcStr` is a single pointer wide and copying it is as cheap
+/// as copying a `Gc`.
+#[derive(Clone, Copy)]
+pub struct GcStr {
+    ptr: NonNull<usize>
+}
+
+impl GcStr {
+    /// Copies `s` into the GC heap. Panics if the heap is exhausted.
+    pub fn new(s: &str) -> Self {
+        match Self::try_new(s) {
+            Ok(gs) => gs,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Copies `s` into the GC heap, returning an error if the heap is
+    /// exhausted.
+    pub fn try_new(s: &str) -> Result<Self, GcErr> {
+        let obj = Self::alloc(s.len())?;
+        unsafe {
+            ptr::write(obj, s.len());
+            ptr::copy_nonoverlapping(s.as_ptr(), obj.add(1) as *mut u8, s.len());
+            Ok(GcStr {
+                ptr: NonNull::new_unchecked(obj)
+            })
+        }
+    }
+
+    /// Allocates an uninitialised string of `len` bytes.
+    fn alloc(len: usize) -> Result<*mut usize, GcErr> {
+        let size = mem::size_of::<usize>().saturating_add(len);
+        Ok(COLLECTOR.alloc_block(size, &STR_INFO, AllocPolicy::Collect)? as *mut usize)
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe {
+            let len = *self.ptr.as_ptr();
+            let bytes = slice::from_raw_parts(self.ptr.as_ptr().add(1) as *const u8, len);
+            str::from_utf8_unchecked(bytes)
+        }
+    }
+
+    /// Returns `true` if both `GcStr`s refer to the same object in the GC heap.
+    #[inline]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+}
+
+impl Deref for GcStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for GcStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for GcStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for GcStr {
+    fn from(s: &str) -> Self {
+        GcStr::new(s)
+    }
+}
+
+impl PartialEq for GcStr {
+    fn eq(&self, other: &Self) -> bool {
+        GcStr::ptr_eq(self, other) || self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for GcStr {}
+
+impl PartialEq<str> for GcStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl Hash for GcStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for GcStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for GcStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Scan for GcStr {
+    fn scan(&self, tracer: &mut Tracer) {
+        tracer.trace(self.ptr.as_ptr());
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::tests::run;
+
+    #[test]
+    fn length_overflow_is_too_large() {
+        run(|| {
+            assert!(matches!(
+                GcStr::alloc(usize::MAX),
+                Err(GcErr::TooLarge(usize::MAX))
+            ));
+            assert!(matches!(
+                GcStr::alloc(isize::MAX as usize),
+                Err(GcErr::TooLarge(_))
+            ));
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fmt, ops::Deref};
+
+use crate::{threads, Gc, Scan, Tracer};
+
+/// A `Gc` which may be shared between threads, for immutable runtime
+/// structures such as interned symbols or code objects.
+///
+/// Plain `Gc<T>` is neither `Send` nor `Sync`. Sending a GC pointer to another
+/// thread is only sound if the collector can see it there, so a `GcSync` must
+/// only ever be held by registered threads (see `register_thread()`), where it
+/// is found like any other root: on the stack, or inside a scanned object. It
+/// must not be stored in a `static` or in memory the collector does not scan;
+/// use `GcSync::leak` for objects which should live forever. Debug builds
+/// check that the thread dereferencing a `GcSync` is registered.
+///
+/// `T` must be `Sync`, since every thread holding the pointer may read the
+/// object concurrently. The collector stops every registered thread before it
+/// runs, so no thread can observe an object being freed.
+pub struct GcSync<T: ?Sized + Sync> {
+    gc: Gc<T>
+}
+
+unsafe impl<T: ?Sized + Sync> Send for GcSync<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for GcSync<T> {}
+
+impl<T: Scan + Sync> GcSync<T> {
+    /// Moves `value` into the GC heap. Panics if the heap is exhausted.
+    pub fn new(value: T) -> Self {
+        GcSync { gc: Gc::new(value) }
+    }
+}
+
+impl<T: ?Sized + Sync> GcSync<T> {
+    /// Returns a thread-local `Gc` to the same object.
+    pub fn get(this: &Self) -> Gc<T> {
+        check_registered();
+        this.gc
+    }
+
+    /// Roots the object permanently and returns a reference to it which may be
+    /// stored anywhere, including in a `static`. The object is never freed.
+    pub fn leak(this: Self) -> &'static T {
+        unsafe { &*Gc::into_raw(this.gc) }
+    }
+
+    /// Returns `true` if both `GcSync`s point to the same object.
+    #[inline]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Gc::ptr_eq(&this.gc, &other.gc)
+    }
+}
+
+#[inline]
+fn check_registered() {
+    debug_assert!(
+        threads::is_registered(),
+        "GcSync used on a thread which is not registered with the collector"
+    );
+}
+
+impl<T: ?Sized + Sync> From<Gc<T>> for GcSync<T> {
+    fn from(gc: Gc<T>) -> Self {
+        GcSync { gc }
+    }
+}
+
+impl<T: ?Sized + Sync> Clone for GcSync<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized + Sync> Copy for GcSync<T> {}
+
+impl<T: ?Sized + Sync> Deref for GcSync<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        check_registered();
+        &self.gc
+    }
+}
+
+impl<T: ?Sized + Sync> Scan for GcSync<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        self.gc.scan(tracer);
+    }
+}
+
+impl<T: ?Sized + Sync + fmt::Debug> fmt::Debug for GcSync<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
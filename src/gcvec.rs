@@ -0,0 +1,253 @@
+use std::{
+    any, fmt, mem,
+    ops::Deref,
+    ptr::{self, NonNull},
+    slice
+};
+
+use crate::{
+    collector::{AllocPolicy, TypeInfo},
+    write_barrier, GcErr, Scan, Tracer, COLLECTOR
+};
+
+/// The number of `usize` words preceding the elements in a `GcVec` buffer: the
+/// capacity, followed by the length.
+const BUF_HDR: usize = 2;
+
+/// A growable vector whose elements live in the GC heap.
+///
+/// The backing buffer is a single GC object laid out as its capacity, its
+/// length, and then the elements. The collector traces exactly the first `len`
+/// elements, so uninitialised capacity is never scanned. Growing allocates a
+/// new buffer and copies the elements across; the old buffer is left for the
+/// collector.
+pub struct GcVec<T: Scan> {
+    buf: Option<NonNull<usize>>,
+    _marker: std::marker::PhantomData<T>
+}
+
+unsafe fn trace_buf<T: Scan>(obj: *const u8, tracer: &mut Tracer) {
+    let buf = obj as *const usize;
+    let len = *buf.add(1);
+    let elems = buf.add(BUF_HDR) as *const T;
+    slice::from_raw_parts(elems, len).scan(tracer);
+}
+
+impl<T: Scan> GcVec<T> {
+    const BUF_TRACED: TypeInfo = TypeInfo {
+        name: |_| any::type_name::<[T]>(),
+        trace: Some(trace_buf::<T>),
+        conservative: false
+    };
+    const BUF_UNTRACED: TypeInfo = TypeInfo {
+        name: |_| any::type_name::<[T]>(),
+        trace: None,
+        conservative: false
+    };
+
+    /// Creates an empty `GcVec`. Nothing is allocated until the first push.
+    pub fn new() -> Self {
+        GcVec {
+            buf: None,
+            _marker: std::marker::PhantomData
+        }
+    }
+
+    /// Creates an empty `GcVec` with room for at least `cap` elements. Panics if
+    /// the heap is exhausted.
+    pub fn with_capacity(cap: usize) -> Self {
+        let mut v = GcVec::new();
+        if cap > 0 {
+            if let Err(e) = v.realloc(cap) {
+                panic!("{}", e);
+            }
+        }
+        v
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self.buf {
+            Some(buf) => unsafe { *buf.as_ptr().add(1) },
+            None => 0
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        match self.buf {
+            Some(buf) => unsafe { *buf.as_ptr() },
+            None => 0
+        }
+    }
+
+    fn elems(&self) -> *mut T {
+        match self.buf {
+            Some(buf) => unsafe { buf.as_ptr().add(BUF_HDR) as *mut T },
+            None => NonNull::dangling().as_ptr()
+        }
+    }
+
+    fn set_len(&mut self, len: usize) {
+        if let Some(buf) = self.buf {
+            unsafe { *buf.as_ptr().add(1) = len };
+        }
+    }
+
+    /// Moves the elements into a new buffer with room for `cap` elements.
+    fn realloc(&mut self, cap: usize) -> Result<(), GcErr> {
+        assert!(
+            mem::align_of::<T>() <= 2 * mem::size_of::<usize>(),
+            "GcVec elements must be at most 16-byte aligned"
+        );
+        // A size which overflows is too large for the heap anyway.
+        let size = mem::size_of::<T>()
+            .saturating_mul(cap)
+            .saturating_add(BUF_HDR * mem::size_of::<usize>());
+        let ty = if T::needs_trace() {
+            &Self::BUF_TRACED
+        } else {
+            &Self::BUF_UNTRACED
+        };
+
+        // The old buffer is only reachable through `self`, which the collector
+        // may not be able to see, so keep it rooted across the allocation.
+        let old = self.buf;
+        if let Some(old) = old {
+            COLLECTOR.with(|c| c.add_raw_root(old.as_ptr() as *mut u8));
+        }
+        let new = COLLECTOR.alloc_block(size, ty, AllocPolicy::Collect);
+        if let Some(old) = old {
+            COLLECTOR.with(|c| c.remove_raw_root(old.as_ptr() as *mut u8));
+        }
+        let new = new? as *mut usize;
+
+        let len = self.len();
+        unsafe {
+            // The length must be written before anything else so that a
+            // collection never traces uninitialised elements.
+            ptr::write(new.add(1), 0);
+            ptr::write(new, cap);
+            ptr::copy_nonoverlapping(self.elems(), new.add(BUF_HDR) as *mut T, len);
+            ptr::write(new.add(1), len);
+            self.buf = Some(NonNull::new_unchecked(new));
+        }
+        Ok(())
+    }
+
+    /// Appends `value`, growing the buffer if necessary. Panics if the heap is
+    /// exhausted.
+    pub fn push(&mut self, value: T) {
+        let len = self.len();
+        if len == self.capacity() {
+            let cap = if len == 0 { 4 } else { len * 2 };
+            if let Err(e) = self.realloc(cap) {
+                panic!("{}", e);
+            }
+        }
+        unsafe { ptr::write(self.elems().add(len), value) };
+        self.set_len(len + 1);
+        write_barrier(self.buf.unwrap().as_ptr());
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        self.set_len(len - 1);
+        Some(unsafe { ptr::read(self.elems().add(len - 1)) })
+    }
+
+    /// Replaces the element at `idx`, returning the old value. Panics if `idx`
+    /// is out of bounds.
+    pub fn set(&mut self, idx: usize, value: T) -> T {
+        assert!(idx < self.len(), "GcVec index out of bounds");
+        let old = unsafe { ptr::replace(self.elems().add(idx), value) };
+        write_barrier(self.buf.unwrap().as_ptr());
+        old
+    }
+
+    pub fn clear(&mut self) {
+        let len = self.len();
+        self.set_len(0);
+        unsafe { ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.elems(), len)) };
+    }
+}
+
+impl<T: Scan> Default for GcVec<T> {
+    fn default() -> Self {
+        GcVec::new()
+    }
+}
+
+impl<T: Scan> Deref for GcVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.elems(), self.len()) }
+    }
+}
+
+impl<T: Scan> Drop for GcVec<T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: Scan + Clone> Clone for GcVec<T> {
+    fn clone(&self) -> Self {
+        let mut v = GcVec::with_capacity(self.len());
+        for x in self.iter() {
+            v.push(x.clone());
+        }
+        v
+    }
+}
+
+impl<T: Scan + fmt::Debug> fmt::Debug for GcVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Scan> Scan for GcVec<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        if let Some(buf) = self.buf {
+            tracer.trace(buf.as_ptr());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::tests::run;
+
+    #[test]
+    fn capacity_overflow_is_too_large() {
+        run(|| {
+            let mut v = GcVec::<u64>::new();
+            assert!(matches!(
+                v.realloc(usize::MAX),
+                Err(GcErr::TooLarge(usize::MAX))
+            ));
+            assert!(matches!(
+                v.realloc(isize::MAX as usize / 8),
+                Err(GcErr::TooLarge(_))
+            ));
+            assert_eq!(v.capacity(), 0);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn with_capacity_overflow_panics() {
+        run(|| GcVec::<u64>::with_capacity(usize::MAX));
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+/// The kind of a collection. The current collector is non-generational, so
+/// every collection is `Major`; `Minor` is reserved for nursery collections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcKind {
+    Minor,
+    Major
+}
+
+/// Why a collection was started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcReason {
+    /// A safepoint poll found that the allocation trigger threshold had been
+    /// passed.
+    Threshold,
+    /// An allocation found no room in the heap.
+    HeapFull,
+    /// The mutator called `force_collect()`.
+    Forced
+}
+
+/// A report on a single collection, as returned by `last_gc_info()`.
+#[derive(Clone, Copy, Debug)]
+pub struct GcInfo {
+    pub kind: GcKind,
+    pub reason: GcReason,
+    /// Wall-clock time from the start of root scanning to the end of sweeping.
+    pub duration: Duration,
+    /// Bytes (including headers) freed by the collection.
+    pub bytes_reclaimed: usize,
+    /// The number of objects which survived the collection.
+    pub survivors: usize,
+    /// Bytes (including headers) occupied by surviving objects.
+    pub survivor_bytes: usize
+}
+
+/// The objects of one type which survived a collection, as returned by
+/// `census()`.
+#[derive(Clone, Debug)]
+pub struct TypeCensus {
+    /// The name of the type, as given by `std::any::type_name`.
+    pub type_name: &'static str,
+    /// The number of live objects of the type.
+    pub objects: usize,
+    /// Bytes (including headers) occupied by those objects.
+    pub bytes: usize
+}
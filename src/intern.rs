@@ -0,0 +1,213 @@
+//! Interning tables whose entries are weak.
+//!
+//! An `Interner` maps each distinct value to a single GC object, without
+//! keeping the objects alive. Its slots hold the objects' addresses, outside
+//! the GC heap, and every table is registered with the collector. Once marking
+//! has finished, the collector zeroes the slots of unmarked objects, and once
+//! the world has resumed, it purges the zeroed slots, as shrinking a table may
+//! free memory.
+
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard
+    }
+};
+
+use crate::{
+    collector::{heap_ptr, Header, HDR},
+    threads, Gc, Scan
+};
+
+/// A table of GC objects, one for each distinct value interned, which doesn't
+/// keep them alive. Interning a value equal to that of an object still in the
+/// table returns the same object, so interned values can be compared by
+/// identity, e.g. with `Gc::ptr_eq()`. Once an object is otherwise
+/// unreachable, the collector removes it from the table, so the table only
+/// grows with the number of values in use.
+///
+/// A value must not change, as far as `Hash` and `Eq` can tell, once interned.
+///
+/// Like the `Gc`s it returns, an `Interner` can't be shared between threads.
+pub struct Interner<T> {
+    table: Arc<Table>,
+    hasher: RandomState,
+    _ty: PhantomData<Gc<T>>
+}
+
+/// The slots of an `Interner`, bucketed by hash. A zero slot has been cleared.
+#[derive(Default)]
+struct Table {
+    slots: Mutex<HashMap<u64, Vec<usize>>>,
+    /// Set when the last collection zeroed any slots.
+    cleared: AtomicBool
+}
+
+impl Table {
+    fn slots(&self) -> MutexGuard<'_, HashMap<u64, Vec<usize>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+static TABLES: Mutex<Vec<Arc<Table>>> = Mutex::new(Vec::new());
+
+fn tables() -> MutexGuard<'static, Vec<Arc<Table>>> {
+    TABLES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<T: Scan> Interner<T> {
+    pub fn new() -> Self {
+        let table = Arc::new(Table::default());
+        // The collector takes the locks while the world is stopped.
+        threads::no_suspend(|| tables().push(Arc::clone(&table)));
+        Interner {
+            table,
+            hasher: RandomState::new(),
+            _ty: PhantomData
+        }
+    }
+
+    /// Returns the object whose value is equal to `key`, allocating it from
+    /// `key.to_owned()` if there isn't one. Panics if the heap is exhausted.
+    pub fn intern<Q>(&self, key: &Q) -> Gc<T>
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = T>
+    {
+        let hash = self.hasher.hash_one(key);
+        if let Some(obj) = self.find(hash, key) {
+            return obj;
+        }
+        // The table is unlocked while allocating, which may collect, so
+        // another thread may have interned an equal value in the meantime.
+        let new = Gc::new(key.to_owned());
+        threads::no_suspend(|| {
+            let mut slots = self.table.slots();
+            if let Some(obj) = unsafe { lookup::<T, Q>(&slots, hash, key) } {
+                return obj;
+            }
+            slots
+                .entry(hash)
+                .or_default()
+                .push((Gc::as_ptr(&new) as *const u8).addr());
+            new
+        })
+    }
+
+    /// Returns the object whose value is equal to `key`, if there is one.
+    pub fn get<Q>(&self, key: &Q) -> Option<Gc<T>>
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        self.find(self.hasher.hash_one(key), key)
+    }
+
+    fn find<Q>(&self, hash: u64, key: &Q) -> Option<Gc<T>>
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Eq
+    {
+        threads::no_suspend(|| unsafe { lookup::<T, Q>(&self.table.slots(), hash, key) })
+    }
+}
+
+impl<T> Interner<T> {
+    /// The number of objects in the table. Objects which have become
+    /// unreachable are counted until the next collection.
+    pub fn len(&self) -> usize {
+        threads::no_suspend(|| {
+            self.table
+                .slots()
+                .values()
+                .map(|b| b.iter().filter(|&&obj| obj != 0).count())
+                .sum()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Finds the object in `hash`'s bucket whose value is equal to `key`.
+///
+/// # Safety
+///
+/// Every slot in `slots` must be zero or the address of a live `T`, which holds
+/// while the caller can't be stopped for a collection.
+unsafe fn lookup<T, Q>(slots: &HashMap<u64, Vec<usize>>, hash: u64, key: &Q) -> Option<Gc<T>>
+where
+    T: Borrow<Q>,
+    Q: ?Sized + Eq
+{
+    slots
+        .get(&hash)?
+        .iter()
+        .filter(|&&obj| obj != 0)
+        .map(|&obj| heap_ptr::<T>(obj))
+        .find(|&obj| (*obj).borrow() == key)
+        .map(|obj| Gc::from_heap(obj))
+}
+
+impl<T: Scan> Default for Interner<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Interner<T> {
+    fn drop(&mut self) {
+        threads::no_suspend(|| tables().retain(|t| !Arc::ptr_eq(t, &self.table)));
+    }
+}
+
+impl<T> fmt::Debug for Interner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Zeroes the slot of every unmarked object. Must be called once marking from
+/// the roots has finished, and before objects awaiting destructors are traced.
+/// Doesn't allocate or free.
+///
+/// # Safety
+///
+/// The world must be stopped, and nothing may have been swept.
+pub(crate) unsafe fn clear_unmarked() {
+    let mut cleared = 0;
+    for table in tables().iter() {
+        for obj in table.slots().values_mut().flatten() {
+            if *obj != 0 && !(*heap_ptr::<Header>(*obj - HDR)).is_marked() {
+                *obj = 0;
+                table.cleared.store(true, Ordering::Relaxed);
+                cleared += 1;
+            }
+        }
+    }
+    if cleared > 0 {
+        debug!("{} interned objects cleared", cleared);
+    }
+}
+
+/// Removes the slots zeroed by the last collection. Must be called once the
+/// world has resumed.
+pub(crate) fn purge() {
+    for table in tables().iter() {
+        if !table.cleared.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        table.slots().retain(|_, bucket| {
+            bucket.retain(|&obj| obj != 0);
+            !bucket.is_empty()
+        });
+    }
+}
@@ -0,0 +1,77 @@
+use std::{fmt, hint, marker::PhantomData, ops::Deref};
+
+use crate::{Gc, COLLECTOR};
+
+/// Keeps an object alive until the guard is dropped, however the object is
+/// referred to in the meantime.
+///
+/// This is for handing a raw pointer into a GC object (or into memory the
+/// object owns) to C code which uses it after the call returns, e.g. as the
+/// buffer for an asynchronous read. Nothing the collector can see refers to
+/// the object while C holds the pointer, so the guard must outlive C's use of
+/// it. Unlike `Gc::into_raw`, the root can't be leaked by forgetting to call
+/// `Gc::from_raw`.
+///
+/// Guards may be nested: the object stays alive until every guard for it has
+/// been dropped.
+pub struct KeepAlive<T: ?Sized> {
+    obj: Gc<T>,
+    // A guard's root is registered and released by the thread which holds it.
+    _not_send: PhantomData<*const ()>
+}
+
+impl<T: ?Sized> KeepAlive<T> {
+    pub fn new(obj: Gc<T>) -> Self {
+        COLLECTOR.with(|c| c.add_raw_root(Gc::as_ptr(&obj) as *mut u8));
+        KeepAlive {
+            obj,
+            _not_send: PhantomData
+        }
+    }
+
+    /// Returns the pointer to the object, which is valid for as long as the
+    /// guard is.
+    pub fn as_ptr(&self) -> *const T {
+        Gc::as_ptr(&self.obj)
+    }
+
+    /// Returns the `Gc` the guard was made from.
+    pub fn get(&self) -> Gc<T> {
+        self.obj
+    }
+}
+
+impl<T: ?Sized> Deref for KeepAlive<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.obj
+    }
+}
+
+impl<T: ?Sized> Drop for KeepAlive<T> {
+    fn drop(&mut self) {
+        COLLECTOR.with(|c| c.remove_raw_root(Gc::as_ptr(&self.obj) as *mut u8));
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for KeepAlive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("KeepAlive").field(&&**self).finish()
+    }
+}
+
+/// Ensures that `obj` is treated as live up to this call. The compiler may
+/// otherwise consider a `Gc` dead after its last use, so that the object it
+/// points to can be reclaimed while a raw pointer derived from it is still in
+/// use:
+///
+/// ```rust, ignore
+/// let buf = Gc::new([0u8; 64]);
+/// unsafe { fill(Gc::as_ptr(&buf) as *mut u8, 64) }; // may collect
+/// gcrt::keep_alive(buf);
+/// ```
+#[inline(never)]
+pub fn keep_alive<T: ?Sized>(obj: Gc<T>) {
+    hint::black_box(Gc::as_ptr(&obj));
+}
@@ -0,0 +1,159 @@
+//! Leak reporting at process exit.
+//!
+//! When enabled, the allocation site of every object is recorded, and the
+//! mutator can mark objects which it expects to become unreachable with
+//! `expect_dead()`. A final collection is run at exit, and the report lists
+//! every object expected to be dead which is still reachable, and a tally by
+//! type of the objects which were never freed.
+//!
+//! Allocation sites are identified by the return addresses on the allocating
+//! thread's stack, with a symbolised backtrace captured the first time each
+//! site is seen. Records for freed objects are dropped after every collection.
+
+use std::{
+    backtrace::Backtrace,
+    collections::{HashMap, HashSet},
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+use crate::{profile, threads, COLLECTOR};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+#[derive(Default)]
+struct State {
+    /// Allocation sites, keyed by their return addresses.
+    site_ids: HashMap<Vec<usize>, usize>,
+    sites: Vec<Backtrace>,
+    /// The allocation site of each object, keyed by address.
+    objects: HashMap<usize, usize>,
+    /// Objects passed to `expect_dead()` which haven't been freed yet.
+    expected_dead: HashSet<usize>
+}
+
+fn state() -> MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Starts recording allocation sites and arranges for the leak report to be
+/// printed at exit. Objects allocated earlier have no recorded site.
+pub(crate) fn start() {
+    let mut state = state();
+    if state.is_none() {
+        *state = Some(State::default());
+        unsafe { libc::atexit(exit_report) };
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Starts leak reporting if `RGCRT_LEAK_REPORT` is set to anything but `0`.
+pub(crate) fn install_from_env() {
+    match env::var("RGCRT_LEAK_REPORT") {
+        Ok(v) if v != "0" => start(),
+        _ => ()
+    }
+}
+
+/// Records the allocation site of the object at `obj`.
+#[inline(always)]
+pub(crate) fn record_alloc(obj: *mut u8) {
+    if enabled() {
+        record_alloc_slow(obj as usize);
+    }
+}
+
+#[inline(never)]
+fn record_alloc_slow(obj: usize) {
+    // The collector takes the lock while the world is stopped, so this thread
+    // mustn't be suspended while holding it.
+    threads::no_suspend(|| {
+        let key = profile::return_addresses();
+        let mut state = state();
+        let state = match state.as_mut() {
+            Some(s) => s,
+            None => return
+        };
+        let next = state.sites.len();
+        let site = *state.site_ids.entry(key).or_insert(next);
+        if site == next {
+            state.sites.push(Backtrace::force_capture());
+        }
+        state.objects.insert(obj, site);
+    });
+}
+
+/// Notes that the object at `obj` should become unreachable.
+pub(crate) fn expect_dead(obj: usize) {
+    if !enabled() {
+        return;
+    }
+    threads::no_suspend(|| {
+        if let Some(state) = state().as_mut() {
+            state.expected_dead.insert(obj);
+        }
+    });
+}
+
+/// Forgets every object for which `live` returns `false`. Called after each
+/// sweep, while the world is still stopped, so doesn't allocate.
+pub(crate) fn retain_live(live: impl Fn(usize) -> bool) {
+    if let Some(state) = state().as_mut() {
+        state.objects.retain(|&o, _| live(o));
+        state.expected_dead.retain(|&o| live(o));
+    }
+}
+
+extern "C" fn exit_report() {
+    // The final collection drops the records of everything it frees.
+    let census = crate::census();
+    ENABLED.store(false, Ordering::Relaxed);
+    let state = state();
+    let state = match state.as_ref() {
+        Some(s) => s,
+        None => return
+    };
+
+    eprintln!("rgcrt: leak report");
+    let mut leaked: Vec<usize> = state.expected_dead.iter().cloned().collect();
+    leaked.sort_unstable();
+    if !leaked.is_empty() {
+        eprintln!(
+            "  {} objects expected to be dead are still reachable:",
+            leaked.len()
+        );
+    }
+    for obj in leaked {
+        let (name, size) = COLLECTOR
+            .with(|c| c.object_info(obj))
+            .unwrap_or(("<unknown>", 0));
+        eprintln!("    {:#x}: {} ({} bytes), allocated at:", obj, name, size);
+        match state.objects.get(&obj) {
+            Some(&site) => {
+                for line in state.sites[site].to_string().lines() {
+                    eprintln!("        {}", line);
+                }
+            }
+            None => eprintln!("        <unknown>")
+        }
+    }
+    if !census.is_empty() {
+        eprintln!("  objects never freed, by type:");
+    }
+    for t in census {
+        eprintln!(
+            "    {} bytes in {} objects of {}",
+            t.bytes, t.objects, t.type_name
+        );
+    }
+}
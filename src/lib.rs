@@ -1,13 +1,15 @@
 //! This file contains the API for the runtime GC. Interaction with the GC is
 //! only legal through these functions.
 //!
-//! The GC runtime consists of a mutable singleton `Collector` struct:
+//! The GC runtime consists of a mutable singleton `Collector` struct, shared by
+//! every thread and protected by a lock:
 //!
 //! ```rust, ignore
 //! struct Collector {
-//!     // Heap bookkeeping information
-//!     hstart: *const usize,
-//!     hptr: *mut usize,
+//!     // Heap bookkeeping information: the chunks making up the heap and the
+//!     // blocks freed by the last collection
+//!     chunks: Vec<Chunk>,
+//!     free: Vec<*mut Header>,
 //!
 //!     // Flag to determine whether to collect at the next safepoint
 //!     collect_next: bool,
@@ -16,34 +18,343 @@
 //!     roots: HashMap<ReturnAddress, SafepointRoots>
 //! }
 
-#[cfg(not(all(target_pointer_width = "64", target_arch = "x86_64")))]
-compile_error!("Requires x86_64 with 64 bit pointer width.");
+#[cfg(not(all(
+    target_pointer_width = "64",
+    any(target_arch = "x86_64", feature = "simulation")
+)))]
+compile_error!("Requires x86_64 with 64 bit pointer width, or the `simulation` feature.");
 
+#[cfg(all(feature = "libunwind", feature = "simulation"))]
+compile_error!("The `libunwind` and `simulation` features can't be used together.");
+
+#[macro_use]
+mod log;
+
+mod alloc_rate;
+mod asan;
+#[cfg(feature = "boehm")]
+mod boehm;
+#[cfg(feature = "capi")]
+mod capi;
+mod chrome_trace;
 mod collector;
+mod crash;
+mod deque;
+pub mod dump;
+mod dylib;
+#[cfg(feature = "capi")]
+mod embed;
+mod ephemeron;
+mod frames;
+mod gc;
+mod gcstr;
+mod gcsync;
+mod gcvec;
+mod info;
+mod intern;
+mod keep_alive;
+mod leaks;
+mod metrics;
+mod pauses;
+mod profile;
+mod replay;
+mod retention;
 mod safepoints;
-use collector::Collector;
+mod scan;
+#[cfg(feature = "serde")]
+mod serialize;
+mod stress;
+#[cfg(all(test, feature = "simulation"))]
+mod tests;
+mod threads;
+mod tlab;
+mod tracer;
+mod unrooted;
+mod valgrind;
+#[cfg(feature = "verify")]
+mod verify;
+mod weak;
+mod workers;
+
+/// Entry points for the fuzz targets in `fuzz/`. These are only built when
+/// fuzzing and aren't part of the public API.
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    use std::path::Path;
+
+    /// Builds the safepoint table for the ELF file at `path`, returning the
+    /// number of safepoints, or zero if the stackmap parser rejected the file.
+    pub fn safepoint_table(path: &Path) -> usize {
+        crate::safepoints::try_gen_safepoint_table(path).map_or(0, |t| t.len())
+    }
+}
+
+/// Entry points for the benchmarks in `benches/`, which need the `bench`
+/// feature. Not part of the public API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    use crate::COLLECTOR;
+
+    /// The return addresses of the safepoints in the running executable.
+    pub fn safepoints() -> Vec<u64> {
+        COLLECTOR.with(|c| c.safepoint_addresses())
+    }
+
+    /// Looks each of `addrs` up in the safepoint table and returns how many
+    /// are safepoints.
+    pub fn count_safepoints(addrs: &[u64]) -> usize {
+        COLLECTOR.with(|c| c.count_safepoints(addrs))
+    }
+}
+
+use std::{
+    env,
+    ffi::CStr,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::raw::{c_int, c_void},
+    path::Path
+};
+
+use collector::{AbortOnPanic, AllocPolicy, GlobalCollector};
+pub use ephemeron::Ephemeron;
+pub use gc::Gc;
+pub use gcstr::GcStr;
+pub use gcsync::GcSync;
+pub use gcvec::GcVec;
+pub use info::{GcInfo, GcKind, GcReason, TypeCensus};
+pub use intern::Interner;
+pub use keep_alive::{keep_alive, KeepAlive};
+pub use pauses::PauseHistogram;
+pub use profile::AllocSite;
+pub use retention::{RetainedObject, RetainingPath, Root};
+pub use tracer::Tracer;
+pub use weak::{Phantom, RefQueue, Soft, Weak};
 
 // FIXME: This will be replaced with the `Scan` trait lang item in our forked
 // rustc's libcore. For now, we define `Scan` at the top level in this library.
 pub trait Scan {
-    fn scan(&self) {}
+    /// Reports every GC pointer directly reachable from `self` to `tracer`.
+    ///
+    /// This is called while the heap is being traced, with the world stopped,
+    /// and may be called on a collector thread. It must not allocate on the GC
+    /// heap or start a collection: either aborts the process.
+    fn scan(&self, _tracer: &mut Tracer) {}
+
+    /// Returns `false` if values of this type can never contain a GC pointer.
+    /// The collector does not call `scan()` on objects of such types at all.
+    #[inline]
+    fn needs_trace() -> bool
+    where
+        Self: Sized
+    {
+        true
+    }
 }
 
+/// A marker for types which can never contain a GC pointer, such as byte
+/// buffers or numeric matrices. The `Scan` impl of a `NoTrace` type should have
+/// an empty `scan()` and return `false` from `needs_trace()`;
+/// `#[derive(NoTrace)]` generates both impls.
+///
+/// # Safety
+///
+/// The collector relies on this trait to skip tracing: a `NoTrace` type which
+/// does hold a GC pointer will have the referent freed from underneath it.
+pub unsafe trait NoTrace {}
+
+/// `#[derive(Scan)]` generates a `scan()` which visits every field in turn.
+/// `#[derive(NoTrace)]` checks that every field is `NoTrace` and generates a
+/// `Scan` impl which is never called.
+#[cfg(feature = "derive")]
+pub use gcrt_derive::{NoTrace, Scan};
+
+#[derive(Debug)]
 pub enum GcErr {
+    /// The system allocator could not provide more memory for the heap.
     OOM(String),
+    /// The heap has no room for the object without collecting or growing. Only
+    /// returned by the `try_` allocation functions.
+    HeapFull,
+    /// An object of this many bytes is too large for any heap.
+    TooLarge(usize),
+    /// The heap doesn't exist yet, because `init()` hasn't been called.
+    NotInitialised,
+    /// `try_init()` was called after the runtime had been initialised.
+    AlreadyInitialised
 }
 
+impl fmt::Display for GcErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GcErr::OOM(msg) => write!(f, "GC heap exhausted: {}", msg),
+            GcErr::HeapFull => write!(f, "GC heap is full"),
+            GcErr::TooLarge(size) => {
+                write!(f, "a {} byte object is too large for the GC heap", size)
+            }
+            GcErr::NotInitialised => write!(f, "the GC runtime has not been initialised"),
+            GcErr::AlreadyInitialised => write!(f, "the GC runtime is already initialised")
+        }
+    }
+}
 
-thread_local!(static COLLECTOR: Collector =  Collector::new());
+static COLLECTOR: GlobalCollector = GlobalCollector::new();
 
 /// This must be called before the GC can be used (usually in the setup code
-/// before `main()`). Initialisation consists of two stages:
+/// before `main()`). Initialisation consists of three stages:
 ///     1. Read the stackmap section in the ELF file into an in-memory table for
 ///        fast lookup.
 ///     2. Allocate a chunk of heap memory to be used to store objects managed
 ///        by the GC.
+///     3. Register the calling thread as a mutator thread.
+///
+/// If the `RGCRT_HEAP_SIZE` environment variable is set, the heap is limited to
+/// that many bytes (see `set_max_heap_size()`). If `RGCRT_PAUSE_REPORT` is set
+/// (to anything other than `0`), a summary of collection pause times is printed
+/// to stderr when the process exits. If `RGCRT_ALLOC_PROFILE` is set to a
+/// number of bytes, allocation-site profiling is started with that sampling
+/// interval (see `start_alloc_profiling()`) and the profile is printed at exit.
+/// If `RGCRT_TRACE_EVENTS` is set to a path, GC activity is recorded as Chrome
+/// trace events (see `start_trace_events()`) and written there at exit. If
+/// `RGCRT_LEAK_REPORT` is set (to anything other than `0`), leak reporting is
+/// started (see `start_leak_report()`). If `RGCRT_CRASH_REPORT` is set (to
+/// anything other than `0`), the crash handler is installed (see
+/// `install_crash_handler()`). If `RGCRT_GC_RECORD` is set to a path, the
+/// collector's schedule is recorded there, and if `RGCRT_GC_REPLAY` is set to
+/// such a recording, the schedule is replayed (see the README). If
+/// `RGCRT_STRESS` is set (to anything other than `0`), every safepoint poll
+/// collects; if `RGCRT_STRESS_SEED` is set, polls collect at random instead.
+/// If `RGCRT_CHECK_ROOTS` is set, every object allocated by `alloc_raw()` must
+/// be rooted by the next safepoint (see `release_raw()`).
+///
+/// Until `init()` has returned, allocation fails with `GcErr::NotInitialised`.
+/// Panics if called more than once.
 pub fn init() {
-    unimplemented!();
+    if let Err(e) = try_init() {
+        panic!("{}", e);
+    }
+}
+
+/// Like `init()`, but returns `GcErr::AlreadyInitialised` instead of panicking
+/// if the runtime has already been initialised, leaving it as it was.
+pub fn try_init() -> Result<(), GcErr> {
+    COLLECTOR.begin_init()?;
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("gcrt init").entered();
+    log::install_from_env();
+    replay::install_from_env();
+    COLLECTOR.with(|c| {
+        if let Ok(size) = env::var("RGCRT_HEAP_SIZE") {
+            match size.parse() {
+                Ok(size) => c.set_max_heap_size(size),
+                Err(_) => panic!("RGCRT_HEAP_SIZE must be a number of bytes, not {}", size)
+            }
+        }
+        c.mk_root_table("/proc/self/exe");
+        c.mk_heap();
+    });
+    register_thread();
+    pauses::install_exit_report();
+    profile::install_from_env();
+    chrome_trace::install_from_env();
+    leaks::install_from_env();
+    crash::install_from_env();
+    stress::install_from_env();
+    unrooted::install_from_env();
+    #[cfg(feature = "verify")]
+    verify::install_from_env();
+    COLLECTOR.finish_init();
+    Ok(())
+}
+
+/// Registers the calling thread with the collector, so that its stack is
+/// scanned for roots and it is stopped at a safepoint during collections. Every
+/// thread which holds GC pointers must be registered. Threads spawned by the
+/// forked standard library are registered automatically; foreign threads which
+/// call into GC-using code must call this first.
+///
+/// Registering an already registered thread does nothing.
+pub fn register_thread() {
+    threads::register();
+}
+
+/// Removes the calling thread from the collector's thread registry. The thread
+/// must not hold any GC pointers afterwards. Threads are also unregistered
+/// automatically when they exit.
+pub fn unregister_thread() {
+    threads::unregister();
+}
+
+/// Marks the calling thread as running native code which does not touch the
+/// GC heap (e.g. a blocking FFI call or I/O), so that collections on other
+/// threads can proceed without waiting for this one to reach a safepoint.
+///
+/// `enter_native()` and the matching `exit_native()` must be called from the
+/// same frame as the native call itself, and no GC pointers may be used in
+/// between. Prefer `native()`, which enforces this.
+pub fn enter_native() {
+    threads::enter_native();
+}
+
+/// Returns the calling thread from native code after `enter_native()`. If a
+/// collection is in progress, this blocks until it has finished.
+pub fn exit_native() {
+    threads::exit_native();
+}
+
+/// Runs `f` with the calling thread marked as in native code (see
+/// `enter_native()`). `f` must not touch the GC heap.
+pub fn native<R, F: FnOnce() -> R>(f: F) -> R {
+    threads::blocking(f)
+}
+
+/// Returns `true` if the calling thread is registered with the collector.
+pub fn is_thread_registered() -> bool {
+    threads::is_registered()
+}
+
+/// Opens a shared library with `dlopen(3)` and adds the safepoints in its
+/// stackmaps to the safepoint table, which otherwise only describes the
+/// executable. Returns the handle, or null if `dlopen` failed. A library whose
+/// stackmaps can't be read is still loaded, but the error is logged. Library
+/// constructors must not hold GC pointers across a safepoint.
+///
+/// # Safety
+///
+/// As for `dlopen`: the library's constructors are run.
+pub unsafe fn dlopen(filename: &CStr, flags: c_int) -> *mut c_void {
+    dylib::dlopen(filename, flags)
+}
+
+/// Closes a library opened with `dlopen()`, with `dlclose(3)`, and removes its
+/// safepoints from the table if it is unloaded. Returns `dlclose`'s result.
+///
+/// # Safety
+///
+/// As for `dlclose`: nothing may use the library afterwards.
+pub unsafe fn dlclose(handle: *mut c_void) -> c_int {
+    dylib::dlclose(handle)
+}
+
+/// Adds the safepoints of a library which the caller opened with `dlopen(3)`
+/// itself to the safepoint table, and returns how many there were. Adding a
+/// library's safepoints again does nothing. The library's code must not have
+/// reached a safepoint before this is called.
+///
+/// # Safety
+///
+/// `handle` must have been returned by `dlopen`, and not yet closed.
+pub unsafe fn library_loaded(handle: *mut c_void) -> io::Result<usize> {
+    dylib::loaded(handle)
+}
+
+/// Removes the safepoints of every library passed to `library_loaded()` which
+/// has since been unloaded. Call this after closing such a library.
+pub fn forget_unloaded_libraries() {
+    dylib::forget_unloaded();
 }
 
 /// This function is the *only* way that a collection can be triggered. Calls to
@@ -59,23 +370,341 @@ pub fn init() {
 /// the mutator. The only thing that can be guaranteed is that a collection
 /// *might* have happened after returning from this call.
 ///
-/// ------------------------------ WARNING -------------------------------------
-/// | We need to be really careful about code which panics inside the collector|
-/// | here. The safepoint poll will *not* be called by native Rust code which  |
-/// | means that panic handling is UB. We should probably abort on panic here. |
-/// ----------------------------------------------------------------------------
+/// Polls are called from frames which only have stackmaps, not unwind tables,
+/// so a panic inside one (e.g. from a `Scan` impl) can't be unwound: the
+/// process is aborted instead.
 #[no_mangle]
 pub extern "C" fn safepoint_poll() {
-    if COLLECTOR.with(|c| c.should_collect()) {
-        COLLECTOR.with(|c| c.reclaim())
+    if !threads::poll_requested() {
+        return;
+    }
+    let _abort = AbortOnPanic("safepoint_poll");
+    // Another thread may be waiting for us to stop so that it can collect.
+    threads::park_if_stopping();
+    COLLECTOR.with(|c| {
+        if c.should_collect() {
+            c.reclaim(GcReason::Threshold)
+        }
+    });
+    if threads::take_finalise() {
+        collector::run_pending_destructors();
     }
 }
 
-/// Blocks the mutator to perform a collection. As this is a single threaded GC
-/// implementation, we can guarantee that this will take place immediately a
-/// safepoint will be inserted into the `force_collect` function prologue.
+/// Returns the address of the calling thread's poll flag, so that compiled code
+/// can test it inline at each poll site and only call `safepoint_poll` when it
+/// is non-zero:
+///
+/// ```text
+///     cmp qword ptr [flag], 0
+///     jne slow
+///   ...
+///   slow:
+///     call safepoint_poll
+/// ```
+///
+/// The flag is a machine word which the collector sets whenever the thread has
+/// to stop or collect. The address remains valid until the thread unregisters,
+/// and must be fetched again after `register_thread()`: an unregistered thread
+/// is given a flag shared by all unregistered threads.
+#[no_mangle]
+pub extern "C" fn gcrt_poll_flag() -> *const usize {
+    threads::poll_word() as *const usize
+}
+
+/// Blocks the mutator to perform a collection. Other registered threads are
+/// stopped at their next safepoint before the collection starts, and resumed
+/// once it has finished.
+///
+/// This is a no-op while the collector is disabled, and before `init()`.
+/// Calling it while the heap is being traced (e.g. from a `Scan` impl) aborts
+/// the process. Called from the destructor of a GC object, it requests a
+/// collection at the next safepoint poll instead.
 pub fn force_collect() {
-    COLLECTOR.with(|c| c.reclaim());
+    collector::forbid_reentry("force_collect() was called");
+    if !COLLECTOR.is_initialised() {
+        return;
+    }
+    COLLECTOR.with(|c| {
+        if !c.is_disabled() {
+            c.reclaim(GcReason::Forced)
+        }
+    });
+}
+
+/// Performs a collection and returns the number of live objects, and the bytes
+/// they occupy, for every type in the heap, largest first. This is much cheaper
+/// than a heap dump when all that is needed is to find out what is filling the
+/// heap. Unlike other collections, it waits for every registered thread to
+/// reach a safepoint, rather than suspending one which is slow to.
+///
+/// While the collector is disabled no collection can run, so an empty census
+/// is returned, as it is before `init()`.
+pub fn census() -> Vec<TypeCensus> {
+    COLLECTOR.with(|c| {
+        if c.is_disabled() || !COLLECTOR.is_initialised() {
+            Vec::new()
+        } else {
+            c.census()
+        }
+    })
+}
+
+/// Starts recording the allocation site of every object, and arranges for a
+/// leak report to be printed to stderr at exit. The report is produced after a
+/// final collection, and lists the objects passed to `expect_dead()` which are
+/// still reachable, with their types and allocation sites, and the number of
+/// objects of each type which were never freed.
+///
+/// Recording allocation sites makes allocation much slower, so this is meant
+/// for debugging.
+pub fn start_leak_report() {
+    leaks::start();
+}
+
+/// Declares that `obj` should become unreachable: if it is still reachable at
+/// exit, the leak report will say so. Does nothing unless leak reporting has
+/// been started.
+pub fn expect_dead<T: ?Sized>(obj: Gc<T>) {
+    leaks::expect_dead(Gc::as_ptr(&obj) as *const u8 as usize);
+}
+
+/// Explains why the object at `obj` is still alive, by returning a shortest
+/// chain of references to it from a root. Print the result to see the chain,
+/// with the function holding a stack root named where possible. Returns `None`
+/// if `obj` is not reachable, and so will be freed by the next collection.
+///
+/// `obj` is a raw pointer so that asking the question doesn't keep the object
+/// alive; if the caller still holds a `Gc` to it across this call, that will
+/// be the answer. As with `dump_heap()`, the search waits for every other
+/// thread to reach a safepoint.
+pub fn why_alive<T: ?Sized>(obj: *const T) -> Option<RetainingPath> {
+    COLLECTOR.with(|c| c.why_alive(obj as *const u8 as usize))
+}
+
+/// Installs a handler for fatal signals (`SIGSEGV`, `SIGBUS`, `SIGILL`, `SIGFPE`
+/// and `SIGABRT`) which prints the collector's state to stderr: the heap's
+/// chunks and bump pointers, whether a collection was in progress and in which
+/// phase, the state of each registered thread, and the return addresses of the
+/// safepoints at which threads were last stopped. The signal is then passed on
+/// to whichever handler was installed before.
+///
+/// Installing the handler more than once does nothing.
+pub fn install_crash_handler() {
+    crash::install();
+}
+
+/// Prevents the collector from running until a matching call to `enable()`.
+/// While disabled, safepoint polls become no-ops and allocation may grow the
+/// heap instead of triggering a collection. This is intended for short critical
+/// sections where the mutator holds raw GC pointers which the collector does
+/// not know about (e.g. across FFI calls).
+///
+/// Calls nest: the collector is only re-enabled once every `disable()` has been
+/// paired with an `enable()`.
+pub fn disable() {
+    COLLECTOR.with(|c| c.disable());
+}
+
+/// Re-enables collection after a call to `disable()`. Panics if the collector
+/// was not disabled.
+pub fn enable() {
+    COLLECTOR.with(|c| c.enable());
+}
+
+/// Returns `true` if the collector is currently disabled by one or more calls to
+/// `disable()`.
+pub fn is_disabled() -> bool {
+    COLLECTOR.with(|c| c.is_disabled())
+}
+
+/// Writes a heap dump to `path`, recording every object reachable from the
+/// roots along with its size, type and the GC pointers it holds. The format is
+/// described in the `dump` module, which can also read dumps back in. Other
+/// threads are stopped at their next safepoint, however long they take to
+/// reach one, while the heap is walked, but nothing is freed.
+pub fn dump_heap<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    COLLECTOR.with(|c| c.snapshot()).save(path)
+}
+
+/// Writes the graph of objects reachable from the roots to `path` in
+/// Graphviz's DOT format, with each object labelled by its type and size. If
+/// `max_objects` is given, only the objects nearest the roots, up to that
+/// many, are included. This is meant for looking at what keeps what alive in
+/// small programs; `dot -Tsvg` will struggle with much more than a few
+/// thousand objects.
+pub fn dump_object_graph<P: AsRef<Path>>(path: P, max_objects: Option<usize>) -> io::Result<()> {
+    let dump = COLLECTOR.with(|c| c.snapshot());
+    let mut w = BufWriter::new(File::create(path)?);
+    dump.write_dot(&mut w, max_objects)?;
+    w.flush()
+}
+
+/// Returns `true` if `addr` points to the start of a live object in the GC
+/// heap. This is intended for conservative scanning, debug assertions and
+/// sanity checks at FFI boundaries. It takes the collector lock, then finds the
+/// chunk containing `addr` by binary search and checks its object-start bitmap.
+pub fn is_gc_ptr<T: ?Sized>(addr: *const T) -> bool {
+    COLLECTOR.with(|c| c.is_gc_ptr(addr as *const u8 as usize))
+}
+
+/// Returns the number of bytes allocated in the GC heap since the last
+/// collection, including object headers and padding. Registered threads
+/// allocate small objects from thread-local buffers, which are counted in full
+/// when they are handed out. The count is reset to zero at the end of every
+/// collection.
+pub fn bytes_allocated_since_gc() -> usize {
+    COLLECTOR.with(|c| c.allocated_since_gc())
+}
+
+/// Returns the number of collections which have completed since the program
+/// started. This acts as a GC epoch: a value cached alongside the count is known
+/// to be unaffected by the collector for as long as the count is unchanged.
+pub fn collection_count() -> u64 {
+    COLLECTOR.with(|c| c.collections())
+}
+
+/// Returns a report on the most recent collection, or `None` if no collection
+/// has happened yet.
+pub fn last_gc_info() -> Option<GcInfo> {
+    COLLECTOR.with(|c| c.last_info())
+}
+
+/// Returns the distribution of the pause times of every collection so far.
+pub fn pause_histogram() -> PauseHistogram {
+    COLLECTOR.with(|c| c.pauses())
+}
+
+/// Returns an estimate of how fast the program is allocating in the GC heap, in
+/// bytes per second. The estimate is an exponentially weighted average over
+/// the intervals between collections, in which an interval's weight halves
+/// with every second that passes after it ends. The interval in progress is
+/// included. Time spent collecting is not counted.
+pub fn allocation_rate() -> f64 {
+    COLLECTOR.with(|c| c.alloc_rate())
+}
+
+/// Returns the collector's current metrics in the Prometheus text exposition
+/// format, ready to be appended to the response of an existing `/metrics`
+/// endpoint. They cover the heap's size and occupancy, the allocation rate,
+/// the number of collections and the distribution of pause times. Every
+/// metric name starts with `rgcrt_`.
+pub fn prometheus_metrics() -> String {
+    COLLECTOR.with(metrics::prometheus)
+}
+
+/// Starts sampling allocations to find out where the heap's memory is being
+/// allocated from. About one allocation per `sample_interval` bytes is
+/// sampled, recording the allocating call stack; smaller intervals are more
+/// accurate but slower. Samples accumulate across calls until the process
+/// exits.
+pub fn start_alloc_profiling(sample_interval: usize) {
+    profile::start(sample_interval);
+}
+
+/// Stops sampling allocations. The samples taken so far are kept.
+pub fn stop_alloc_profiling() {
+    profile::stop();
+}
+
+/// Returns the estimated bytes and allocation counts for every allocation site
+/// sampled so far, heaviest first.
+pub fn alloc_profile() -> Vec<AllocSite> {
+    profile::report()
+}
+
+/// Starts recording GC activity as Chrome trace events: a span for each
+/// collection and its phases, and a sample of the allocation rate at the start
+/// of each collection. Use `save_trace_events()` to write them out, and load
+/// the file into Perfetto or `chrome://tracing`.
+pub fn start_trace_events() {
+    chrome_trace::start();
+}
+
+/// Writes the trace events recorded so far to `path` in Chrome's JSON trace
+/// event format.
+pub fn save_trace_events<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    chrome_trace::save(path)
+}
+
+/// Sets the number of bytes which may be allocated between collections before
+/// the next safepoint poll triggers a collection. Lowering the threshold makes
+/// collections smaller and more frequent (e.g. during idle periods); raising it
+/// defers them (e.g. during latency-critical phases).
+///
+/// If more than `bytes` have already been allocated since the last collection,
+/// the next safepoint poll will collect.
+pub fn set_gc_trigger_threshold(bytes: usize) {
+    COLLECTOR.with(|c| c.set_trigger_threshold(bytes));
+}
+
+/// Returns the current collection trigger threshold in bytes.
+pub fn gc_trigger_threshold() -> usize {
+    COLLECTOR.with(|c| c.trigger_threshold())
+}
+
+/// Informs the collector that a GC object has acquired `bytes` of memory outside
+/// the GC heap (e.g. a malloc'd buffer or a file mapping) which will only be
+/// released when the object is collected. Reported bytes count towards the
+/// collection trigger threshold as if they had been allocated in the heap, so
+/// that a small heap retaining large external buffers is still collected.
+pub fn report_external_alloc(bytes: usize) {
+    COLLECTOR.with(|c| c.report_external_alloc(bytes));
+}
+
+/// Informs the collector that `bytes` of external memory previously reported
+/// with `report_external_alloc` have been released.
+pub fn report_external_free(bytes: usize) {
+    COLLECTOR.with(|c| c.report_external_free(bytes));
+}
+
+/// Limits the heap to `bytes`, counting every chunk in full. Once allocating
+/// would need the heap to grow past the limit, `Gc::new` panics, and the
+/// `try_` allocation functions return `GcErr::OOM`. A limit below the current
+/// size of the heap stops it growing any further but doesn't shrink it.
+///
+/// The heap is unlimited by default.
+pub fn set_max_heap_size(bytes: usize) {
+    COLLECTOR.with(|c| c.set_max_heap_size(bytes));
+}
+
+/// Returns the heap size limit set by `set_max_heap_size()`, or `usize::MAX` if
+/// there is none.
+pub fn max_heap_size() -> usize {
+    COLLECTOR.with(|c| c.max_heap_size())
+}
+
+/// Sets the fraction of the heap limit which the survivors of a collection
+/// must occupy for it to count as under memory pressure: after two such
+/// collections in a row, the next clears soft references (see `Soft`). The
+/// default is 0.9. Panics unless `fraction` is between 0 and 1.
+pub fn set_soft_occupancy(fraction: f64) {
+    assert!(
+        (0.0..=1.0).contains(&fraction),
+        "the soft occupancy must be between 0 and 1, not {}",
+        fraction
+    );
+    COLLECTOR.with(|c| c.set_soft_occupancy(fraction));
+}
+
+/// Returns the amount of external memory currently reported as held by GC
+/// objects.
+pub fn external_bytes() -> usize {
+    COLLECTOR.with(|c| c.external_bytes())
+}
+
+/// Must be called after a GC pointer is stored into an object in the GC heap,
+/// with a pointer to the object which was written to. The current collector is
+/// neither incremental nor generational, so the barrier does nothing, but GC
+/// containers call it on every store so that a future collector can rely on it.
+///
+/// With the `verify` feature, setting `RGCRT_VERIFY_BARRIERS` checks at every
+/// collection that no barrier was missed, and aborts naming the object whose
+/// store wasn't followed by one.
+#[inline(always)]
+pub fn write_barrier<T: ?Sized>(_obj: *const T) {
+    #[cfg(feature = "verify")]
+    verify::remember(_obj as *const u8 as usize);
 }
 
 /// Attempts to store an object in the GC heap and return a raw pointer on
@@ -90,6 +719,49 @@ pub fn force_collect() {
 ///     call; loop-backedge; new GC allocation) *UNLESS* it has been placed in
 ///     a container which implements the `Scan` trait, with a `scan()` method
 ///     to inform the collector that it is, indeed, a valid GC pointer.
+///
+/// If the heap has no room for `object`, a collection is performed (unless the
+/// collector is disabled), then the heap is grown. The errors returned are:
+///     - `GcErr::NotInitialised` if `init()` hasn't been called.
+///     - `GcErr::TooLarge` if `object` is too large for any heap.
+///     - `GcErr::OOM` if growing the heap would exceed its size limit, or the
+///     system allocator is out of memory.
+///
+/// `GcErr::HeapFull` is never returned, as only `try_alloc_raw` gives up
+/// without collecting or growing the heap.
+///
+/// With `RGCRT_CHECK_ROOTS` set, the next safepoint poll collects, and aborts
+/// the process if the returned object wasn't reached from the roots.
 pub fn alloc_raw<T: Scan>(object: T) -> Result<*mut T, GcErr> {
-    COLLECTOR.with(|c| c.alloc_obj(object))
+    let ptr = COLLECTOR.alloc_obj(object, AllocPolicy::Collect)?;
+    unrooted::record(ptr as usize);
+    Ok(ptr)
+}
+
+/// Declares that the object at `obj`, returned by `alloc_raw()` or
+/// `try_alloc_raw()`, has been deliberately dropped without being rooted, so
+/// that `RGCRT_CHECK_ROOTS` doesn't report it. Does nothing unless that check
+/// is on.
+pub fn release_raw<T: ?Sized>(obj: *const T) {
+    unrooted::release(obj as *const u8 as usize);
+}
+
+/// Like `alloc_raw`, but the object is never reclaimed: it is a root for the
+/// rest of the process, so whatever it refers to is traced at every
+/// collection. Intended for runtime singletons and interning tables, which
+/// would otherwise have to be registered with `Gc::into_raw`.
+pub fn alloc_uncollectable<T: Scan>(object: T) -> Result<*mut T, GcErr> {
+    let ptr = alloc_raw(object)?;
+    COLLECTOR.with(|c| c.add_raw_root(ptr as *mut u8));
+    Ok(ptr)
+}
+
+/// Like `alloc_raw`, but never collects or grows the heap: if no existing free
+/// space can hold `object`, `GcErr::HeapFull` is returned straight away. This is
+/// intended for performance-critical call sites which have a cheaper fallback
+/// than a collection.
+pub fn try_alloc_raw<T: Scan>(object: T) -> Result<*mut T, GcErr> {
+    let ptr = COLLECTOR.alloc_obj(object, AllocPolicy::NoCollect)?;
+    unrooted::record(ptr as usize);
+    Ok(ptr)
 }
@@ -0,0 +1,145 @@
+//! The runtime's diagnostic log, written to stderr.
+//!
+//! Logging is off unless the `RGCRT_LOG` environment variable names a level:
+//! `error`, `info`, `debug` or `trace`. Each level includes the ones before it.
+//! The variable is read once, by `init()` or the first time anything is logged,
+//! whichever comes first.
+//!
+//! Messages are formatted on the stack and written straight to file descriptor
+//! 2 rather than through `std::io::stderr()`: the collector logs while other
+//! threads are stopped, and one of them may have been suspended while holding
+//! the malloc or stderr lock.
+
+use std::{
+    env, fmt,
+    sync::atomic::{AtomicU8, Ordering}
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Error = 1,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE"
+        }
+    }
+}
+
+const OFF: u8 = 0;
+const UNSET: u8 = u8::MAX;
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(UNSET);
+
+fn max_level() -> u8 {
+    let level = MAX_LEVEL.load(Ordering::Relaxed);
+    if level != UNSET {
+        return level;
+    }
+    let level = match env::var("RGCRT_LOG").as_deref() {
+        Ok("error") => Level::Error as u8,
+        Ok("info") => Level::Info as u8,
+        Ok("debug") => Level::Debug as u8,
+        Ok("trace") => Level::Trace as u8,
+        _ => OFF
+    };
+    MAX_LEVEL.store(level, Ordering::Relaxed);
+    level
+}
+
+/// Reads `RGCRT_LOG`, unless it has been already, so that a message logged
+/// during a collection needn't.
+pub(crate) fn install_from_env() {
+    max_level();
+}
+
+/// Returns `true` if messages at `level` are logged. Callers which need to do
+/// extra work to produce a message should check this first.
+#[inline]
+pub(crate) fn enabled(level: Level) -> bool {
+    level as u8 <= max_level()
+}
+
+/// The size of the buffer messages are formatted into. Longer messages are
+/// written in pieces.
+const BUF_SIZE: usize = 512;
+
+/// A buffer for formatting a message, which is flushed to stderr when full.
+struct Line {
+    buf: [u8; BUF_SIZE],
+    len: usize
+}
+
+impl Line {
+    fn flush(&mut self) {
+        let mut buf = &self.buf[..self.len];
+        while !buf.is_empty() {
+            let n = unsafe { libc::write(2, buf.as_ptr() as *const libc::c_void, buf.len()) };
+            if n <= 0 {
+                break;
+            }
+            buf = &buf[n as usize..];
+        }
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut s = s.as_bytes();
+        while !s.is_empty() {
+            if self.len == BUF_SIZE {
+                self.flush();
+            }
+            let n = s.len().min(BUF_SIZE - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+            self.len += n;
+            s = &s[n..];
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write(level: Level, args: fmt::Arguments) {
+    let mut line = Line {
+        buf: [0; BUF_SIZE],
+        len: 0
+    };
+    let _ = fmt::Write::write_fmt(
+        &mut line,
+        format_args!("[rgcrt {}] {}\n", level.name(), args)
+    );
+    line.flush();
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => (log!($crate::log::Level::Error, $($arg)+));
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => (log!($crate::log::Level::Info, $($arg)+));
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => (log!($crate::log::Level::Debug, $($arg)+));
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => (log!($crate::log::Level::Trace, $($arg)+));
+}
@@ -0,0 +1,81 @@
+//! GC metrics in the Prometheus text exposition format, for embedders to serve
+//! from their own scrape endpoint.
+
+use std::fmt::Write;
+
+use crate::collector::Collector;
+
+/// Formats the collector's current metrics. Every metric is prefixed `rgcrt_`.
+pub(crate) fn prometheus(c: &Collector) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP rgcrt_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE rgcrt_{} {}", name, kind);
+        let _ = writeln!(out, "rgcrt_{} {}", name, value);
+    };
+    metric(
+        "heap_bytes",
+        "gauge",
+        "Bytes in the GC heap's chunks.",
+        c.heap_size() as f64
+    );
+    metric(
+        "live_bytes",
+        "gauge",
+        "Bytes occupied by objects which survived the last collection.",
+        c.last_info().map_or(0, |i| i.survivor_bytes) as f64
+    );
+    metric(
+        "allocated_since_gc_bytes",
+        "gauge",
+        "Bytes allocated since the last collection.",
+        c.allocated_since_gc() as f64
+    );
+    metric(
+        "external_bytes",
+        "gauge",
+        "Bytes held outside the GC heap by GC objects, as reported by the embedder.",
+        c.external_bytes() as f64
+    );
+    metric(
+        "allocation_rate_bytes_per_second",
+        "gauge",
+        "Exponentially weighted allocation rate.",
+        c.alloc_rate()
+    );
+    metric(
+        "collections_total",
+        "counter",
+        "Collections completed.",
+        c.collections() as f64
+    );
+
+    let pauses = c.pauses();
+    let _ = writeln!(out, "# HELP rgcrt_pause_seconds Collection pause times.");
+    let _ = writeln!(out, "# TYPE rgcrt_pause_seconds summary");
+    for &q in &[0.5, 0.95, 0.99] {
+        let _ = writeln!(
+            out,
+            "rgcrt_pause_seconds{{quantile=\"{}\"}} {}",
+            q,
+            pauses.percentile(q * 100.0).as_secs_f64()
+        );
+    }
+    let _ = writeln!(
+        out,
+        "rgcrt_pause_seconds_sum {}",
+        pauses.total().as_secs_f64()
+    );
+    let _ = writeln!(out, "rgcrt_pause_seconds_count {}", pauses.count());
+    let _ = writeln!(
+        out,
+        "# HELP rgcrt_pause_max_seconds The longest collection pause."
+    );
+    let _ = writeln!(out, "# TYPE rgcrt_pause_max_seconds gauge");
+    let _ = writeln!(
+        out,
+        "rgcrt_pause_max_seconds {}",
+        pauses.max().as_secs_f64()
+    );
+    out
+}
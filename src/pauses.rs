@@ -0,0 +1,128 @@
+//! A histogram of collection pause times.
+//!
+//! Pauses are counted in log-linear buckets: each power of two of nanoseconds
+//! is split into `SUB_BUCKETS` equal parts, so a percentile is accurate to
+//! within about 6% whatever the scale, in a fixed amount of memory.
+
+use std::{convert::TryFrom, env, fmt, time::Duration};
+
+use crate::COLLECTOR;
+
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// The distribution of pause times of every collection so far, as returned by
+/// `pause_histogram()`.
+#[derive(Clone)]
+pub struct PauseHistogram {
+    counts: Box<[u64; BUCKETS]>,
+    count: u64,
+    total: Duration,
+    max: Duration
+}
+
+impl PauseHistogram {
+    pub(crate) fn new() -> Self {
+        PauseHistogram {
+            counts: Box::new([0; BUCKETS]),
+            count: 0,
+            total: Duration::from_secs(0),
+            max: Duration::from_secs(0)
+        }
+    }
+
+    pub(crate) fn record(&mut self, pause: Duration) {
+        let nanos = u64::try_from(pause.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket(nanos)] += 1;
+        self.count += 1;
+        self.total += pause;
+        self.max = self.max.max(pause);
+    }
+
+    /// The number of pauses recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The sum of every pause.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// The longest pause. Unlike the percentiles, this is exact.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the pause time below which `p` percent of pauses fell (e.g.
+    /// `percentile(99.0)`), or zero if no pauses have been recorded. The result
+    /// is the upper bound of the bucket holding the percentile, capped at
+    /// `max()`.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_secs(0);
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil() as u64;
+        let rank = rank.max(1);
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_upper(i)).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for PauseHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} pauses totalling {:?}: p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.count,
+            self.total,
+            self.percentile(50.0),
+            self.percentile(95.0),
+            self.percentile(99.0),
+            self.max
+        )
+    }
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exp = 63 - nanos.leading_zeros();
+    let shift = exp - SUB_BITS;
+    let sub = (nanos >> shift) as usize - SUB_BUCKETS;
+    (shift as usize + 1) * SUB_BUCKETS + sub
+}
+
+/// The largest value which falls into bucket `i`.
+fn bucket_upper(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64;
+    }
+    let shift = (i / SUB_BUCKETS - 1) as u32;
+    let low = ((i % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+    low.saturating_add((1u64 << shift) - 1)
+}
+
+/// Arranges for the pause histogram to be printed to stderr when the process
+/// exits, if the `RGCRT_PAUSE_REPORT` environment variable is set to anything
+/// but `0`.
+pub(crate) fn install_exit_report() {
+    match env::var("RGCRT_PAUSE_REPORT") {
+        Ok(v) if v != "0" => (),
+        _ => return
+    }
+    unsafe { libc::atexit(exit_report) };
+}
+
+extern "C" fn exit_report() {
+    let pauses = COLLECTOR.with(|c| c.pauses());
+    eprintln!("rgcrt: {}", pauses);
+}
@@ -0,0 +1,184 @@
+//! Sampled allocation-site profiling.
+//!
+//! When profiling is on, roughly one allocation per `interval` bytes is
+//! sampled: the return addresses on the allocating thread's stack identify the
+//! allocation site, and each sample is counted as `interval` bytes (or the
+//! object's size, if larger) allocated there. This gives an unbiased estimate
+//! of the bytes allocated at each site at a small fraction of the cost of
+//! recording every allocation.
+//!
+//! Sites are found by walking the frame pointer chain, which is cheap. A full
+//! symbolised backtrace is only captured the first time a site is sampled.
+
+#[cfg(not(feature = "simulation"))]
+use std::arch::asm;
+use std::{
+    backtrace::Backtrace,
+    cell::Cell,
+    cmp::Reverse,
+    collections::HashMap,
+    env, fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+/// The maximum number of frames used to tell allocation sites apart.
+const MAX_FRAMES: usize = 32;
+
+/// Bytes between samples, or 0 if profiling is off.
+static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+static SITES: Mutex<Option<HashMap<Vec<usize>, Site>>> = Mutex::new(None);
+
+// Bytes which the calling thread may allocate before it takes its next sample.
+thread_local!(static UNTIL_SAMPLE: Cell<usize> = const { Cell::new(0) });
+
+struct Site {
+    bytes: u64,
+    count: u64,
+    backtrace: Backtrace
+}
+
+/// The estimated allocation volume at one allocation site, as returned by
+/// `alloc_profile()`.
+pub struct AllocSite {
+    /// The estimated number of bytes allocated at the site.
+    pub bytes: u64,
+    /// The estimated number of allocations made at the site.
+    pub count: u64,
+    /// A backtrace from the first sampled allocation at the site.
+    pub backtrace: String
+}
+
+fn sites() -> MutexGuard<'static, Option<HashMap<Vec<usize>, Site>>> {
+    SITES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn start(interval: usize) {
+    let mut sites = sites();
+    sites.get_or_insert_with(HashMap::new);
+    INTERVAL.store(interval.max(1), Ordering::Relaxed);
+}
+
+pub(crate) fn stop() {
+    INTERVAL.store(0, Ordering::Relaxed);
+}
+
+/// Called on every allocation of `size` bytes.
+#[inline(always)]
+pub(crate) fn record(size: usize) {
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    if interval != 0 {
+        maybe_sample(size, interval);
+    }
+}
+
+#[inline(never)]
+fn maybe_sample(size: usize, interval: usize) {
+    let sample = UNTIL_SAMPLE
+        .try_with(|u| {
+            let left = u.get();
+            if left > size {
+                u.set(left - size);
+                false
+            } else {
+                u.set(interval);
+                true
+            }
+        })
+        .unwrap_or(false);
+    if !sample {
+        return;
+    }
+
+    let key = return_addresses();
+    let weight = interval.max(size) as u64;
+    let mut sites = sites();
+    let sites = match sites.as_mut() {
+        Some(s) => s,
+        None => return
+    };
+    let site = sites.entry(key).or_insert_with(|| Site {
+        bytes: 0,
+        count: 0,
+        backtrace: Backtrace::force_capture()
+    });
+    site.bytes += weight;
+    site.count += (weight / size.max(1) as u64).max(1);
+}
+
+#[cfg(not(feature = "simulation"))]
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) fp) };
+    fp
+}
+
+/// Simulated builds may not keep frame pointers, so sites have no frames.
+#[cfg(feature = "simulation")]
+fn frame_pointer() -> usize {
+    0
+}
+
+pub(crate) fn return_addresses() -> Vec<usize> {
+    let mut fp = frame_pointer();
+    let mut addrs = Vec::with_capacity(MAX_FRAMES);
+    while fp != 0 && addrs.len() < MAX_FRAMES {
+        addrs.push(unsafe { *((fp + 8) as *const usize) });
+        let next = unsafe { *(fp as *const usize) };
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    addrs
+}
+
+/// Returns the allocation sites sampled so far, heaviest first.
+pub(crate) fn report() -> Vec<AllocSite> {
+    let sites = sites();
+    let mut report: Vec<AllocSite> = sites
+        .iter()
+        .flat_map(|s| s.values())
+        .map(|s| AllocSite {
+            bytes: s.bytes,
+            count: s.count,
+            backtrace: s.backtrace.to_string()
+        })
+        .collect();
+    report.sort_by_key(|s| Reverse(s.bytes));
+    report
+}
+
+impl fmt::Display for AllocSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} bytes in {} allocations at:", self.bytes, self.count)?;
+        for line in self.backtrace.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns profiling on if the `RGCRT_ALLOC_PROFILE` environment variable is set
+/// to a sampling interval in bytes, and arranges for the profile to be printed
+/// to stderr when the process exits.
+pub(crate) fn install_from_env() {
+    let interval = match env::var("RGCRT_ALLOC_PROFILE").map(|v| v.parse::<usize>()) {
+        Ok(Ok(i)) if i > 0 => i,
+        _ => return
+    };
+    start(interval);
+    unsafe { libc::atexit(exit_report) };
+}
+
+extern "C" fn exit_report() {
+    stop();
+    eprintln!("rgcrt: allocation profile");
+    for site in report() {
+        eprint!("{}", site);
+    }
+}
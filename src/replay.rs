@@ -0,0 +1,215 @@
+//! Recording the collector's schedule, and replaying it in a later run.
+//!
+//! With `RGCRT_GC_RECORD` set to a path, every decision the collector makes is
+//! written there as it happens, one event per line:
+//!
+//! ```text
+//! trigger <allocation>                        the allocation threshold was passed
+//! grow <allocation> <bytes>                   a chunk was added to the heap
+//! collect <n> <allocation> <reason>           collection n started
+//! outcome <n> <bytes reclaimed> <survivors>   collection n finished
+//! ```
+//!
+//! Allocations are numbered from 1 in the order they happen, which is the only
+//! clock a rerun of the same program can reproduce.
+//!
+//! With `RGCRT_GC_REPLAY` set to such a file, the allocation threshold is
+//! ignored: wherever the collector would check it, a collection is instead
+//! requested if a `trigger` was recorded at or before the current allocation.
+//! Collections started for other reasons (a full heap, or `force_collect()`)
+//! happen by themselves if the program is deterministic.
+//! The rest of the schedule is checked as the run goes, and the first event to
+//! differ is logged as an error, since from there on the runs have diverged.
+//! Allocation order is only reproducible across runs if one thread allocates
+//! at a time.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+use crate::GcReason;
+
+const OFF: u8 = 0;
+const RECORD: u8 = 1;
+const REPLAY: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(OFF);
+
+/// The number of allocations so far. Only counted while recording or
+/// replaying.
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+/// When replaying, the allocation at which the next recorded trigger happened.
+static NEXT_TRIGGER: AtomicU64 = AtomicU64::new(u64::MAX);
+
+static STATE: Mutex<State> = Mutex::new(State {
+    out: None,
+    triggers: Vec::new(),
+    expected: Vec::new(),
+    next: 0,
+    diverged: false
+});
+
+struct State {
+    /// Where events are written when recording.
+    out: Option<BufWriter<File>>,
+    /// The allocations at which triggers happened, latest first.
+    triggers: Vec<u64>,
+    /// Every other recorded event, in order, and the index of the next one the
+    /// replay should produce.
+    expected: Vec<String>,
+    next: usize,
+    diverged: bool
+}
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts recording or replaying if `RGCRT_GC_RECORD` or `RGCRT_GC_REPLAY` is
+/// set. Must be called before the heap is created, so that its first chunk is
+/// part of the schedule.
+pub(crate) fn install_from_env() {
+    if let Ok(path) = env::var("RGCRT_GC_REPLAY") {
+        let sched = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) => panic!("Can't read the GC schedule {}: {}", path, e)
+        };
+        let mut state = state();
+        for line in sched.lines() {
+            match line.strip_prefix("trigger ") {
+                Some(n) => match n.parse() {
+                    Ok(n) => state.triggers.push(n),
+                    Err(_) => panic!("Malformed GC schedule line: {}", line)
+                },
+                None => state.expected.push(line.to_owned())
+            }
+        }
+        state.triggers.reverse();
+        NEXT_TRIGGER.store(state.triggers.pop().unwrap_or(u64::MAX), Ordering::Relaxed);
+        MODE.store(REPLAY, Ordering::Relaxed);
+    } else if let Ok(path) = env::var("RGCRT_GC_RECORD") {
+        match File::create(&path) {
+            Ok(f) => state().out = Some(BufWriter::new(f)),
+            Err(e) => panic!("Can't create the GC schedule {}: {}", path, e)
+        }
+        unsafe { libc::atexit(flush) };
+        MODE.store(RECORD, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if collections are being replayed, in which case they are
+/// triggered by `trigger_due()` rather than the allocation threshold.
+#[inline]
+pub(crate) fn replaying() -> bool {
+    MODE.load(Ordering::Relaxed) == REPLAY
+}
+
+/// Counts an allocation.
+#[inline(always)]
+pub(crate) fn count_alloc() {
+    if active() {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// When replaying, returns `true` if the recording has a trigger at or before
+/// the current allocation which hasn't been replayed yet. Several triggers
+/// may have been recorded at the same allocation, if a collection reset the
+/// counters and the threshold was passed again straight away; they count as
+/// one.
+pub(crate) fn trigger_due() -> bool {
+    let n = allocs();
+    if n < NEXT_TRIGGER.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut state = state();
+    let next = loop {
+        match state.triggers.pop() {
+            Some(t) if t <= n => continue,
+            Some(t) => break t,
+            None => break u64::MAX
+        }
+    };
+    NEXT_TRIGGER.store(next, Ordering::Relaxed);
+    true
+}
+
+pub(crate) fn triggered() {
+    if MODE.load(Ordering::Relaxed) == RECORD {
+        event(format!("trigger {}", allocs()));
+    }
+}
+
+pub(crate) fn heap_grown(size: usize) {
+    if !active() {
+        return;
+    }
+    event(format!("grow {} {}", allocs(), size));
+}
+
+pub(crate) fn collection_started(n: u64, reason: GcReason) {
+    if !active() {
+        return;
+    }
+    event(format!("collect {} {} {:?}", n, allocs(), reason));
+}
+
+pub(crate) fn collection_finished(n: u64, bytes_reclaimed: usize, survivors: usize) {
+    if !active() {
+        return;
+    }
+    event(format!("outcome {} {} {}", n, bytes_reclaimed, survivors));
+    if let Some(out) = state().out.as_mut() {
+        // A run worth replaying may well be about to crash.
+        let _ = out.flush();
+    }
+}
+
+/// Returns `true` if collections are being recorded or replayed.
+#[inline]
+fn active() -> bool {
+    MODE.load(Ordering::Relaxed) != OFF
+}
+
+fn allocs() -> u64 {
+    ALLOCS.load(Ordering::Relaxed)
+}
+
+/// Records or checks `ev`. Callers check `active()` first, so that events are
+/// only formatted when they are needed.
+fn event(ev: String) {
+    let mode = MODE.load(Ordering::Relaxed);
+    let mut state = state();
+    if mode == RECORD {
+        if let Some(out) = state.out.as_mut() {
+            let _ = writeln!(out, "{}", ev);
+        }
+        return;
+    }
+    if state.diverged {
+        return;
+    }
+    let expected = state.expected.get(state.next).cloned();
+    state.next += 1;
+    if expected.as_deref() != Some(&ev) {
+        state.diverged = true;
+        error!(
+            "GC replay diverged: expected `{}`, got `{}`",
+            expected.as_deref().unwrap_or("end of schedule"),
+            ev
+        );
+    }
+}
+
+extern "C" fn flush() {
+    if let Some(out) = state().out.as_mut() {
+        let _ = out.flush();
+    }
+}
@@ -0,0 +1,168 @@
+//! Retaining paths, which explain why an object is still alive: a chain of
+//! references from a root to the object, as found by `why_alive()`.
+
+use std::{ffi::CStr, fmt, mem};
+
+/// Where a retaining path starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Root {
+    /// A stack slot recorded in the safepoint table. `ret` is the return
+    /// address of the call at which the frame holding the slot is suspended.
+    Stack {
+        thread: usize,
+        slot: usize,
+        ret: usize
+    },
+    /// A word on the stack of a thread which was suspended away from a
+    /// safepoint, which might be a GC pointer.
+    Conservative { thread: usize },
+    /// A register of a thread which was suspended away from a safepoint.
+    Register { thread: usize },
+    /// A pointer passed to `Gc::into_raw()`.
+    Raw
+}
+
+/// An object on a retaining path.
+#[derive(Clone, Copy, Debug)]
+pub struct RetainedObject {
+    pub addr: usize,
+    /// The name of the object's type, as given by `std::any::type_name`.
+    pub type_name: &'static str,
+    /// Bytes (including the header) occupied by the object.
+    pub size: usize
+}
+
+/// A chain of references from `root` to an object. `objects[0]` is the object
+/// `root` points to, each object refers to the next, and the last is the
+/// object asked about.
+///
+/// Threads are numbered in the order the collector stopped them, with the
+/// calling thread as thread 0. The `Display` impl symbolises return addresses
+/// using the dynamic symbol table, so they are only named in binaries linked
+/// with `-rdynamic`.
+#[derive(Clone, Debug)]
+pub struct RetainingPath {
+    pub root: Root,
+    pub objects: Vec<RetainedObject>
+}
+
+impl fmt::Display for Root {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Root::Stack { thread, slot, ret } => write!(
+                f,
+                "stack slot {:#x} in {} on thread {}",
+                slot,
+                symbolise(ret),
+                thread
+            ),
+            Root::Conservative { thread } => {
+                write!(
+                    f,
+                    "a conservatively scanned word on thread {}'s stack",
+                    thread
+                )
+            }
+            Root::Register { thread } => write!(f, "a register of thread {}", thread),
+            Root::Raw => f.write_str("a raw root (Gc::into_raw)")
+        }
+    }
+}
+
+impl fmt::Display for RetainingPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "retained by {}", self.root)?;
+        for o in &self.objects {
+            writeln!(f, "  -> {:#x} {} ({} bytes)", o.addr, o.type_name, o.size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Names the function containing `addr`, as `name+offset`, or returns `addr`
+/// in hex if it has no dynamic symbol.
+fn symbolise(addr: usize) -> String {
+    let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+    if unsafe { libc::dladdr(addr as *const libc::c_void, &mut info) } == 0
+        || info.dli_sname.is_null()
+    {
+        return format!("{:#x}", addr);
+    }
+    let name = unsafe { CStr::from_ptr(info.dli_sname) }.to_string_lossy();
+    format!("{}+{:#x}", demangle(&name), addr - info.dli_saddr as usize)
+}
+
+/// Demangles a symbol in Rust's legacy mangling scheme, dropping the trailing
+/// hash. Anything else is returned unchanged.
+fn demangle(sym: &str) -> String {
+    let mut rest = match sym.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return sym.to_owned()
+    };
+    let mut parts = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len = match rest[..digits].parse::<usize>() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return sym.to_owned()
+        };
+        parts.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    if let Some(last) = parts.last() {
+        if last.len() == 17 && last.starts_with('h') {
+            parts.pop();
+        }
+    }
+
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push_str("::");
+        }
+        // Identifiers which would start with an escape get a leading `_`.
+        let mut part = if part.starts_with("_$") {
+            &part[1..]
+        } else {
+            part
+        };
+        while !part.is_empty() {
+            if let Some(p) = part.strip_prefix("..") {
+                out.push_str("::");
+                part = p;
+            } else if part.starts_with('$') {
+                let end = match part[1..].find('$') {
+                    Some(end) => end + 2,
+                    None => break
+                };
+                match unescape(&part[1..end - 1]) {
+                    Some(c) => out.push(c),
+                    None => out.push_str(&part[..end])
+                }
+                part = &part[end..];
+            } else {
+                let c = part.chars().next().unwrap();
+                out.push(c);
+                part = &part[c.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+
+fn unescape(esc: &str) -> Option<char> {
+    Some(match esc {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => {
+            let hex = esc.strip_prefix('u')?;
+            char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+        }
+    })
+}
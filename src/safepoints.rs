@@ -1,5 +1,5 @@
 use std::{collections::HashMap, path::Path};
-use ykstackmaps::{LocKind, LocOffset, SMRec, StackMapParser};
+use ykstackmaps::{LocKind, LocOffset, Location, SMRec, StackMapParser};
 
 use core::mem;
 
@@ -8,48 +8,52 @@ static NUM_SKIP_STACKMAPS: usize = 2;
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct ReturnAddress(pub u64);
 
-/// The offset from the stack pointer.
-#[derive(Debug)]
-struct SPO(u32);
+/// The DWARF register numbers of the stack and frame pointers on x86_64.
+const DWARF_RSP: u16 = 7;
+const DWARF_RBP: u16 = 6;
 
-/// A `PtrSlot` identifies a stack root at a given safepoint using its offset
-/// from the Stack Pointer.
-///
-/// A base pointer (not to be confused with X86 terminology, where base pointer
-/// refers to the frame pointer register) is a pointer an object. In opposition
-/// to this, a derived pointer points to the interior of an object.
-///
-/// The Derived variant of a `PtrSlot` also contains a Stack Pointer offset to
-/// the base of the object.
-#[derive(Debug)]
-enum PtrSlot {
-    Base(SPO),
-    Derived(SPO, SPO)
+/// A stack slot which holds a GC pointer across a safepoint, as an offset from
+/// the caller's stack pointer or frame pointer at the call.
+#[derive(Debug, PartialEq)]
+enum StackSlot {
+    Sp(i32),
+    Fp(i32)
 }
 
 /// Contains root locations for a Safepoint.
 #[derive(Debug)]
 pub struct SafepointRoots {
-    /// A list of registers which contain roots across a safepoint
-    /// DWARF Register number mapping can be found here:
-    /// Pg.63 https://software.intel.com/sites/default/files/article/402129/mpx-linux64-abi.pdf
-    registers: Vec<u16>,
-
-    /// A list of `PtrSlot`s which correspond to roots accessible from a stack
-    /// pointer offset across a safepoint.
-    stack_offsets: Vec<PtrSlot>
+    /// The stack slots which hold pointers to GC objects across a safepoint.
+    slots: Vec<StackSlot>
+}
+
+impl SafepointRoots {
+    /// Returns the addresses of the stack slots which hold pointers to GC
+    /// objects, given the values of the stack and frame pointers at the
+    /// safepoint.
+    pub(crate) fn stack_slots(&self, sp: usize, fp: usize) -> impl Iterator<Item = usize> + '_ {
+        self.slots.iter().map(move |slot| match *slot {
+            StackSlot::Sp(o) => sp.wrapping_add_signed(o as isize),
+            StackSlot::Fp(o) => fp.wrapping_add_signed(o as isize)
+        })
+    }
 }
 
-/// Converts an offset to always be from the Stack Pointer.
-/// Pointer slots in stackmap locations are treated as offsets from a
-/// particular register. In the case of a Direct or Indirect location kind,
-/// these are the frame pointer and stack pointer registers respectively. To
-/// avoid calculating this during a GC pause, we convert all offsets to be
-/// from an SP upfront.
-fn as_sp_offset(offset: &LocOffset) -> SPO {
-    match offset {
-        LocOffset::I32(ref o) => SPO(*o as u32),
+/// Returns the stack slot an `Indirect` location refers to. Its offset is from
+/// the register the location names, which for the spill slots of a statepoint
+/// is the stack pointer or the frame pointer.
+fn stack_slot(loc: &Location) -> StackSlot {
+    let offset = match loc.offset {
+        LocOffset::I32(o) => o,
         _ => panic!("Offset must be signed")
+    };
+    match loc.dwarf_reg_num {
+        DWARF_RSP => StackSlot::Sp(offset),
+        DWARF_RBP => StackSlot::Fp(offset),
+        r => panic!(
+            "Stack slots must be relative to RSP or RBP, not DWARF register {}",
+            r
+        )
     }
 }
 
@@ -78,57 +82,218 @@ fn gen_safepoint_roots(stackmap: SMRec) -> SafepointRoots {
     // IR: a base pointer; and a derived pointer.
     //
     // We check that the number of remaining values is even.
-    debug_assert!((stackmap.locs.len() - idx) % 2 == 0);
-    let mut offsets = Vec::new();
+    debug_assert!((stackmap.locs.len() - idx).is_multiple_of(2));
+    let mut slots = Vec::new();
     let mut gc_ptrs = stackmap.locs.iter().skip(idx);
 
     while let Some(base) = gc_ptrs.next() {
+        // A base pointer (not to be confused with X86 terminology, where base
+        // pointer refers to the frame pointer register) points to an object,
+        // and a derived pointer to its interior. Objects never move, so
+        // keeping the base alive is all that is needed of either.
         let derived = gc_ptrs.next().unwrap();
         match base.kind {
             LocKind::Register => {
-                eprintln!("UNIMPLEMENTED: Skipping Registers for now");
+                debug!("unimplemented: skipping a GC pointer held in a register");
             }
             LocKind::Indirect => match derived.kind {
-                LocKind::Indirect => {
-                    if base.offset == derived.offset {
-                        offsets.push(PtrSlot::Base(as_sp_offset(&base.offset)))
-                    } else {
-                        offsets.push(PtrSlot::Derived(
-                            as_sp_offset(&base.offset),
-                            as_sp_offset(&derived.offset)
-                        ))
-                    }
-                }
+                LocKind::Indirect => slots.push(stack_slot(base)),
                 _ => unimplemented!()
             },
-            _ => eprintln!("UNIMPLEMENTED: Skipping over value")
+            _ => debug!("unimplemented: skipping a GC pointer location of unsupported kind")
         }
     }
 
-    SafepointRoots {
-        registers: Vec::new(),
-        stack_offsets: offsets
-    }
+    SafepointRoots { slots }
 }
 
 /// Generates a safepoint table which can be used during GC to lookup
 /// information about where pointers reside in a program.
 ///
 /// This function will parse the .llvm_stackmap section of the given ELF file
-/// and generate an efficient hashmap -- keyed by a function's return address --
-/// which can be queried by the collector.
+/// and generate an efficient hashmap -- keyed by the return address of each
+/// safepoint -- which can be queried by the collector.
 pub fn gen_safepoint_table<P: AsRef<Path>>(path: P) -> HashMap<ReturnAddress, SafepointRoots> {
-    let parser = StackMapParser::new(path.as_ref()).unwrap();
+    try_gen_safepoint_table(path.as_ref()).unwrap()
+}
+
+/// Like `gen_safepoint_table`, but returns an error if the stackmap section
+/// can't be parsed instead of panicking. Records which parse but don't have
+/// the shape LLVM's statepoints give them still panic.
+pub(crate) fn try_gen_safepoint_table(
+    path: &Path
+) -> Result<HashMap<ReturnAddress, SafepointRoots>, String> {
+    let parser = StackMapParser::new(path).map_err(|e| format!("{:?}", e))?;
 
     let mut frames = HashMap::new();
-    let ref mut stackmaps = parser.iter_stackmaps();
+    let mut stackmaps = parser.iter_stackmaps();
 
     // Read functions
     for func in parser.iter_functions() {
-        let func = func.unwrap();
-        for sm in stackmaps.take(func.record_count() as usize) {
-            frames.insert(ReturnAddress(func.addr()), gen_safepoint_roots(sm.unwrap()));
+        let func = func.map_err(|e| format!("{:?}", e))?;
+        let records = stackmaps
+            .by_ref()
+            .take(func.record_count() as usize)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{:?}", e))?;
+        add_function(&mut frames, func.addr(), records);
+    }
+    Ok(frames)
+}
+
+/// Adds the roots at each of the safepoints in the function at `addr` to
+/// `table`. Each record gives the offset of its safepoint's return address
+/// from the start of the function.
+fn add_function(
+    table: &mut HashMap<ReturnAddress, SafepointRoots>,
+    addr: u64,
+    records: Vec<SMRec>
+) {
+    for sm in records {
+        let ret = ReturnAddress(addr.wrapping_add(sm.offset as u64));
+        table.insert(ret, gen_safepoint_roots(sm));
+    }
+}
+
+/// Like `try_gen_safepoint_table`, for a shared library loaded at `base`: the
+/// addresses in its stackmaps are relative to where it is loaded.
+pub(crate) fn gen_library_safepoint_table(
+    path: &Path,
+    base: u64
+) -> Result<HashMap<ReturnAddress, SafepointRoots>, String> {
+    Ok(try_gen_safepoint_table(path)?
+        .into_iter()
+        .map(|(ReturnAddress(addr), roots)| (ReturnAddress(base.wrapping_add(addr)), roots))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(kind: LocKind, offset: LocOffset) -> Location {
+        Location {
+            kind,
+            size: 8,
+            dwarf_reg_num: DWARF_RSP,
+            offset
         }
     }
-    frames
+
+    /// A spill slot at `offset` from the stack pointer.
+    fn ind(offset: i32) -> Location {
+        loc(LocKind::Indirect, LocOffset::I32(offset))
+    }
+
+    /// Builds a statepoint record: the two leading constants, `deopts` de-opt
+    /// parameters, and then `ptrs` as (base, derived) pairs.
+    fn record(deopts: u32, ptrs: Vec<(Location, Location)>) -> SMRec {
+        let mut locs = vec![
+            loc(LocKind::Constant, LocOffset::U32(0)),
+            loc(LocKind::Constant, LocOffset::U32(0)),
+            loc(LocKind::Constant, LocOffset::U32(deopts)),
+        ];
+        for i in 0..deopts {
+            locs.push(loc(LocKind::Constant, LocOffset::U32(i)));
+        }
+        for (base, derived) in ptrs {
+            locs.push(base);
+            locs.push(derived);
+        }
+        SMRec {
+            id: 0,
+            offset: 0,
+            locs,
+            live_outs: Vec::new()
+        }
+    }
+
+    fn slots(roots: &SafepointRoots) -> Vec<usize> {
+        roots.stack_slots(0x1000, 0x2000).collect()
+    }
+
+    #[test]
+    fn no_gc_pointers() {
+        let roots = gen_safepoint_roots(record(0, vec![]));
+        assert!(roots.slots.is_empty());
+    }
+
+    #[test]
+    fn base_pointers() {
+        let roots = gen_safepoint_roots(record(0, vec![(ind(8), ind(8)), (ind(24), ind(24))]));
+        assert_eq!(roots.slots, [StackSlot::Sp(8), StackSlot::Sp(24)]);
+        assert_eq!(slots(&roots), [0x1008, 0x1018]);
+    }
+
+    #[test]
+    fn deopt_parameters_are_skipped() {
+        let roots = gen_safepoint_roots(record(3, vec![(ind(16), ind(16))]));
+        assert_eq!(slots(&roots), [0x1010]);
+    }
+
+    #[test]
+    fn derived_pointers_report_their_base() {
+        let roots = gen_safepoint_roots(record(0, vec![(ind(8), ind(32))]));
+        assert_eq!(roots.slots, [StackSlot::Sp(8)]);
+        assert_eq!(slots(&roots), [0x1008]);
+    }
+
+    #[test]
+    fn frame_pointer_relative_slots() {
+        let fp_ind = |offset| Location {
+            dwarf_reg_num: DWARF_RBP,
+            ..ind(offset)
+        };
+        let roots = gen_safepoint_roots(record(
+            0,
+            vec![(fp_ind(-16), fp_ind(-16)), (ind(-8), ind(-8))]
+        ));
+        assert_eq!(roots.slots, [StackSlot::Fp(-16), StackSlot::Sp(-8)]);
+        assert_eq!(slots(&roots), [0x1ff0, 0xff8]);
+    }
+
+    #[test]
+    fn unsupported_locations_are_skipped() {
+        let reg = || loc(LocKind::Register, LocOffset::I32(0));
+        let direct = || loc(LocKind::Direct, LocOffset::I32(8));
+        let roots = gen_safepoint_roots(record(
+            1,
+            vec![(reg(), reg()), (direct(), direct()), (ind(40), ind(40))]
+        ));
+        assert_eq!(slots(&roots), [0x1028]);
+    }
+
+    #[test]
+    fn records_are_keyed_by_return_address() {
+        let first = SMRec {
+            offset: 0x10,
+            ..record(0, vec![(ind(8), ind(8))])
+        };
+        let second = SMRec {
+            offset: 0x30,
+            ..record(0, vec![(ind(24), ind(24))])
+        };
+        let mut table = HashMap::new();
+        add_function(&mut table, 0x4000, vec![first, second]);
+        assert_eq!(table.len(), 2);
+        assert_eq!(slots(&table[&ReturnAddress(0x4010)]), [0x1008]);
+        assert_eq!(slots(&table[&ReturnAddress(0x4030)]), [0x1018]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Offset must be signed")]
+    fn unsigned_stack_offsets_are_rejected() {
+        let unsigned = || loc(LocKind::Indirect, LocOffset::U32(8));
+        gen_safepoint_roots(record(0, vec![(unsigned(), unsigned())]));
+    }
+
+    #[test]
+    #[should_panic(expected = "not DWARF register 3")]
+    fn slots_relative_to_other_registers_are_rejected() {
+        let rbx = || Location {
+            dwarf_reg_num: 3,
+            ..ind(8)
+        };
+        gen_safepoint_roots(record(0, vec![(rbx(), rbx())]));
+    }
 }
@@ -0,0 +1,214 @@
+//! `Scan` implementations for primitive and standard library types.
+//!
+//! Types which can never contain a GC pointer get an empty `scan()` and are
+//! marked `NoTrace`. Containers forward `scan()` to each of the values they
+//! own, so that a user type built from std containers only needs to scan its
+//! own fields. A container of `NoTrace` values is itself `NoTrace`.
+//!
+//! `Rc` and `Arc` are scanned through to the value they share, so a GC object
+//! may hold one. What keeps that value's GC pointers alive is the GC object
+//! being reachable, not the reference count: a clone held outside the GC heap
+//! (in a `static`, a `Box`, or on the stack of code compiled without
+//! statepoints) is invisible to the collector. If such a clone may outlive
+//! every GC object holding the same `Rc`, its GC pointers must be rooted some
+//! other way, e.g. with `Gc::into_raw`. An `Rc` in a reclaimed object is
+//! released when the object's destructor runs.
+
+use std::{rc::Rc, sync::Arc};
+
+use crate::{NoTrace, Scan, Tracer};
+
+macro_rules! scan_leaf {
+    ($($t:ty),*) => {
+        $(impl Scan for $t {
+            #[inline]
+            fn scan(&self, _: &mut Tracer) {}
+
+            #[inline]
+            fn needs_trace() -> bool {
+                false
+            }
+        }
+
+        unsafe impl NoTrace for $t {})*
+    };
+}
+
+scan_leaf!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    String
+);
+
+impl Scan for str {}
+unsafe impl NoTrace for str {}
+
+impl<T: Scan> Scan for Option<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        if let Some(v) = self {
+            v.scan(tracer);
+        }
+    }
+
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+}
+
+unsafe impl<T: NoTrace> NoTrace for Option<T> {}
+
+/// Smart pointers are scanned through to what they point to. Sized and unsized
+/// pointees have separate impls, as only a sized one's `needs_trace()` can be
+/// forwarded to. A pointer to a trait object other than `dyn Scan` needs an impl
+/// of its own, e.g. `impl Scan for Box<dyn MyTrait>`.
+///
+/// Other threads are stopped while the collector scans, so the value shared by
+/// an `Arc` can't change underneath it.
+macro_rules! scan_through {
+    ($($p:ident),*) => {
+        $(impl<T: Scan> Scan for $p<T> {
+            fn scan(&self, tracer: &mut Tracer) {
+                (**self).scan(tracer);
+            }
+
+            #[inline]
+            fn needs_trace() -> bool {
+                T::needs_trace()
+            }
+        }
+
+        unsafe impl<T: NoTrace> NoTrace for $p<T> {}
+
+        impl<T: Scan> Scan for $p<[T]> {
+            fn scan(&self, tracer: &mut Tracer) {
+                (**self).scan(tracer);
+            }
+
+            #[inline]
+            fn needs_trace() -> bool {
+                T::needs_trace()
+            }
+        }
+
+        unsafe impl<T: NoTrace> NoTrace for $p<[T]> {}
+
+        impl Scan for $p<str> {
+            #[inline]
+            fn scan(&self, _: &mut Tracer) {}
+
+            #[inline]
+            fn needs_trace() -> bool {
+                false
+            }
+        }
+
+        unsafe impl NoTrace for $p<str> {}
+
+        impl Scan for $p<dyn Scan> {
+            fn scan(&self, tracer: &mut Tracer) {
+                (**self).scan(tracer);
+            }
+        }
+
+        impl Scan for $p<dyn Scan + Send> {
+            fn scan(&self, tracer: &mut Tracer) {
+                (**self).scan(tracer);
+            }
+        }
+
+        impl Scan for $p<dyn Scan + Send + Sync> {
+            fn scan(&self, tracer: &mut Tracer) {
+                (**self).scan(tracer);
+            }
+        })*
+    };
+}
+
+scan_through!(Box, Rc, Arc);
+
+impl<T: Scan> Scan for [T] {
+    fn scan(&self, tracer: &mut Tracer) {
+        if !T::needs_trace() {
+            return;
+        }
+        for v in self {
+            v.scan(tracer);
+        }
+    }
+}
+
+unsafe impl<T: NoTrace> NoTrace for [T] {}
+
+impl<T: Scan, const N: usize> Scan for [T; N] {
+    fn scan(&self, tracer: &mut Tracer) {
+        self[..].scan(tracer);
+    }
+
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+}
+
+unsafe impl<T: NoTrace, const N: usize> NoTrace for [T; N] {}
+
+impl<T: Scan> Scan for Vec<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        self[..].scan(tracer);
+    }
+
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+}
+
+unsafe impl<T: NoTrace> NoTrace for Vec<T> {}
+
+macro_rules! scan_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Scan),+> Scan for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn scan(&self, tracer: &mut Tracer) {
+                let ($(ref $name,)+) = *self;
+                $($name.scan(tracer);)+
+            }
+
+            #[inline]
+            fn needs_trace() -> bool {
+                $($name::needs_trace())||+
+            }
+        }
+
+        unsafe impl<$($name: NoTrace),+> NoTrace for ($($name,)+) {}
+    };
+}
+
+scan_tuple!(A);
+scan_tuple!(A B);
+scan_tuple!(A B C);
+scan_tuple!(A B C D);
+scan_tuple!(A B C D E);
+scan_tuple!(A B C D E F);
+scan_tuple!(A B C D E F G);
+scan_tuple!(A B C D E F G H);
+scan_tuple!(A B C D E F G H I);
+scan_tuple!(A B C D E F G H I J);
+scan_tuple!(A B C D E F G H I J K);
+scan_tuple!(A B C D E F G H I J K L);
@@ -0,0 +1,91 @@
+//! `serde` support for the GC heap's types, behind the `serde` feature.
+//!
+//! A `Gc<T>` is serialised as the `T` it points to, and deserialised by moving
+//! a new `T` into the GC heap. Object identity isn't preserved: an object
+//! reached through several `Gc`s is written once for each, and read back as
+//! that many separate objects. A cycle of `Gc`s recurses until the stack
+//! overflows.
+
+use std::{fmt, marker::PhantomData};
+
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer
+};
+
+use crate::{Gc, GcStr, GcVec, Scan};
+
+/// The most elements a `GcVec` reserves room for before any have been read,
+/// whatever the input claims its length is.
+const MAX_PREALLOC: usize = 4096;
+
+impl<T: Serialize + ?Sized> Serialize for Gc<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de> + Scan> Deserialize<'de> for Gc<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Gc::new)
+    }
+}
+
+impl Serialize for GcStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for GcStr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StrVisitor;
+
+        impl<'de> Visitor<'de> for StrVisitor {
+            type Value = GcStr;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<GcStr, E> {
+                Ok(GcStr::new(s))
+            }
+        }
+
+        deserializer.deserialize_str(StrVisitor)
+    }
+}
+
+impl<T: Scan + Serialize> Serialize for GcVec<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// Elements are pushed straight into the `GcVec` as they are read, rather than
+/// collected in a `Vec` first, whose buffer the collector wouldn't scan.
+impl<'de, T: Scan + Deserialize<'de>> Deserialize<'de> for GcVec<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SeqVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Scan + Deserialize<'de>> Visitor<'de> for SeqVisitor<T> {
+            type Value = GcVec<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a sequence")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<GcVec<T>, A::Error> {
+                let cap = seq.size_hint().unwrap_or(0).min(MAX_PREALLOC);
+                let mut v = GcVec::with_capacity(cap);
+                while let Some(elem) = seq.next_element()? {
+                    v.push(elem);
+                }
+                Ok(v)
+            }
+        }
+
+        deserializer.deserialize_seq(SeqVisitor(PhantomData))
+    }
+}
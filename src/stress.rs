@@ -0,0 +1,101 @@
+//! Stress testing modes, which collect far more often than the heap needs, so
+//! that a missing root or an object freed too early shows up straight away
+//! rather than once in a blue moon.
+//!
+//! With `RGCRT_STRESS` set, every safepoint poll collects.
+//!
+//! With `RGCRT_STRESS_SEED` set to a number, collections are instead triggered
+//! at random: each poll collects, and each allocation requests a collection at
+//! the next poll, with probability `RGCRT_STRESS_PROBABILITY` (by default
+//! 0.01). The random numbers come from a generator seeded with the given seed,
+//! so a single-threaded program which fails with one seed fails in the same
+//! place every time it is rerun with it, and CI can explore many schedules by
+//! trying many seeds.
+
+use std::{
+    env,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering}
+};
+
+use crate::threads;
+
+const DEFAULT_PROBABILITY: f64 = 0.01;
+
+static STRESS: AtomicBool = AtomicBool::new(false);
+
+static RANDOM: AtomicBool = AtomicBool::new(false);
+/// The state of the SplitMix64 generator.
+static RNG: AtomicU64 = AtomicU64::new(0);
+/// A random number below this is a hit.
+static CUTOFF: AtomicU64 = AtomicU64::new(0);
+/// Set when an allocation has requested a collection at the next poll.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Turns on a stress mode if `RGCRT_STRESS` or `RGCRT_STRESS_SEED` is set.
+pub(crate) fn install_from_env() {
+    match env::var("RGCRT_STRESS") {
+        Ok(v) if v != "0" => STRESS.store(true, Ordering::Relaxed),
+        _ => ()
+    }
+    if let Ok(seed) = env::var("RGCRT_STRESS_SEED") {
+        let seed = match seed.parse::<u64>() {
+            Ok(s) => s,
+            Err(_) => panic!("RGCRT_STRESS_SEED must be a number, not {}", seed)
+        };
+        let p = match env::var("RGCRT_STRESS_PROBABILITY") {
+            Ok(p) => match p.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => p,
+                _ => panic!(
+                    "RGCRT_STRESS_PROBABILITY must be between 0 and 1, not {}",
+                    p
+                )
+            },
+            Err(_) => DEFAULT_PROBABILITY
+        };
+        info!(
+            "random collections with seed {} and probability {}",
+            seed, p
+        );
+        RNG.store(seed, Ordering::Relaxed);
+        // `as` saturates, so a probability of 1 always hits bar one value in
+        // 2^64.
+        CUTOFF.store((p * u64::MAX as f64) as u64, Ordering::Relaxed);
+        RANDOM.store(true, Ordering::Relaxed);
+    }
+    if polls_always_check() {
+        threads::request_collect();
+    }
+}
+
+/// Returns `true` if every safepoint poll must ask the collector whether to
+/// collect. The collect flag in the poll words is then never cleared, so that
+/// every poll takes its slow path.
+#[inline]
+pub(crate) fn polls_always_check() -> bool {
+    STRESS.load(Ordering::Relaxed) || RANDOM.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if a stress mode wants a collection at this poll.
+#[inline]
+pub(crate) fn collect_now() -> bool {
+    STRESS.load(Ordering::Relaxed)
+        || (RANDOM.load(Ordering::Relaxed) && (PENDING.swap(false, Ordering::Relaxed) || hit()))
+}
+
+/// Called on each allocation.
+#[inline(always)]
+pub(crate) fn on_alloc() {
+    if RANDOM.load(Ordering::Relaxed) && hit() {
+        PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+fn hit() -> bool {
+    let mut z = RNG
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    z < CUTOFF.load(Ordering::Relaxed)
+}
@@ -0,0 +1,111 @@
+//! Tests of the collector as a whole, which run with `--features simulation`:
+//! roots then come only from `Gc::into_raw()` and the other raw roots, so
+//! anything a test wants kept must be registered there. Every test shares the
+//! one global heap, so they take turns through `run()`.
+
+use std::sync::{Mutex, Once};
+
+use crate::{force_collect, is_gc_ptr, last_gc_info, try_init, Gc, GcErr, Scan, Tracer};
+
+static LOCK: Mutex<()> = Mutex::new(());
+static INIT: Once = Once::new();
+
+/// Unregisters the calling thread when dropped, even if the test panicked. A
+/// registered thread which never reaches a safepoint would hang the next
+/// test's first collection.
+struct Registered;
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        crate::unregister_thread();
+    }
+}
+
+/// Runs `f` on a thread registered with the runtime, once no other test is
+/// running.
+pub(crate) fn run<R>(f: impl FnOnce() -> R) -> R {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    INIT.call_once(crate::init);
+    crate::register_thread();
+    let _registered = Registered;
+    f()
+}
+
+/// A node of a singly linked list.
+pub(crate) struct Node {
+    pub(crate) next: Option<Gc<Node>>,
+    pub(crate) value: usize
+}
+
+impl Scan for Node {
+    fn scan(&self, tracer: &mut Tracer) {
+        self.next.scan(tracer);
+    }
+}
+
+/// Builds a list of `len` nodes, holding `len - 1` down to 0, and returns its
+/// head as a raw root. The collector is disabled while the list is only
+/// reachable from the stack.
+pub(crate) fn rooted_list(len: usize) -> *const Node {
+    crate::disable();
+    let mut head = None;
+    for value in 0..len {
+        head = Some(Gc::new(Node { next: head, value }));
+    }
+    let root = Gc::into_raw(head.unwrap());
+    crate::enable();
+    root
+}
+
+/// Returns the values in the list starting at `head`, which must be live.
+pub(crate) fn values(head: *const Node) -> Vec<usize> {
+    let mut values = Vec::new();
+    let mut node = Some(head);
+    while let Some(n) = node {
+        let n = unsafe { &*n };
+        values.push(n.value);
+        node = n.next.as_ref().map(Gc::as_ptr);
+    }
+    values
+}
+
+#[test]
+fn init_twice() {
+    run(|| assert!(matches!(try_init(), Err(GcErr::AlreadyInitialised))));
+}
+
+#[test]
+fn unrooted_objects_are_freed() {
+    run(|| {
+        let root = Gc::into_raw(Gc::new(Node {
+            next: None,
+            value: 1
+        }));
+        let garbage = Gc::as_ptr(&Gc::new(Node {
+            next: None,
+            value: 2
+        }));
+        assert!(is_gc_ptr(garbage));
+        force_collect();
+        assert!(is_gc_ptr(root));
+        assert!(!is_gc_ptr(garbage));
+        assert!(last_gc_info().unwrap().bytes_reclaimed > 0);
+
+        unsafe { Gc::from_raw(root) };
+        force_collect();
+        assert!(!is_gc_ptr(root));
+    });
+}
+
+#[test]
+fn rooted_list_survives_collections() {
+    run(|| {
+        let head = rooted_list(1000);
+        for i in 0..10_000usize {
+            Gc::new([i; 4]);
+        }
+        force_collect();
+        assert_eq!(values(head), (0..1000).rev().collect::<Vec<_>>());
+        unsafe { Gc::from_raw(head) };
+    });
+}
@@ -0,0 +1,611 @@
+//! The registry of mutator threads, and the handshake used to stop them all at
+//! safepoints for a collection.
+//!
+//! Every thread which can hold GC pointers must be registered, so that the
+//! collector knows which stacks to scan and which threads to wait for. A
+//! registered thread is always in one of three states:
+//!
+//!   * `RUNNING`: executing mutator code. The collector must wait for it to
+//!     reach a safepoint before it can start.
+//!   * `PARKED`: stopped at a safepoint (or blocked inside the runtime, or in
+//!     native code between `enter_native` and `exit_native`), with the stack
+//!     top at which it stopped recorded in `stop_top`. Its stack can be scanned
+//!     from there and it will not touch the heap until the collection finishes.
+//!   * `SUSPENDED`: stopped by a signal at an arbitrary instruction, because it
+//!     failed to reach a safepoint within `SUSPEND_TIMEOUT` (e.g. it is blocked
+//!     in a syscall, or spinning in code without polls). The innermost frames
+//!     are not at a safepoint, so the stackmap table says nothing about them:
+//!     the signal handler records the thread's registers, and the collector
+//!     scans them and the stack up to the first frame which *is* at a
+//!     safepoint conservatively.
+
+#[cfg(not(any(feature = "simulation", feature = "libunwind")))]
+use std::arch::asm;
+use std::{
+    cell::{Cell, RefCell, UnsafeCell},
+    fmt, ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard
+    },
+    thread,
+    time::{Duration, Instant}
+};
+#[cfg(not(feature = "simulation"))]
+use std::{mem, sync::Once};
+
+#[cfg(feature = "libunwind")]
+use crate::frames;
+
+const RUNNING: u8 = 0;
+const PARKED: u8 = 1;
+const SUSPENDED: u8 = 2;
+
+/// How long a collection waits for a thread to reach a safepoint before
+/// suspending it with a signal.
+const SUSPEND_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// The signal used to suspend threads which miss safepoints.
+const SUSPEND_SIGNAL: libc::c_int = libc::SIGUSR2;
+
+/// The number of general purpose registers saved in a `ucontext_t`.
+const NGREG: usize = 23;
+
+/// Set while a collection wants every other registered thread to stop.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The poll word of threads which are not registered. Every registered thread
+/// has its own copy in its `ThreadRecord`, which is what its safepoint polls
+/// test; updates are made to this word and to every thread's copy together,
+/// under the registry lock. Non-zero if either a stop or a collection has been
+/// requested.
+static POLL_WORD: AtomicUsize = AtomicUsize::new(0);
+
+const POLL_STOP: usize = 1;
+const POLL_COLLECT: usize = 2;
+/// Set in a single thread's poll word when destructors are waiting to run on
+/// it (see `collector::run_pending_destructors`).
+const POLL_FINALISE: usize = 4;
+
+/// The id of the next thread to register. Ids start at 1, so 0 can stand for
+/// an unregistered thread.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+static REGISTRY: Mutex<Vec<Arc<ThreadRecord>>> = Mutex::new(Vec::new());
+
+/// Parked threads wait on this for the collection to finish.
+static RESUME: (Mutex<()>, Condvar) = (Mutex::new(()), Condvar::new());
+
+pub(crate) struct ThreadRecord {
+    /// Unlike the record's address, never reused by a later thread.
+    id: usize,
+    /// This thread's copy of `POLL_WORD`. Its address is handed out to compiled
+    /// code for inline polls, so it must not move while the thread is
+    /// registered.
+    poll: AtomicUsize,
+    state: AtomicU8,
+    /// Where the walk of the thread's stack starts (see `frames`).
+    stop_top: AtomicUsize,
+    /// The registers saved when the thread stopped, which `stop_top` points to.
+    #[cfg(feature = "libunwind")]
+    context: UnsafeCell<frames::Context>,
+    pthread: libc::pthread_t,
+    /// The highest address of the thread's stack.
+    stack_end: usize,
+    /// Set by the collector when it has sent the suspend signal, so that it is
+    /// only sent once per collection.
+    signalled: AtomicBool,
+    /// The stack pointer and registers at the point of suspension. Written by
+    /// the signal handler before `state` becomes `SUSPENDED`, and only read by
+    /// the collector while the thread remains suspended.
+    stop_sp: AtomicUsize,
+    regs: UnsafeCell<[usize; NGREG]>
+}
+
+// The saved context holds pointers into the thread's own stack.
+#[cfg(feature = "libunwind")]
+unsafe impl Send for ThreadRecord {}
+unsafe impl Sync for ThreadRecord {}
+
+/// How a stopped thread's stack should be scanned.
+#[derive(Clone, Copy)]
+pub(crate) enum StoppedThread {
+    /// The thread stopped at a safepoint: walk its frames from `top`.
+    Precise { top: usize },
+    /// The thread was suspended by a signal. `regs`, and the stack between `sp`
+    /// and the first frame at a safepoint (or `stack_end`), must be scanned
+    /// conservatively before walking its frames from `top`.
+    Suspended {
+        top: usize,
+        sp: usize,
+        stack_end: usize,
+        regs: [usize; NGREG]
+    }
+}
+
+/// Unregisters the thread when its thread-locals are destroyed, so threads
+/// which exit without calling `unregister_thread()` are not waited for forever.
+struct Registration(Arc<ThreadRecord>);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        registry().retain(|t| !Arc::ptr_eq(t, &self.0));
+        crate::collector::forget_pending(self.0.id);
+    }
+}
+
+thread_local!(static CURRENT: RefCell<Option<Registration>> = const { RefCell::new(None) });
+
+// A raw pointer to the current thread's record, for use in the signal handler
+// where touching `CURRENT` isn't async-signal-safe.
+thread_local!(static CURRENT_RAW: Cell<*const ThreadRecord> = const { Cell::new(ptr::null()) });
+
+// Non-zero while the thread is in a runtime critical section (e.g. writing an
+// object header) during which it must not be suspended. A suspend signal which
+// arrives then is deferred until the section ends.
+thread_local!(static NO_SUSPEND: Cell<usize> = const { Cell::new(0) });
+thread_local!(static SUSPEND_DEFERRED: Cell<bool> = const { Cell::new(false) });
+
+#[cfg(not(feature = "simulation"))]
+static INSTALL_HANDLER: Once = Once::new();
+
+fn registry() -> MutexGuard<'static, Vec<Arc<ThreadRecord>>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(not(any(feature = "simulation", feature = "libunwind")))]
+#[inline(always)]
+pub(crate) fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) fp) };
+    fp
+}
+
+#[cfg(feature = "simulation")]
+pub(crate) fn frame_pointer() -> usize {
+    0
+}
+
+/// Records the calling function's frame as the top of the thread's stack.
+#[cfg(not(feature = "libunwind"))]
+#[inline(always)]
+fn record_stop(record: &ThreadRecord) {
+    record.stop_top.store(frame_pointer(), Ordering::SeqCst);
+}
+
+#[cfg(feature = "libunwind")]
+#[inline(always)]
+fn record_stop(record: &ThreadRecord) {
+    unsafe { frames::getcontext(record.context.get()) };
+    record
+        .stop_top
+        .store(record.context.get() as usize, Ordering::SeqCst);
+}
+
+fn current() -> Option<Arc<ThreadRecord>> {
+    CURRENT
+        .try_with(|c| c.borrow().as_ref().map(|r| r.0.clone()))
+        .ok()
+        .flatten()
+}
+
+/// Returns the highest address of the calling thread's stack.
+#[cfg(not(feature = "simulation"))]
+fn stack_end() -> usize {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return 0;
+        }
+        let mut addr = ptr::null_mut();
+        let mut size = 0;
+        libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        addr as usize + size
+    }
+}
+
+/// Simulated threads are never suspended, so their stacks are never scanned
+/// conservatively.
+#[cfg(feature = "simulation")]
+fn stack_end() -> usize {
+    0
+}
+
+#[cfg(not(feature = "simulation"))]
+fn install_suspend_handler() {
+    INSTALL_HANDLER.call_once(|| unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = suspend_handler as extern "C" fn(_, _, _) as usize;
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        if libc::sigaction(SUSPEND_SIGNAL, &sa, ptr::null_mut()) != 0 {
+            panic!("Can't install the thread suspension signal handler.");
+        }
+    });
+}
+
+pub(crate) fn register() {
+    #[cfg(not(feature = "simulation"))]
+    install_suspend_handler();
+    CURRENT.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.is_some() {
+            return;
+        }
+        // Hold the registry lock while copying the poll word, so that an update
+        // can't slip in between the copy and the thread becoming visible.
+        let mut threads = registry();
+        let record = Arc::new(ThreadRecord {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            poll: AtomicUsize::new(POLL_WORD.load(Ordering::SeqCst)),
+            state: AtomicU8::new(RUNNING),
+            stop_top: AtomicUsize::new(0),
+            #[cfg(feature = "libunwind")]
+            context: UnsafeCell::new(unsafe { mem::zeroed() }),
+            pthread: unsafe { libc::pthread_self() },
+            stack_end: stack_end(),
+            signalled: AtomicBool::new(false),
+            stop_sp: AtomicUsize::new(0),
+            regs: UnsafeCell::new([0; NGREG])
+        });
+        CURRENT_RAW.with(|r| r.set(Arc::as_ptr(&record)));
+        threads.push(record.clone());
+        drop(threads);
+        *c = Some(Registration(record));
+    });
+}
+
+pub(crate) fn unregister() {
+    if current_id() != 0 {
+        crate::collector::run_pending_destructors();
+    }
+    let _ = CURRENT_RAW.try_with(|r| r.set(ptr::null()));
+    // Dropping the registration removes it from the registry.
+    let _ = CURRENT.try_with(|c| c.borrow_mut().take());
+}
+
+/// Runs `f` with suspension by signal deferred. If a collection tried to
+/// suspend the thread in the meantime, the thread parks as soon as `f`
+/// returns.
+#[inline]
+pub(crate) fn no_suspend<R, F: FnOnce() -> R>(f: F) -> R {
+    NO_SUSPEND.with(|n| n.set(n.get() + 1));
+    let r = f();
+    NO_SUSPEND.with(|n| n.set(n.get() - 1));
+    if NO_SUSPEND.with(|n| n.get()) == 0 && SUSPEND_DEFERRED.with(|d| d.replace(false)) {
+        park_if_stopping();
+    }
+    r
+}
+
+/// Suspends the thread for the duration of a collection. Only the
+/// async-signal-safe parts of the runtime may be used here: atomics, and
+/// `sched_yield`.
+#[cfg(not(feature = "simulation"))]
+extern "C" fn suspend_handler(
+    _sig: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    ctx: *mut libc::c_void
+) {
+    let record = match CURRENT_RAW.try_with(|r| r.get()) {
+        Ok(r) if !r.is_null() => unsafe { &*r },
+        _ => return
+    };
+    if !STOP_REQUESTED.load(Ordering::SeqCst) || record.state.load(Ordering::SeqCst) != RUNNING {
+        // The thread reached a safepoint (or the runtime) after all, or the
+        // signal arrived after the collection finished.
+        return;
+    }
+    if NO_SUSPEND.with(|n| n.get()) > 0 {
+        SUSPEND_DEFERRED.with(|d| d.set(true));
+        return;
+    }
+
+    unsafe {
+        let ctx = &*(ctx as *const libc::ucontext_t);
+        let regs = &mut *record.regs.get();
+        for (r, g) in regs.iter_mut().zip(ctx.uc_mcontext.gregs.iter()) {
+            *r = *g as usize;
+        }
+        #[cfg(not(feature = "libunwind"))]
+        record
+            .stop_top
+            .store(regs[libc::REG_RBP as usize], Ordering::SeqCst);
+        #[cfg(feature = "libunwind")]
+        {
+            ptr::copy_nonoverlapping(ctx, record.context.get(), 1);
+            record
+                .stop_top
+                .store(record.context.get() as usize, Ordering::SeqCst);
+        }
+        record
+            .stop_sp
+            .store(regs[libc::REG_RSP as usize], Ordering::SeqCst);
+    }
+    record.state.store(SUSPENDED, Ordering::SeqCst);
+    while STOP_REQUESTED.load(Ordering::SeqCst) {
+        unsafe { libc::sched_yield() };
+    }
+    record.state.store(RUNNING, Ordering::SeqCst);
+}
+
+pub(crate) fn is_registered() -> bool {
+    current().is_some()
+}
+
+/// Returns the calling thread's id, or 0 if it isn't registered.
+pub(crate) fn current_id() -> usize {
+    match CURRENT_RAW.try_with(|r| r.get()) {
+        Ok(record) if !record.is_null() => unsafe { (*record).id },
+        _ => 0
+    }
+}
+
+/// Asks the thread with id `id` to run its pending destructors at its next
+/// safepoint poll. Returns `false` if there is no such thread.
+pub(crate) fn request_finalise(id: usize) -> bool {
+    match registry().iter().find(|t| t.id == id) {
+        Some(t) => {
+            t.poll.fetch_or(POLL_FINALISE, Ordering::SeqCst);
+            true
+        }
+        None => false
+    }
+}
+
+/// Returns `true`, and clears the request, if the calling thread has been
+/// asked to run its pending destructors.
+pub(crate) fn take_finalise() -> bool {
+    match CURRENT_RAW.try_with(|r| r.get()) {
+        Ok(record) if !record.is_null() => unsafe {
+            (*record).poll.fetch_and(!POLL_FINALISE, Ordering::SeqCst) & POLL_FINALISE != 0
+        },
+        _ => false
+    }
+}
+
+/// Returns the address of the calling thread's poll word, which is non-zero
+/// whenever `safepoint_poll` has work to do. Unregistered threads share the
+/// global word.
+#[inline]
+pub(crate) fn poll_word() -> *const AtomicUsize {
+    match CURRENT_RAW.try_with(|r| r.get()) {
+        Ok(record) if !record.is_null() => unsafe { &(*record).poll },
+        _ => &POLL_WORD
+    }
+}
+
+#[inline]
+pub(crate) fn poll_requested() -> bool {
+    unsafe { (*poll_word()).load(Ordering::Relaxed) != 0 }
+}
+
+/// Sets (if `set`) or clears `bits` in the global poll word and in that of
+/// every registered thread.
+fn update_poll(bits: usize, set: bool) {
+    let threads = registry();
+    let words = threads.iter().map(|t| &t.poll).chain(Some(&POLL_WORD));
+    for w in words {
+        if set {
+            w.fetch_or(bits, Ordering::SeqCst);
+        } else {
+            w.fetch_and(!bits, Ordering::SeqCst);
+        }
+    }
+}
+
+pub(crate) fn request_collect() {
+    update_poll(POLL_COLLECT, true);
+}
+
+pub(crate) fn clear_collect() {
+    update_poll(POLL_COLLECT, false);
+}
+
+/// Parks the calling thread for the duration of a pending collection, if there
+/// is one. Called from the slow path of `safepoint_poll`.
+#[inline(never)]
+pub(crate) fn park_if_stopping() {
+    if !STOP_REQUESTED.load(Ordering::SeqCst) {
+        return;
+    }
+    let record = match current() {
+        Some(r) => r,
+        None => return
+    };
+    record_stop(&record);
+    record.state.store(PARKED, Ordering::SeqCst);
+    unpark(&record);
+}
+
+/// Returns a parked thread to `RUNNING`, first waiting for any collection in
+/// progress to finish.
+///
+/// The thread announces that it is running *before* checking for a stop
+/// request, and the collector requests a stop *before* checking thread states,
+/// so at least one of them sees the other: either the collector waits for us,
+/// or we go back to being parked and wait for it.
+fn unpark(record: &ThreadRecord) {
+    loop {
+        let mut guard = RESUME.0.lock().unwrap_or_else(|e| e.into_inner());
+        while STOP_REQUESTED.load(Ordering::SeqCst) {
+            guard = RESUME.1.wait(guard).unwrap_or_else(|e| e.into_inner());
+        }
+        drop(guard);
+        record.state.store(RUNNING, Ordering::SeqCst);
+        if !STOP_REQUESTED.load(Ordering::SeqCst) {
+            return;
+        }
+        record.state.store(PARKED, Ordering::SeqCst);
+    }
+}
+
+/// Runs `f`, which may block for an arbitrary time without reaching a
+/// safepoint (e.g. waiting for the collector lock, or in a blocking syscall),
+/// with the calling thread marked as parked so that a collection can proceed
+/// without it. `f` must not touch the GC heap.
+#[inline(never)]
+pub(crate) fn blocking<R, F: FnOnce() -> R>(f: F) -> R {
+    let record = match current() {
+        Some(r) => r,
+        None => return f()
+    };
+    record_stop(&record);
+    record.state.store(PARKED, Ordering::SeqCst);
+    let r = f();
+    unpark(&record);
+    r
+}
+
+/// Marks the calling thread as being in native code until `exit_native`. The
+/// stack top recorded is this function's own: once it returns, the caller's
+/// next call overwrites the saved frame pointer and return address at that
+/// location with its own, so the stack is walked from the caller's innermost
+/// call site. With libunwind, this relies on the callee pushing the frame
+/// pointer first, as the frame pointer walk does.
+#[inline(never)]
+pub(crate) fn enter_native() {
+    if let Some(record) = current() {
+        debug_assert_eq!(record.state.load(Ordering::SeqCst), RUNNING);
+        record_stop(&record);
+        record.state.store(PARKED, Ordering::SeqCst);
+    }
+}
+
+/// Returns the calling thread from native code, blocking while a collection is
+/// in progress.
+pub(crate) fn exit_native() {
+    if let Some(record) = current() {
+        unpark(&record);
+    }
+}
+
+/// The number of registered threads.
+pub(crate) fn count() -> usize {
+    registry().len()
+}
+
+/// Stops every registered thread other than the caller, and fills `stopped`
+/// with how each of their stacks should be scanned. Threads which don't reach
+/// a safepoint within `SUSPEND_TIMEOUT` are suspended with a signal, except in
+/// simulated builds, which wait for them for as long as it takes.
+pub(crate) fn stop_the_world(stopped: &mut Vec<StoppedThread>) {
+    stop(stopped, true);
+}
+
+/// Like `stop_the_world`, but never suspends a thread with a signal, waiting
+/// instead for as long as it takes every thread to reach a safepoint. What
+/// allocates while the world is stopped must stop it this way: a suspended
+/// thread may hold the global allocator's lock.
+pub(crate) fn stop_at_safepoints(stopped: &mut Vec<StoppedThread>) {
+    stop(stopped, false);
+}
+
+fn stop(stopped: &mut Vec<StoppedThread>, suspend: bool) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    update_poll(POLL_STOP, true);
+    let me = current();
+    let start = Instant::now();
+    loop {
+        let threads = registry();
+        let others = threads
+            .iter()
+            .filter(|t| me.as_ref().is_none_or(|m| !Arc::ptr_eq(t, m)));
+        if others
+            .clone()
+            .all(|t| t.state.load(Ordering::SeqCst) != RUNNING)
+        {
+            // Unless a thread has registered since `count()` was used to size
+            // it, this doesn't allocate.
+            stopped.clear();
+            stopped.extend(others.map(|t| t.stopped()));
+            return;
+        }
+        if suspend && !cfg!(feature = "simulation") && start.elapsed() >= SUSPEND_TIMEOUT {
+            for t in others.filter(|t| t.state.load(Ordering::SeqCst) == RUNNING) {
+                if !t.signalled.swap(true, Ordering::SeqCst) {
+                    debug!("suspending a thread which missed a safepoint");
+                    unsafe { libc::pthread_kill(t.pthread, SUSPEND_SIGNAL) };
+                }
+            }
+        }
+        drop(threads);
+        thread::yield_now();
+    }
+}
+
+/// Writes what the crash handler needs to know about threads: whether the
+/// world is stopped, and the state of every registered thread. The registry is
+/// skipped if it is locked, since the crashing thread may hold the lock.
+pub(crate) fn write_crash_state(w: &mut dyn fmt::Write) -> fmt::Result {
+    let state = |t: &ThreadRecord| match t.state.load(Ordering::Relaxed) {
+        RUNNING => "running",
+        PARKED => "parked",
+        _ => "suspended"
+    };
+    writeln!(
+        w,
+        "rgcrt: world stopped: {}",
+        STOP_REQUESTED.load(Ordering::Relaxed)
+    )?;
+    match CURRENT_RAW.try_with(|r| r.get()) {
+        Ok(r) if !r.is_null() => writeln!(
+            w,
+            "rgcrt: crashing thread: registered, {}",
+            state(unsafe { &*r })
+        )?,
+        _ => writeln!(w, "rgcrt: crashing thread: not registered")?
+    }
+    match REGISTRY.try_lock() {
+        Ok(threads) => {
+            write!(w, "rgcrt: registered threads: {}", threads.len())?;
+            for t in threads.iter() {
+                write!(w, " {}", state(t))?;
+            }
+            writeln!(w)
+        }
+        Err(_) => writeln!(w, "rgcrt: thread registry is locked")
+    }
+}
+
+impl ThreadRecord {
+    fn stopped(&self) -> StoppedThread {
+        let top = self.stop_top.load(Ordering::SeqCst);
+        if self.state.load(Ordering::SeqCst) == SUSPENDED {
+            StoppedThread::Suspended {
+                top,
+                sp: self.stop_sp.load(Ordering::SeqCst),
+                stack_end: self.stack_end,
+                regs: unsafe { *self.regs.get() }
+            }
+        } else {
+            StoppedThread::Precise { top }
+        }
+    }
+}
+
+/// Releases the threads stopped by `stop_the_world`.
+pub(crate) fn resume_the_world() {
+    for t in registry().iter() {
+        t.signalled.store(false, Ordering::SeqCst);
+    }
+    let _guard = RESUME.0.lock().unwrap_or_else(|e| e.into_inner());
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+    update_poll(POLL_STOP, false);
+    RESUME.1.notify_all();
+}
+
+/// The hook called by the forked standard library at the start of every
+/// thread it spawns.
+#[no_mangle]
+pub extern "C" fn gcrt_thread_start() {
+    register();
+}
+
+/// The hook called by the forked standard library just before a thread it
+/// spawned exits.
+#[no_mangle]
+pub extern "C" fn gcrt_thread_exit() {
+    unregister();
+}
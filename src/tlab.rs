@@ -0,0 +1,105 @@
+//! Thread-local allocation buffers (TLABs).
+//!
+//! Each registered thread bump-allocates small objects from a private region of
+//! the shared heap, so the common allocation path needs no synchronisation.
+//! TLABs are carved out of the heap under the collector lock and are abandoned
+//! at every collection.
+//!
+//! The unused tail of a TLAB is always formatted as a free block, so the heap
+//! stays walkable however much of the TLAB has been used.
+
+use std::{
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering}
+};
+
+use crate::{
+    collector::{set_object_start, Header, TypeInfo, HDR},
+    threads
+};
+
+/// Incremented at every collection. A TLAB carved out in an earlier epoch has
+/// been swept and must not be allocated from.
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct Tlab {
+    top: *mut u8,
+    end: usize,
+    /// The start of the chunk the TLAB was carved from.
+    chunk: usize,
+    epoch: u64
+}
+
+thread_local!(static TLAB: Cell<Tlab> = const {
+    Cell::new(Tlab {
+        top: ptr::null_mut(),
+        end: 0,
+        chunk: 0,
+        epoch: u64::MAX
+    })
+});
+
+/// Allocates a block of `bsize` bytes (including the header) from the calling
+/// thread's TLAB, returning a pointer to the object. Returns `None` if the TLAB
+/// is stale or doesn't have enough room.
+#[inline]
+pub(crate) fn alloc(bsize: usize, ty: &'static TypeInfo) -> Option<*mut u8> {
+    // Suspending the thread half way through formatting a block would leave the
+    // heap unwalkable.
+    threads::no_suspend(|| alloc_inner(bsize, ty))
+}
+
+#[inline]
+fn alloc_inner(bsize: usize, ty: &'static TypeInfo) -> Option<*mut u8> {
+    TLAB.try_with(|t| {
+        let mut tlab = t.get();
+        if tlab.epoch != EPOCH.load(Ordering::Acquire) {
+            return None;
+        }
+        let avail = tlab.end - tlab.top.addr();
+        if bsize > avail {
+            return None;
+        }
+        // A remainder too small to hold a header is absorbed into the object.
+        let bsize = if avail - bsize < HDR { avail } else { bsize };
+        let block = tlab.top.cast::<Header>();
+        unsafe {
+            tlab.top = tlab.top.add(bsize);
+            if avail > bsize {
+                Header::init_free(tlab.top.cast(), avail - bsize);
+            }
+            Header::init(block, bsize, ty);
+            set_object_start(tlab.chunk, block.addr());
+            t.set(tlab);
+            Some(block.cast::<u8>().add(HDR))
+        }
+    })
+    .ok()
+    .flatten()
+}
+
+/// Replaces the calling thread's TLAB with the free block from `start` to the
+/// address `end`, in the chunk starting at `chunk`.
+///
+/// # Safety
+///
+/// The block must have been reserved from the heap for this thread's exclusive
+/// use, and be formatted as a single free block.
+pub(crate) unsafe fn refill(start: *mut Header, end: usize, chunk: usize) {
+    TLAB.with(|t| {
+        t.set(Tlab {
+            top: start.cast(),
+            end,
+            chunk,
+            epoch: EPOCH.load(Ordering::Acquire)
+        })
+    });
+}
+
+/// Abandons every thread's TLAB. Must only be called while the world is
+/// stopped.
+pub(crate) fn invalidate_all() {
+    EPOCH.fetch_add(1, Ordering::Release);
+}
@@ -0,0 +1,71 @@
+use crate::collector::{heap_ptr, mark_overflowed};
+
+/// A `Tracer` is handed to each `Scan::scan()` call during a collection. Scan
+/// implementations report the GC pointers they contain by calling `trace()`;
+/// the collector then drains the tracer's worklist to mark (and eventually
+/// relocate) the objects those pointers refer to.
+pub struct Tracer {
+    worklist: Vec<usize>,
+    // Whether the worklist is kept within its capacity, as it must be while
+    // the world is stopped. A pointer which doesn't fit is handed to
+    // `mark_overflowed` instead.
+    bounded: bool
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            worklist: Vec::new(),
+            bounded: false
+        }
+    }
+
+    /// Creates a tracer whose worklist never grows past its capacity, which
+    /// can only be increased by `reserve()`.
+    pub(crate) fn bounded() -> Self {
+        Tracer {
+            worklist: Vec::new(),
+            bounded: true
+        }
+    }
+
+    /// Reports a GC pointer discovered while scanning an object. Null pointers
+    /// are ignored.
+    #[inline]
+    pub fn trace<T: ?Sized>(&mut self, ptr: *const T) {
+        let ptr = (ptr as *const u8).addr();
+        if ptr == 0 {
+            return;
+        }
+        if self.bounded && self.worklist.len() == self.worklist.capacity() {
+            mark_overflowed(ptr);
+        } else {
+            self.worklist.push(ptr);
+        }
+    }
+
+    /// The number of pointers which have yet to be processed.
+    pub(crate) fn len(&self) -> usize {
+        self.worklist.len()
+    }
+
+    /// Removes the next pointer which has yet to be processed by the collector.
+    pub(crate) fn pop(&mut self) -> Option<*mut u8> {
+        self.worklist.pop().map(heap_ptr)
+    }
+
+    /// The pointers which have yet to be processed.
+    pub(crate) fn pending(&self) -> &[usize] {
+        &self.worklist
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.worklist.clear();
+    }
+
+    /// Makes room for at least `n` pointers, so that tracing that many doesn't
+    /// allocate.
+    pub(crate) fn reserve(&mut self, n: usize) {
+        self.worklist.reserve(n.saturating_sub(self.worklist.len()));
+    }
+}
@@ -0,0 +1,151 @@
+//! A detector for GC pointers which are held across a safepoint without being
+//! rooted, turned on by setting `RGCRT_CHECK_ROOTS`.
+//!
+//! Every pointer returned by `alloc_raw()` is recorded, and each recording
+//! requests a collection at the next safepoint poll. The collection, after
+//! marking from the roots, checks that every recorded object was marked: the
+//! mutator has had until that safepoint to put the pointer somewhere the
+//! collector can see, so an object which wasn't reached is either garbage or,
+//! much more likely, held in a raw pointer the collector doesn't know about.
+//! Code which allocates an object and discards it before the next safepoint
+//! must say so with `release_raw()`.
+//!
+//! Unrooted objects are reported on stderr, once the world has been resumed,
+//! and the process aborted.
+
+use std::{
+    env,
+    fmt::Write,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+use crate::{
+    collector::{heap_ptr, Header, HDR},
+    log::{self, Level},
+    threads
+};
+
+/// The maximum number of unrooted objects reported before aborting.
+const MAX_FAILURES: usize = 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set while some recorded object has yet to be checked.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+static STATE: Mutex<State> = Mutex::new(State {
+    recent: Vec::new(),
+    failures: [(0, ""); MAX_FAILURES],
+    failed: 0
+});
+
+struct State {
+    /// Objects allocated since the last collection, which haven't been
+    /// released.
+    recent: Vec<usize>,
+    /// The address and type of each unrooted object found, filled in while the
+    /// world is stopped, when nothing may be allocated.
+    failures: [(usize, &'static str); MAX_FAILURES],
+    /// The number of unrooted objects found, which may exceed `MAX_FAILURES`.
+    failed: usize
+}
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts checking for unrooted pointers if `RGCRT_CHECK_ROOTS` is set to
+/// anything but `0`.
+pub(crate) fn install_from_env() {
+    match env::var("RGCRT_CHECK_ROOTS") {
+        Ok(v) if v != "0" => ENABLED.store(true, Ordering::Relaxed),
+        _ => ()
+    }
+}
+
+/// Returns `true` if objects have been allocated since the last collection, and
+/// the next safepoint poll should collect to check them.
+#[inline]
+pub(crate) fn pending() -> bool {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Records `obj`, which has just been returned by `alloc_raw()`.
+#[inline]
+pub(crate) fn record(obj: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // The collector takes the lock while the world is stopped.
+    threads::no_suspend(|| state().recent.push(obj));
+    PENDING.store(true, Ordering::Relaxed);
+    threads::request_collect();
+}
+
+/// Stops checking `obj`, which the mutator has finished with.
+pub(crate) fn release(obj: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    threads::no_suspend(|| {
+        let mut state = state();
+        if let Some(i) = state.recent.iter().rposition(|&o| o == obj) {
+            state.recent.swap_remove(i);
+        }
+    });
+}
+
+/// Notes every recorded object which wasn't marked, and forgets the recorded
+/// objects. Doesn't allocate.
+///
+/// # Safety
+///
+/// The world must be stopped, and marking from the roots must have finished,
+/// but nothing may have been swept.
+pub(crate) unsafe fn check() {
+    if !PENDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let mut state = state();
+    let State {
+        recent,
+        failures,
+        failed
+    } = &mut *state;
+    for &obj in recent.iter() {
+        let hdr = &*heap_ptr::<Header>(obj - HDR);
+        if hdr.is_marked() {
+            continue;
+        }
+        if *failed < MAX_FAILURES {
+            failures[*failed] = (obj, hdr.ty().map_or("?", |ty| ty.name()));
+        }
+        *failed += 1;
+    }
+    recent.clear();
+}
+
+/// Aborts, listing the unrooted objects, if `check()` found any. Must be called
+/// once the world has been resumed.
+pub(crate) fn report() {
+    let state = state();
+    if state.failed == 0 {
+        return;
+    }
+    let mut msg = format!(
+        "{} object(s) allocated by alloc_raw() were not rooted at the next safepoint:",
+        state.failed
+    );
+    for (obj, ty) in state.failures.iter().take(state.failed) {
+        let _ = write!(msg, "\n  {:#x} ({})", obj, ty);
+    }
+    if state.failed > MAX_FAILURES {
+        let _ = write!(msg, "\n  ...");
+    }
+    log::write(Level::Error, format_args!("{}", msg));
+    process::abort();
+}
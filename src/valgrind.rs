@@ -0,0 +1,105 @@
+//! Valgrind client requests describing the GC heap to memcheck, enabled by the
+//! `valgrind` feature. Without the feature, every function here does nothing.
+//!
+//! The heap's chunks come from the system allocator, which memcheck already
+//! tracks, so the objects carved out of them are described as pieces of a
+//! single memory pool: each object body is a `MEMPOOL_ALLOC`ed piece from its
+//! allocation until the sweep frees it with `MEMPOOL_FREE`. Memcheck then
+//! reports any access to a freed object -- the usual symptom of a missing
+//! root -- at the point it happens. Block headers are never part of a piece,
+//! so the collector's own heap walks stay quiet. The one place the runtime
+//! writes into memory which may once have held an object is when it formats a
+//! new header, which it first marks as undefined.
+//!
+//! Under Valgrind the requests are a few no-op instructions which Valgrind
+//! recognises; run natively, they cost almost nothing.
+
+#[cfg(feature = "valgrind")]
+mod imp {
+    use std::{arch::asm, sync::Once};
+
+    const CREATE_MEMPOOL: usize = 0x1303;
+    const DESTROY_MEMPOOL: usize = 0x1304;
+    const MEMPOOL_ALLOC: usize = 0x1305;
+    const MEMPOOL_FREE: usize = 0x1306;
+    const MAKE_MEM_UNDEFINED: usize = 0x4d43_0001;
+
+    /// The pool's identity. Valgrind only uses its address.
+    static POOL: u8 = 0;
+    static CREATE_POOL: Once = Once::new();
+
+    fn pool() -> usize {
+        &POOL as *const u8 as usize
+    }
+
+    /// Issues a client request, returning `default` when not running under
+    /// Valgrind. This is the x86_64 sequence from `valgrind.h`.
+    #[inline(always)]
+    unsafe fn request(default: usize, req: usize, args: [usize; 5]) -> usize {
+        let block = [req, args[0], args[1], args[2], args[3], args[4]];
+        let result;
+        asm!(
+            "rol rdi, 3",
+            "rol rdi, 13",
+            "rol rdi, 61",
+            "rol rdi, 51",
+            "xchg rbx, rbx",
+            inout("rdx") default => result,
+            in("rax") block.as_ptr(),
+            out("rdi") _,
+            options(nostack)
+        );
+        result
+    }
+
+    pub(crate) fn create_pool() {
+        CREATE_POOL.call_once(|| unsafe {
+            request(0, CREATE_MEMPOOL, [pool(), 0, 0, 0, 0]);
+            libc::atexit(destroy_pool);
+        });
+    }
+
+    /// Objects still live at exit are not leaks in the sense memcheck means.
+    extern "C" fn destroy_pool() {
+        unsafe { request(0, DESTROY_MEMPOOL, [pool(), 0, 0, 0, 0]) };
+    }
+
+    #[inline(always)]
+    pub(crate) fn alloc(addr: usize, size: usize) {
+        unsafe { request(0, MEMPOOL_ALLOC, [pool(), addr, size, 0, 0]) };
+    }
+
+    #[inline(always)]
+    pub(crate) fn free(addr: usize) {
+        unsafe { request(0, MEMPOOL_FREE, [pool(), addr, 0, 0, 0]) };
+    }
+
+    #[inline(always)]
+    pub(crate) fn make_undefined(addr: usize, size: usize) {
+        unsafe { request(0, MAKE_MEM_UNDEFINED, [addr, size, 0, 0, 0]) };
+    }
+}
+
+#[cfg(not(feature = "valgrind"))]
+mod imp {
+    #[inline(always)]
+    pub(crate) fn create_pool() {}
+
+    #[inline(always)]
+    pub(crate) fn alloc(_addr: usize, _size: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn free(_addr: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn make_undefined(_addr: usize, _size: usize) {}
+}
+
+/// Marks `[addr, addr + size)` as an object.
+pub(crate) use self::imp::alloc;
+/// Creates the memory pool, if it hasn't been already.
+pub(crate) use self::imp::create_pool;
+/// Marks the object at `addr` as freed.
+pub(crate) use self::imp::free;
+/// Marks `[addr, addr + size)` as writable but undefined.
+pub(crate) use self::imp::make_undefined;
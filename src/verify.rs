@@ -0,0 +1,247 @@
+//! A heap verifier, run at the end of every collection when the `verify`
+//! feature is enabled.
+//!
+//! With the world still stopped, the verifier walks every chunk and checks
+//! that:
+//!
+//!   * every block header is plausible: its size is a multiple of `HALIGN`, at
+//!     least a header long, and doesn't overrun the chunk;
+//!   * no mark bit has been left set;
+//!   * every live object has a type;
+//!   * every pointer reported by a live object's `scan()` is the start of a
+//!     live object. Objects from the C API are scanned conservatively, so
+//!     their words which aren't are taken to be integers;
+//!   * every free block is still filled with `POISON`, which the sweep writes
+//!     into each block it frees. Anything else means something wrote to memory
+//!     it no longer owned.
+//!
+//! If `RGCRT_VERIFY_BARRIERS` is set, write barriers are checked too, as if
+//! the collector were generational. The objects which survived the previous
+//! collection are the old generation, and every object allocated since is
+//! young; `write_barrier()` adds the object written to to a remembered set. At
+//! each collection, every old object which refers to a young one must be in
+//! the remembered set, or a store to it was missing its barrier. This keeps
+//! the address of every surviving object between collections, so it is slow
+//! even by this module's standards.
+//!
+//! Any failure is reported on stderr and the process aborted, so that a
+//! collector bug is caught at the collection which caused it.
+
+use std::{
+    collections::HashSet,
+    env,
+    fmt::Write,
+    process, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard
+    }
+};
+
+use crate::{
+    asan,
+    collector::{find_header_in, heap_ptr, Header, HALIGN, HDR},
+    log::{self, Level},
+    threads, valgrind, Tracer
+};
+
+/// The byte with which freed memory is filled.
+pub(crate) const POISON: u8 = 0xdb;
+
+/// The maximum number of failures reported before aborting.
+const MAX_FAILURES: usize = 20;
+
+static VERIFY_BARRIERS: AtomicBool = AtomicBool::new(false);
+
+/// Objects passed to `write_barrier()` since the last collection.
+static REMEMBERED: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+/// The objects which survived the last collection.
+static OLD: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+fn lock(
+    set: &'static Mutex<Option<HashSet<usize>>>
+) -> MutexGuard<'static, Option<HashSet<usize>>> {
+    set.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts checking write barriers if `RGCRT_VERIFY_BARRIERS` is set to anything
+/// but `0`.
+pub(crate) fn install_from_env() {
+    match env::var("RGCRT_VERIFY_BARRIERS") {
+        Ok(v) if v != "0" => VERIFY_BARRIERS.store(true, Ordering::Relaxed),
+        _ => ()
+    }
+}
+
+/// Adds `obj` to the remembered set.
+#[inline]
+pub(crate) fn remember(obj: usize) {
+    if VERIFY_BARRIERS.load(Ordering::Relaxed) {
+        // The collector takes the lock while the world is stopped.
+        threads::no_suspend(|| {
+            lock(&REMEMBERED)
+                .get_or_insert_with(HashSet::new)
+                .insert(obj);
+        });
+    }
+}
+
+/// Fills the body of the free block `block` with `POISON`.
+///
+/// # Safety
+///
+/// `block` must be a free block which nothing else refers to.
+pub(crate) unsafe fn poison(block: *mut Header) {
+    let size = (*block).size();
+    asan::unpoison(block.addr() + HDR, size - HDR);
+    valgrind::make_undefined(block.addr() + HDR, size - HDR);
+    ptr::write_bytes(block.cast::<u8>().add(HDR), POISON, size - HDR);
+}
+
+/// Checks every chunk `[start, top)` in `ranges`, aborting if the heap is
+/// corrupt.
+///
+/// # Safety
+///
+/// The world must be stopped and every range must be the allocated part of a
+/// heap chunk.
+pub(crate) unsafe fn verify_heap(ranges: &[(usize, usize)]) {
+    let mut failures = Vec::new();
+    let mut objects = 0;
+    let mut tracer = Tracer::new();
+    'chunks: for &(start, top) in ranges {
+        let mut cur = start;
+        while cur < top {
+            let hdr = &*heap_ptr::<Header>(cur);
+            let size = hdr.size();
+            if size < HDR || !size.is_multiple_of(HALIGN) || size > top - cur {
+                failures.push(format!(
+                    "block at {:#x} has an invalid size of {} bytes",
+                    cur, size
+                ));
+                // The rest of the chunk can't be walked.
+                continue 'chunks;
+            }
+            if hdr.is_marked() {
+                failures.push(format!("block at {:#x} is still marked", cur));
+            }
+            let obj = cur + HDR;
+            if hdr.is_live() {
+                objects += 1;
+                match hdr.ty() {
+                    Some(ty) => {
+                        if let Some(trace) = hdr.trace_fn() {
+                            trace(heap_ptr(obj), &mut tracer);
+                        }
+                        while let Some(p) = tracer.pop() {
+                            if find_header_in(ranges, p.addr()).is_none() && !ty.conservative {
+                                failures.push(format!(
+                                    "object at {:#x} ({}) refers to {:p}, which is not a live object",
+                                    obj,
+                                    ty.name(),
+                                    p
+                                ));
+                            }
+                        }
+                    }
+                    None => failures.push(format!("live object at {:#x} has no type", obj))
+                }
+            } else if let Some(i) = (obj..cur + size).position(|a| *heap_ptr::<u8>(a) != POISON) {
+                failures.push(format!(
+                    "free block at {:#x} was written to at {:#x}",
+                    cur,
+                    obj + i
+                ));
+            }
+            if failures.len() >= MAX_FAILURES {
+                break 'chunks;
+            }
+            cur += size;
+        }
+    }
+
+    if failures.is_empty() {
+        debug!("heap verified: {} live objects", objects);
+        return;
+    }
+    let mut msg = String::from("heap verification failed:");
+    for f in &failures {
+        let _ = write!(msg, "\n  {}", f);
+    }
+    log::write(Level::Error, format_args!("{}", msg));
+    process::abort();
+}
+
+/// Checks that every old object which refers to a young one is in the
+/// remembered set, aborting if not, and then makes every live object old and
+/// empties the remembered set. Does nothing unless barrier checking is on.
+///
+/// # Safety
+///
+/// The world must be stopped, the sweep must have finished, and every range
+/// must be the allocated part of a heap chunk.
+pub(crate) unsafe fn verify_barriers(ranges: &[(usize, usize)]) {
+    if !VERIFY_BARRIERS.load(Ordering::Relaxed) {
+        return;
+    }
+    let remembered = lock(&REMEMBERED).take().unwrap_or_default();
+    let mut old = lock(&OLD);
+    let prev = old.take().unwrap_or_default();
+    let mut live = HashSet::new();
+    let mut failures = Vec::new();
+    let mut tracer = Tracer::new();
+    for &(start, top) in ranges {
+        let mut cur = start;
+        while cur < top {
+            let hdr = &*heap_ptr::<Header>(cur);
+            let obj = cur + HDR;
+            cur += hdr.size();
+            if !hdr.is_live() {
+                continue;
+            }
+            live.insert(obj);
+            if !prev.contains(&obj) || remembered.contains(&obj) {
+                continue;
+            }
+            let ty = match hdr.ty() {
+                Some(ty) => ty,
+                None => continue
+            };
+            if let Some(trace) = hdr.trace_fn() {
+                trace(heap_ptr(obj), &mut tracer);
+            }
+            while let Some(p) = tracer.pop() {
+                let p = p.addr();
+                if prev.contains(&p) || failures.len() >= MAX_FAILURES {
+                    continue;
+                }
+                let young = match find_header_in(ranges, p) {
+                    Some(h) => (*h).ty().map_or("?", |ty| ty.name()),
+                    // Not an object at all.
+                    None if ty.conservative => continue,
+                    None => "?"
+                };
+                failures.push(format!(
+                    "old object at {:#x} ({}) refers to young object at {:#x} ({}), \
+                     but was never passed to write_barrier()",
+                    obj,
+                    ty.name(),
+                    p,
+                    young
+                ));
+            }
+        }
+    }
+    *old = Some(live);
+
+    if failures.is_empty() {
+        return;
+    }
+    let mut msg = String::from("write barrier verification failed:");
+    for f in &failures {
+        let _ = write!(msg, "\n  {}", f);
+    }
+    log::write(Level::Error, format_args!("{}", msg));
+    process::abort();
+}
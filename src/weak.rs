@@ -0,0 +1,235 @@
+//! Weak, soft and phantom references, and the queues which are told when their
+//! referents die.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard}
+};
+
+use crate::{ephemeron::Strength, threads, Ephemeron, Gc, Scan, Tracer};
+
+/// A reference which doesn't keep its referent alive. Once the referent is
+/// only reachable through weak references, the collector clears them all, and
+/// `upgrade()` returns `None` from then on.
+///
+/// A `Weak` is an ephemeron with an empty value, so it is itself a small GC
+/// object, kept alive by whatever holds the `Weak`.
+pub struct Weak<T: ?Sized> {
+    eph: Gc<Ephemeron<T, ()>>
+}
+
+impl<T: ?Sized> Weak<T> {
+    pub fn new(obj: Gc<T>) -> Self {
+        Weak {
+            eph: Ephemeron::new(obj, ())
+        }
+    }
+
+    /// Creates a weak reference which posts `token` to `queue` when it is
+    /// cleared. Nothing is posted if the `Weak` itself becomes unreachable
+    /// first.
+    pub fn with_queue(obj: Gc<T>, queue: &RefQueue, token: usize) -> Self {
+        Weak {
+            eph: Ephemeron::with_queue(obj, (), queue, token)
+        }
+    }
+
+    /// Returns the referent, or `None` if it has been collected.
+    pub fn upgrade(&self) -> Option<Gc<T>> {
+        self.eph.key()
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Weak<T> {}
+
+impl<T: ?Sized> Scan for Weak<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        self.eph.scan(tracer);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.upgrade() {
+            Some(obj) => f.debug_tuple("Weak").field(&obj).finish(),
+            None => f.write_str("Weak(<cleared>)")
+        }
+    }
+}
+
+/// A reference which keeps its referent alive until the heap is short of
+/// memory, for caches which should give memory back rather than fail.
+///
+/// Soft references are cleared, like weak ones, by a collection which either
+/// precedes a failed allocation, or follows two collections in a row whose
+/// survivors occupied more than 90% of the heap limit (see
+/// `set_max_heap_size()` and `set_soft_occupancy()`). Without a heap limit,
+/// only the former applies.
+pub struct Soft<T: ?Sized> {
+    eph: Gc<Ephemeron<T, ()>>
+}
+
+impl<T: ?Sized> Soft<T> {
+    pub fn new(obj: Gc<T>) -> Self {
+        Soft {
+            eph: Ephemeron::register(obj, (), None, Strength::Soft)
+        }
+    }
+
+    /// Creates a soft reference which posts `token` to `queue` when it is
+    /// cleared.
+    pub fn with_queue(obj: Gc<T>, queue: &RefQueue, token: usize) -> Self {
+        Soft {
+            eph: Ephemeron::register(obj, (), Some((queue.clone(), token)), Strength::Soft)
+        }
+    }
+
+    /// Returns the referent, or `None` if it has been collected.
+    pub fn get(&self) -> Option<Gc<T>> {
+        self.eph.key()
+    }
+}
+
+impl<T: ?Sized> Clone for Soft<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Soft<T> {}
+
+impl<T: ?Sized> Scan for Soft<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        self.eph.scan(tracer);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Soft<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(obj) => f.debug_tuple("Soft").field(&obj).finish(),
+            None => f.write_str("Soft(<cleared>)")
+        }
+    }
+}
+
+/// A reference which gives no access to its referent, but posts a token to a
+/// queue once the referent has been reclaimed. Unlike a weak reference, which
+/// is cleared as soon as its referent is unreachable, a phantom reference
+/// waits until the referent's destructor has run and nothing else, including
+/// a destructor which stored it somewhere, could still reach it. This is the
+/// point at which an external resource associated with the referent can
+/// safely be released.
+pub struct Phantom<T: ?Sized> {
+    eph: Gc<Ephemeron<T, ()>>
+}
+
+impl<T: ?Sized> Phantom<T> {
+    /// Creates a phantom reference to `obj`, which posts `token` to `queue`.
+    /// Nothing is posted if the `Phantom` itself becomes unreachable first.
+    pub fn new(obj: Gc<T>, queue: &RefQueue, token: usize) -> Self {
+        Phantom {
+            eph: Ephemeron::register(obj, (), Some((queue.clone(), token)), Strength::Phantom)
+        }
+    }
+
+    /// Returns `true` once the referent has been reclaimed, by which time the
+    /// token has been posted.
+    pub fn is_cleared(&self) -> bool {
+        self.eph.key().is_none()
+    }
+}
+
+impl<T: ?Sized> Clone for Phantom<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Phantom<T> {}
+
+impl<T: ?Sized> Scan for Phantom<T> {
+    fn scan(&self, tracer: &mut Tracer) {
+        self.eph.scan(tracer);
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Phantom<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Phantom")
+            .field("cleared", &self.is_cleared())
+            .finish()
+    }
+}
+
+/// A queue of tokens, one posted for each weak, soft or phantom reference, or
+/// ephemeron, registered with the queue whose referent has been collected. This
+/// lets an embedder clean up whatever it associated with the referent -- a hash
+/// table entry, a native handle -- as soon as the collector notices, rather than
+/// by periodically looking for cleared references.
+///
+/// Tokens are posted by the collecting thread once the world has resumed,
+/// before destructors run. Clones share the same queue.
+#[derive(Clone, Default)]
+pub struct RefQueue {
+    inner: Arc<Inner>
+}
+
+#[derive(Default)]
+struct Inner {
+    tokens: Mutex<VecDeque<usize>>,
+    posted: Condvar
+}
+
+impl RefQueue {
+    pub fn new() -> Self {
+        RefQueue::default()
+    }
+
+    fn tokens(&self) -> MutexGuard<'_, VecDeque<usize>> {
+        self.inner.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn post(&self, token: usize) {
+        self.tokens().push_back(token);
+        self.inner.posted.notify_one();
+    }
+
+    /// Removes the oldest token, if there is one.
+    pub fn poll(&self) -> Option<usize> {
+        self.tokens().pop_front()
+    }
+
+    /// Removes the oldest token, waiting for one to be posted if necessary.
+    /// Collections can run on other threads while this waits.
+    pub fn wait(&self) -> usize {
+        threads::blocking(|| {
+            let mut tokens = self.tokens();
+            loop {
+                if let Some(t) = tokens.pop_front() {
+                    return t;
+                }
+                tokens = self
+                    .inner
+                    .posted
+                    .wait(tokens)
+                    .unwrap_or_else(|e| e.into_inner());
+            }
+        })
+    }
+}
+
+impl fmt::Debug for RefQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RefQueue")
+            .field("pending", &self.tokens().len())
+            .finish()
+    }
+}
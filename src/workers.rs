@@ -0,0 +1,125 @@
+//! The threads which mark and sweep in parallel with the collecting thread.
+//!
+//! Spawning a thread allocates, which mustn't happen while the world is
+//! stopped, so workers are started beforehand and kept for the rest of the
+//! process, waiting for the next job.
+
+use std::{
+    mem,
+    sync::{Condvar, Mutex, MutexGuard},
+    thread
+};
+
+use crate::collector::AbortOnPanic;
+
+/// A job runs with one id per thread taking part, `0` being the caller's.
+type Job = &'static (dyn Fn(usize) + Sync);
+
+struct State {
+    /// The number of worker threads started so far.
+    spawned: usize,
+    /// Incremented for each job, so that a worker can tell a new one from the
+    /// one it has just finished.
+    generation: u64,
+    job: Option<Job>,
+    /// The number of ids the current job runs with.
+    ids: usize,
+    /// Workers which have yet to finish the current job.
+    pending: usize
+}
+
+struct Pool {
+    state: Mutex<State>,
+    start: Condvar,
+    done: Condvar
+}
+
+static POOL: Pool = Pool {
+    state: Mutex::new(State {
+        spawned: 0,
+        generation: 0,
+        job: None,
+        ids: 0,
+        pending: 0
+    }),
+    start: Condvar::new(),
+    done: Condvar::new()
+};
+
+fn state() -> MutexGuard<'static, State> {
+    POOL.state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts worker threads until there are at least `n`. Must not be called
+/// while the world is stopped.
+pub(crate) fn ensure(n: usize) {
+    let mut s = state();
+    while s.spawned < n {
+        let id = s.spawned + 1;
+        let spawned = thread::Builder::new()
+            .name(format!("gcrt worker {}", id))
+            .spawn(move || work(id));
+        if let Err(e) = spawned {
+            error!("can't start a GC worker thread: {}", e);
+            return;
+        }
+        s.spawned += 1;
+    }
+}
+
+/// The number of threads which can take part in a job: the workers, and the
+/// caller.
+pub(crate) fn available() -> usize {
+    state().spawned + 1
+}
+
+/// Calls `f` once with each id in `0..ids`, concurrently, and returns once
+/// every call has. The caller runs id `0`. `ids` must be at most
+/// `available()`.
+pub(crate) fn run(ids: usize, f: &(dyn Fn(usize) + Sync)) {
+    if ids == 1 {
+        return f(0);
+    }
+    {
+        let mut s = state();
+        assert!(ids <= s.spawned + 1, "more GC worker ids than threads");
+        // The job is only reachable until every worker has finished it, which
+        // is waited for below.
+        s.job = Some(unsafe { mem::transmute::<&(dyn Fn(usize) + Sync), Job>(f) });
+        s.ids = ids;
+        s.pending = ids - 1;
+        s.generation += 1;
+    }
+    POOL.start.notify_all();
+    f(0);
+    let mut s = state();
+    while s.pending > 0 {
+        s = POOL.done.wait(s).unwrap_or_else(|e| e.into_inner());
+    }
+    s.job = None;
+}
+
+fn work(id: usize) {
+    // A worker holds no GC pointers of its own, so it isn't a mutator.
+    crate::unregister_thread();
+    let _abort = AbortOnPanic("a GC worker thread");
+    let mut seen = 0;
+    loop {
+        let (job, generation) = {
+            let mut s = state();
+            while s.generation == seen {
+                s = POOL.start.wait(s).unwrap_or_else(|e| e.into_inner());
+            }
+            (s.job.filter(|_| id < s.ids), s.generation)
+        };
+        seen = generation;
+        if let Some(job) = job {
+            job(id);
+            let mut s = state();
+            s.pending -= 1;
+            if s.pending == 0 {
+                POOL.done.notify_all();
+            }
+        }
+    }
+}